@@ -0,0 +1,69 @@
+//! Route lookup cost for routers of increasing size. Run with `cargo bench --bench router`.
+
+extern crate rust_tag_server;
+
+use rust_tag_server::httpd::{Handler, Request, Router};
+use std::hint::black_box;
+use std::io::Error;
+use std::time::Instant;
+
+const ITERATIONS: u32 = 200_000;
+
+struct NoopHandler;
+
+impl Handler for NoopHandler {
+    fn handle(&self, _request: &mut Request) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Builds a router shaped like a typical REST API: `routes` entries spread over a handful of
+/// versions and resources, half static and half with a path parameter.
+fn build_router(routes: usize) -> Router {
+    let mut router = Router::new();
+
+    for i in 0..routes {
+        let version = i % 4;
+        let resource = i / 4;
+
+        if i % 2 == 0 {
+            router.add_route(&format!("/api/v{}/resource{}/items", version, resource), "GET", NoopHandler);
+        } else {
+            router.add_route(&format!("/api/v{}/resource{}/:id/items", version, resource), "GET", NoopHandler);
+        }
+    }
+
+    router
+}
+
+fn time_lookups(router: &Router, path: &str) -> f64 {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let _ = black_box(router.get_handler(black_box(path), "GET"));
+    }
+
+    start.elapsed().as_nanos() as f64 / f64::from(ITERATIONS)
+}
+
+fn main() {
+    println!("{:>8} {:>12} {:>12} {:>12} {:>12}", "routes", "static", "param", "prefix", "miss");
+
+    for routes in [10, 100, 500, 1000, 5000].iter() {
+        let router = build_router(*routes);
+        let last = *routes - 1;
+        let last_static = last - last % 2;
+        let last_param = if last % 2 == 1 { last } else { last - 1 };
+
+        let static_path = format!("/api/v{}/resource{}/items", last_static % 4, last_static / 4);
+        let param_path = format!("/api/v{}/resource{}/12345/items", last_param % 4, last_param / 4);
+        let prefix_path = format!("/api/v{}/resource{}/items/extra/segments", last_static % 4, last_static / 4);
+        let miss_path = "/api/v9/nothing/here";
+
+        println!("{:>8} {:>10.1}ns {:>10.1}ns {:>10.1}ns {:>10.1}ns",
+                 routes,
+                 time_lookups(&router, &static_path),
+                 time_lookups(&router, &param_path),
+                 time_lookups(&router, &prefix_path),
+                 time_lookups(&router, miss_path));
+    }
+}
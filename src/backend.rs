@@ -0,0 +1,453 @@
+use std::collections::{BTreeSet, HashMap};
+use std::collections::Bound::{Excluded, Unbounded};
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, SecondsFormat};
+
+use tag_expr::Expr;
+use events::EventBus;
+use merkle::Digest;
+
+/// What a call to `TagBackend::snapshot` wrote.
+#[derive(Clone, Copy, Debug)]
+pub struct SnapshotStats {
+    /// The first log segment not covered by the snapshot.
+    pub generation: u64,
+    pub users: usize,
+    /// Tags written, tombstones included.
+    pub entries: u64,
+}
+
+/// Entries reclaimed by tombstone garbage collection.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GcStats {
+    pub tombstones: u64,
+    /// Users dropped because they were left with no tags at all.
+    pub users: u64,
+}
+
+/// One page of the users holding a tag, in order.
+#[derive(Clone, Debug, Default)]
+pub struct UserPage {
+    pub users: Vec<String>,
+    /// How many users hold the tag in all.
+    pub total: usize,
+    /// The cursor for the next page, if there is one.
+    pub next: Option<String>,
+}
+
+impl UserPage {
+    /// Up to `limit` of `users`, starting after the user `after`.
+    pub fn from_set(users: &BTreeSet<String>, after: Option<&str>, limit: usize) -> UserPage {
+        let start = match after {
+            Some(after) => Excluded(after),
+            None => Unbounded,
+        };
+
+        let mut page: Vec<String> = users.range::<str, _>((start, Unbounded)).take(limit + 1).cloned().collect();
+        let next = if page.len() > limit {
+            page.truncate(limit);
+            page.last().cloned()
+        } else {
+            None
+        };
+
+        UserPage {
+            users: page,
+            total: users.len(),
+            next,
+        }
+    }
+}
+
+/// What an operation in a user's history asked for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Add,
+    Remove,
+    /// Replaced every tag in a group.
+    ReplaceGroup,
+}
+
+/// An operation on one of a user's tags, as kept in their history.
+#[derive(Clone, Debug)]
+pub struct Change {
+    pub kind: ChangeKind,
+    /// The tag, or the group for a replacement.
+    pub tag: String,
+    pub timestamp: i64,
+    pub expires_at: Option<i64>,
+    /// When the store received the operation.
+    pub received_at: i64,
+    /// Who sent it, such as `api` or `expiry`.
+    pub source: String,
+    /// Whether it changed the tag, rather than being rejected as stale because a later operation
+    /// had already been applied or it arrived after the tag's tombstone was collected.
+    pub applied: bool,
+}
+
+/// A write to a `TagBackend`, carrying everything needed to apply it again elsewhere, such as on
+/// another replica.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    Add {
+        user: String,
+        tag: String,
+        timestamp: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<i64>,
+        source: String,
+    },
+    Remove {
+        user: String,
+        tag: String,
+        timestamp: i64,
+        source: String,
+    },
+    SetGroup {
+        user: String,
+        group: String,
+        tags: Vec<String>,
+        timestamp: i64,
+        source: String,
+    },
+    SetAttribute {
+        user: String,
+        key: String,
+        value: String,
+        timestamp: i64,
+    },
+    RemoveAttribute {
+        user: String,
+        key: String,
+        timestamp: i64,
+    },
+}
+
+impl Operation {
+    /// Applies the operation to `backend` through the matching write.
+    pub fn apply<B: TagBackend + ?Sized>(&self, backend: &B) -> Result<(), Error> {
+        match *self {
+            Operation::Add { ref user, ref tag, timestamp, expires_at: Some(expires_at), ref source } => {
+                backend.add_expiring_tag(user, tag, timestamp, expires_at, source)
+            }
+            Operation::Add { ref user, ref tag, timestamp, expires_at: None, ref source } => {
+                backend.add_tag(user, tag, timestamp, source)
+            }
+            Operation::Remove { ref user, ref tag, timestamp, ref source } => backend.remove_tag(user, tag, timestamp, source),
+            Operation::SetGroup { ref user, ref group, ref tags, timestamp, ref source } => {
+                backend.set_group(user, group, tags, timestamp, source)
+            }
+            Operation::SetAttribute { ref user, ref key, ref value, timestamp } => backend.set_attribute(user, key, value, timestamp),
+            Operation::RemoveAttribute { ref user, ref key, timestamp } => backend.remove_attribute(user, key, timestamp),
+        }
+    }
+
+    /// Why the operation can't be applied, if it would make a backend refuse it. Stores keep the
+    /// sign of a timestamp to tell adds from removes, so only positive times can be applied.
+    pub(crate) fn invalid(&self) -> Option<&'static str> {
+        match *self {
+            Operation::SetGroup { ref group, .. } if !is_group_name(group) => {
+                Some("Invalid group name")
+            }
+            _ if self.timestamp() <= 0 => Some("Expected timestamp to be positive"),
+            Operation::Add { expires_at: Some(expires_at), .. } if expires_at <= 0 => {
+                Some("Expected expires_at to be positive")
+            }
+            _ => None,
+        }
+    }
+
+    fn timestamp(&self) -> i64 {
+        match *self {
+            Operation::Add { timestamp, .. }
+            | Operation::Remove { timestamp, .. }
+            | Operation::SetGroup { timestamp, .. }
+            | Operation::SetAttribute { timestamp, .. }
+            | Operation::RemoveAttribute { timestamp, .. } => timestamp,
+        }
+    }
+}
+
+/// Milliseconds since the Unix epoch, the unit of every timestamp in the store.
+pub(crate) fn now_millis() -> isize {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as isize
+}
+
+/// Formats milliseconds since the epoch as ISO 8601 in UTC, or as the number if out of range.
+pub fn format_timestamp(millis: i64) -> String {
+    DateTime::from_timestamp_millis(millis)
+        .map_or_else(|| millis.to_string(), |datetime| datetime.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+/// Lowercase hex for `bytes`.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Whether a tag whose latest operation has the signed timestamp `ts`, with an expiry of
+/// `expires` or 0 for none, is present at `now`. An expiry acts as a remove at that time, so it
+/// only counts once due and if it wins over `ts`.
+pub(crate) fn is_present(ts: isize, expires: isize, now: isize) -> bool {
+    ts > 0 && !(expires > 0 && expires <= now && expires >= ts)
+}
+
+/// Separates the group from the tag in the full name of a tag in a group, as in `device:ios`.
+pub const GROUP_SEPARATOR: char = ':';
+
+/// Whether `group` can name a group, which takes at least one character and no separator.
+pub(crate) fn is_group_name(group: &str) -> bool {
+    !group.is_empty() && !group.contains(GROUP_SEPARATOR)
+}
+
+/// The group a tag belongs to, if it has one.
+pub(crate) fn group_of(tag: &str) -> Option<&str> {
+    tag.split_once(GROUP_SEPARATOR).map(|(group, _)| group)
+}
+
+/// Whether replacing a group at `cleared` removes a tag in it whose latest operation has the
+/// signed timestamp `ts`, which it does to anything older.
+pub(crate) fn group_clears(cleared: isize, ts: isize) -> bool {
+    ts.abs() < cleared
+}
+
+/// Whether an attribute operation with the signed timestamp `ts`, negated for a remove, wins over
+/// the current state under last-write-wins: the later one wins, then a remove, then the greater
+/// value. Removes carry an empty value.
+pub(crate) fn attribute_wins(ts: isize, value: &str, current_ts: isize, current_value: &str) -> bool {
+    (ts.abs(), ts < 0, value) > (current_ts.abs(), current_ts < 0, current_value)
+}
+
+/// Storage for per-user tag sets with last-write-wins semantics. An operation only takes effect
+/// if its timestamp is newer than the latest one already applied to that tag, and a remove wins
+/// a tie with an add, so operations may be applied in any order and more than once.
+///
+/// Tag operations carry a `source` naming who sent them, which is kept in the user's history.
+///
+/// A tag named `group:tag` belongs to `group`, and all of a user's tags in a group can be
+/// replaced at once with `set_group`.
+pub trait TagBackend: Send + Sync {
+    fn add_tag(&self, user: &str, tag: &str, ts: i64, source: &str) -> Result<(), Error>;
+
+    fn remove_tag(&self, user: &str, tag: &str, ts: i64, source: &str) -> Result<(), Error>;
+
+    /// Adds a tag that is removed again at `expires_at`, exactly as if a remove with that
+    /// timestamp had also been applied. It only takes effect once that time has passed.
+    fn add_expiring_tag(&self, user: &str, tag: &str, ts: i64, expires_at: i64, source: &str) -> Result<(), Error>;
+
+    /// Replaces the user's tags in `group` with `tags` as of `ts`. Tags in the group last changed
+    /// before `ts` are removed, even if their operations only arrive later, and `tags` are added
+    /// at `ts`. Readers see either all of the change or none of it. Fails with
+    /// `ErrorKind::InvalidInput` if `group` is empty or contains `GROUP_SEPARATOR`.
+    fn set_group(&self, user: &str, group: &str, tags: &[String], ts: i64, source: &str) -> Result<(), Error>;
+
+    /// The user's current tags, in no particular order, or `None` if the store holds nothing at
+    /// all for the user. A user whose tags have all been removed has an empty list.
+    fn user_tags(&self, user: &str) -> Result<Option<Vec<String>>, Error>;
+
+    /// Sets the user's attribute `key` to `value`, unless it was set or removed later than `ts`.
+    /// Of two values set at the same time the greater wins, so the order they arrive in doesn't
+    /// matter.
+    fn set_attribute(&self, user: &str, key: &str, value: &str, ts: i64) -> Result<(), Error>;
+
+    /// Removes the user's attribute `key`, unless it was set later than `ts`.
+    fn remove_attribute(&self, user: &str, key: &str, ts: i64) -> Result<(), Error>;
+
+    /// The user's current attributes, or `None` if the store holds no attributes at all for the
+    /// user, removed or not.
+    fn user_attributes(&self, user: &str) -> Result<Option<HashMap<String, String>>, Error>;
+
+    /// The user's current tags, in no particular order.
+    fn tags_for_user(&self, user: &str) -> Result<Vec<String>, Error> {
+        self.user_tags(user).map(Option::unwrap_or_default)
+    }
+
+    /// Up to `limit` of the users currently holding `tag`, in order, starting after the user
+    /// `after`. Pass the previous page's `next` as `after` to fetch the following page.
+    ///
+    /// Backends without an index of users by tag scan everything they store for every page, so
+    /// paging through a tag costs a full read of the store per page.
+    fn users_with_tag(&self, tag: &str, after: Option<&str>, limit: usize) -> Result<UserPage, Error>;
+
+    /// Every user matching `expr`. `NOT` is relative to the users holding at least one tag. Like
+    /// `users_with_tag`, this may cost a full read of the store.
+    fn audience(&self, expr: &Expr) -> Result<BTreeSet<String>, Error>;
+
+    /// Up to `limit` of the users matching `expr`, paged like `users_with_tag`.
+    fn users_matching(&self, expr: &Expr, after: Option<&str>, limit: usize) -> Result<UserPage, Error> {
+        self.audience(expr).map(|users| UserPage::from_set(&users, after, limit))
+    }
+
+    /// The user's latest tag operations, oldest first, whether they were applied or rejected as
+    /// stale, or `None` if the store holds nothing for the user. Backends that keep no history
+    /// fail with `ErrorKind::Unsupported`.
+    fn history(&self, _user: &str) -> Result<Option<Vec<Change>>, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "History is not kept by this backend"))
+    }
+
+    /// Where the backend publishes every change to which users hold which tags, if it does.
+    fn events(&self) -> Option<&EventBus> {
+        None
+    }
+
+    /// The digests of `nodes` of a Merkle tree over the store's contents, with users bucketed by
+    /// `bucket_of`. Stores holding the same state for the users under a node have the same digest
+    /// for it. Backends that keep no tree fail with `ErrorKind::Unsupported`.
+    fn merkle_digests(&self, _nodes: &[usize]) -> Result<Vec<Digest>, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "No Merkle tree is kept by this backend"))
+    }
+
+    /// Everything held for the users in `buckets`, tombstones included, as operations that
+    /// recreate it when merged into another store.
+    fn bucket_contents(&self, _buckets: &[usize]) -> Result<Vec<Operation>, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "No Merkle tree is kept by this backend"))
+    }
+
+    /// Applies operations taken from another store's contents, such as by repair between
+    /// replicas. Backends that keep history leave them out of it.
+    fn merge(&self, ops: &[Operation]) -> Result<(), Error> {
+        for op in ops.iter() {
+            op.apply(self)?;
+        }
+        Ok(())
+    }
+
+    /// Turns tags whose expiry has passed into tombstones, returning how many were. Expired tags
+    /// are hidden from `user_tags` either way, but backends that need this to drop them from
+    /// by-tag lookups and audiences implement it.
+    fn expire_tags(&self) -> Result<u64, Error> {
+        Ok(0)
+    }
+
+    /// Compacts everything stored so far into a snapshot that recovery can start from.
+    fn snapshot(&self) -> Result<SnapshotStats, Error>;
+
+    /// Drops tombstones older than `horizon`. Operations older than that may be ignored from then
+    /// on, since the removes they would have lost to can no longer be seen. Backends that keep
+    /// every tombstone reclaim nothing.
+    fn collect_garbage(&self, _horizon: Duration) -> Result<GcStats, Error> {
+        Ok(GcStats::default())
+    }
+
+    /// Totals reclaimed by every garbage collection so far.
+    fn gc_totals(&self) -> GcStats {
+        GcStats::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsm::LsmStore;
+    use tag_store::TagStore;
+    use wal::SyncPolicy;
+    use wal::tests::scratch_dir;
+
+    /// Sets of an attribute, or removes where the value is `None`, with their timestamps.
+    const WRITES: &[(Option<&str>, i64)] = &[
+        (Some("gold"), 1_000),
+        (Some("silver"), 1_000),
+        (Some("bronze"), 999),
+        (Some("iron"), 1_001),
+        (None, 1_001),
+    ];
+
+    /// Every order of `0..n`.
+    fn orders(n: usize) -> Vec<Vec<usize>> {
+        if n == 0 {
+            return vec![Vec::new()];
+        }
+
+        let mut orders = Vec::new();
+        for order in self::orders(n - 1) {
+            for i in 0..n {
+                let mut order = order.clone();
+                order.insert(i, n - 1);
+                orders.push(order);
+            }
+        }
+        orders
+    }
+
+    /// Applies `writes` in every order, each to its own user named after `name`, calling `halfway`
+    /// in the middle of each, and returns every user's value for the attribute.
+    fn apply_in_every_order(backend: &dyn TagBackend, name: &str, writes: &[(Option<&str>, i64)], halfway: &dyn Fn())
+                            -> Vec<Option<String>> {
+        orders(writes.len()).iter().enumerate()
+            .map(|(i, order)| {
+                let user = format!("{}-{}", name, i);
+                for (n, &write) in order.iter().enumerate() {
+                    if n == order.len() / 2 {
+                        halfway();
+                    }
+
+                    match writes[write] {
+                        (Some(value), ts) => backend.set_attribute(&user, "plan", value, ts).unwrap(),
+                        (None, ts) => backend.remove_attribute(&user, "plan", ts).unwrap(),
+                    }
+                }
+
+                backend.user_attributes(&user).unwrap().unwrap().remove("plan")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn attribute_ties_go_to_removes_then_greater_values() {
+        // The later write wins whatever it is
+        assert!(attribute_wins(1_001, "a", 1_000, "b"));
+        assert!(attribute_wins(-1_001, "", 1_000, "b"));
+        assert!(!attribute_wins(999, "z", 1_000, "b"));
+        assert!(!attribute_wins(999, "z", -1_000, ""));
+        // At the same time a remove wins, then the greater value
+        assert!(attribute_wins(-1_000, "", 1_000, "b"));
+        assert!(!attribute_wins(1_000, "z", -1_000, ""));
+        assert!(attribute_wins(1_000, "c", 1_000, "b"));
+        assert!(!attribute_wins(1_000, "a", 1_000, "b"));
+        // The same write again changes nothing
+        assert!(!attribute_wins(1_000, "b", 1_000, "b"));
+        assert!(!attribute_wins(-1_000, "", -1_000, ""));
+    }
+
+    #[test]
+    fn attribute_writes_commute_in_tag_store() {
+        let store = TagStore::new();
+
+        let values = apply_in_every_order(&store, "removed", WRITES, &|| {});
+        assert!(values.iter().all(Option::is_none), "{:?}", values);
+
+        let values = apply_in_every_order(&store, "set", &WRITES[..3], &|| {});
+        assert!(values.iter().all(|value| value.as_deref() == Some("silver")), "{:?}", values);
+    }
+
+    #[test]
+    fn attribute_writes_commute_in_lsm_store() {
+        let dir = scratch_dir("lsm-attributes");
+        let store = LsmStore::open(&dir, SyncPolicy::Never).unwrap();
+        // Half of each order's writes end up in a table, so states are merged across both
+        let flush = || { store.snapshot().unwrap(); };
+
+        let values = apply_in_every_order(&store, "removed", WRITES, &flush);
+        assert!(values.iter().all(Option::is_none), "{:?}", values);
+
+        let values = apply_in_every_order(&store, "set", &WRITES[..3], &flush);
+        assert!(values.iter().all(|value| value.as_deref() == Some("silver")), "{:?}", values);
+    }
+
+    #[test]
+    fn expiries_act_as_removes_once_due() {
+        // No expiry, or one not due yet
+        assert!(is_present(1_000, 0, 5_000));
+        assert!(is_present(1_000, 6_000, 5_000));
+        // Due, and later than the add
+        assert!(!is_present(1_000, 5_000, 5_000));
+        assert!(!is_present(1_000, 1_000, 5_000));
+        // Overtaken by a later add
+        assert!(is_present(4_000, 3_000, 5_000));
+        // Removed either way
+        assert!(!is_present(-1_000, 0, 5_000));
+        assert!(!is_present(-1_000, 6_000, 5_000));
+    }
+}
@@ -1,244 +1,1345 @@
 extern crate rust_tag_server;
 extern crate http;
 extern crate chrono;
+extern crate ctrlc;
 
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
 extern crate serde_json;
 
-use rust_tag_server::httpd::{WebServer, Handler, Router, Request};
+use rust_tag_server::httpd::{WebServer, Handler, Router, Request, EventStream};
+use rust_tag_server::tags::{TagBackend, TagStore, LsmStore, SyncPolicy, Expr, ChangeKind, EventBus, GROUP_SEPARATOR,
+                             WebhookDispatcher, Endpoint, WebhookConfig, ReplicatedBackend, ReplicationConfig,
+                             ReplicationHandler, MerkleHandler, BucketsHandler, PeerStatus, REPLICATION_PATH,
+                             MERKLE_PATH, BUCKETS_PATH, format_timestamp};
 use http::StatusCode;
-use std::io::{Write, Error, Read};
-use std::sync::{Arc, RwLock};
-use std::collections::HashMap;
-use std::sync::atomic::Ordering;
-use std::sync::atomic::AtomicIsize;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::io::{Write, Error, ErrorKind, BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 use chrono::{DateTime, FixedOffset};
 
 #[derive(Serialize, Deserialize)]
 struct TagRequest {
     pub user: String,
-    pub add: Vec<String>,
+    pub add: Vec<AddedTag>,
     pub remove: Vec<String>,
+    /// Attributes to set, by key.
+    #[serde(default)]
+    pub set: HashMap<String, String>,
+    /// Keys of attributes to remove.
+    #[serde(default)]
+    pub unset: Vec<String>,
+    /// Changes to tags in groups, by group.
+    #[serde(default)]
+    pub groups: HashMap<String, GroupRequest>,
     pub timestamp: String,
+    /// Who sent the request, as recorded in the user's history.
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+/// Changes to a user's tags in one group, named without the group. `set` replaces every tag in
+/// the group before `add` and `remove` apply.
+#[derive(Serialize, Deserialize)]
+struct GroupRequest {
+    #[serde(default)]
+    pub add: Vec<AddedTag>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+    #[serde(default)]
+    pub set: Option<Vec<String>>,
+}
+
+/// A tag to add, either just its name or with the time it should be removed again.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum AddedTag {
+    Plain(String),
+    Expiring {
+        tag: String,
+        expires_at: String,
+    },
+}
+
+impl AddedTag {
+    /// The tag and its expiry, if it has one, checking that it expires after `ts`.
+    fn parse(self, ts: i64) -> Result<(String, Option<i64>), &'static str> {
+        match self {
+            AddedTag::Plain(tag) => Ok((tag, None)),
+            AddedTag::Expiring { tag, expires_at } => {
+                let expires_at = parse_timestamp(&expires_at).ok_or(EXPIRY_PARSE_ERROR)?;
+                if expires_at <= ts {
+                    return Err(EXPIRY_ORDER_ERROR);
+                }
+                Ok((tag, Some(expires_at)))
+            }
+        }
+    }
+}
+
+/// A `TagRequest` with its times parsed into milliseconds since the epoch.
+struct TagUpdate {
+    user: String,
+    add: Vec<(String, Option<i64>)>,
+    remove: Vec<String>,
+    set: HashMap<String, String>,
+    unset: Vec<String>,
+    /// Groups to replace, with their new tags.
+    set_groups: Vec<(String, Vec<String>)>,
+    ts: i64,
+    source: String,
 }
 
 #[derive(Serialize, Deserialize)]
 struct TagResponse {
     pub user: String,
     pub tags: Vec<String>,
+    pub attributes: BTreeMap<String, String>,
+}
+
+/// The outcome of one item in a batch, in the order the items were sent.
+#[derive(Serialize, Deserialize)]
+struct BatchItemResponse {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BatchResponse {
+    pub applied: usize,
+    pub failed: usize,
+    pub results: Vec<BatchItemResponse>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UsersByTagResponse {
+    pub tag: String,
+    pub users: Vec<String>,
+    pub total: usize,
+    pub next: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AudienceResponse {
+    pub query: String,
+    pub users: Vec<String>,
+    pub total: usize,
+    pub next: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HistoryResponse {
+    pub user: String,
+    pub changes: Vec<ChangeResponse>,
+}
+
+/// One operation in a user's history, with its times in ISO 8601.
+#[derive(Serialize, Deserialize)]
+struct ChangeResponse {
+    /// `add`, `remove` or `replace_group`.
+    pub op: String,
+    /// The tag, or the group for `replace_group`.
+    pub tag: String,
+    pub timestamp: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+    pub received_at: String,
+    pub source: String,
+    /// `applied`, or `stale` if a later operation had already been applied.
+    pub outcome: String,
+}
+
+/// The data of an `add` or `remove` event.
+#[derive(Serialize, Deserialize)]
+struct TagEventResponse {
+    pub user: String,
+    pub tag: String,
+    pub timestamp: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotResponse {
+    pub generation: u64,
+    pub users: usize,
+    pub entries: u64,
+}
+
+/// How replication to one peer is getting on, as listed at `/admin/replication`.
+#[derive(Serialize, Deserialize)]
+struct PeerResponse {
+    pub url: String,
+    pub pending: usize,
+    pub sent: u64,
+    pub dropped: u64,
+    pub rejected: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct GcResponse {
+    pub tombstones: u64,
+    pub users: u64,
+}
+
+/// An entry in the file named by `TAG_WEBHOOKS`.
+#[derive(Deserialize)]
+struct WebhookEndpoint {
+    pub url: String,
+    pub secret: String,
 }
 
 struct TagHandler {
-    tag_store: TagStore,
+    tag_store: Arc<dyn TagBackend>,
+}
+
+/// Applies many `TagRequest`s at once, sent as a JSON array or as newline-delimited JSON.
+struct BatchHandler {
+    tag_store: Arc<dyn TagBackend>,
+}
+
+/// Reads a user's tags from `/api/tags/{user}` or `/api/tags?user={user}`.
+struct UserTagsHandler {
+    tag_store: Arc<dyn TagBackend>,
+}
+
+/// Lists a user's latest tag operations, applied or not, at `/api/tags/{user}/history`.
+struct HistoryHandler {
+    tag_store: Arc<dyn TagBackend>,
+}
+
+/// Pages through the users holding a tag at `/api/tags/by-tag/{tag}?after={user}&limit={n}`.
+struct UsersByTagHandler {
+    tag_store: Arc<dyn TagBackend>,
+}
+
+/// Pages through the users matching a tag expression at `/api/audience?q={expr}`.
+struct AudienceHandler {
+    tag_store: Arc<dyn TagBackend>,
+}
+
+/// Streams every change to which users hold which tags as Server-Sent Events at `/api/events`.
+/// A client reconnecting with the `Last-Event-ID` header, or a `last_event_id` query parameter,
+/// resumes after that event.
+struct EventsHandler {
+    tag_store: Arc<dyn TagBackend>,
+    streams: AtomicUsize,
+}
+
+struct SnapshotHandler {
+    tag_store: Arc<dyn TagBackend>,
+}
+
+struct GcMetricsHandler {
+    tag_store: Arc<dyn TagBackend>,
+}
+
+struct ReplicationStatusHandler {
+    replicated: Arc<ReplicatedBackend>,
 }
 
 const MISSING_BODY_ERROR: &str = "Request had no body";
 const JSON_PARSE_ERROR: &str = "Couldn't parse request JSON";
 const TS_PARSE_ERROR: &str = "Couldn't parse timestamp, expected zoned ISO 8601";
+const TS_RANGE_ERROR: &str = "Expected timestamp to be after 1970-01-01T00:00:00Z";
+const EXPIRY_PARSE_ERROR: &str = "Couldn't parse expires_at, expected zoned ISO 8601";
+const EXPIRY_ORDER_ERROR: &str = "Expected expires_at to be after timestamp";
+const MISSING_USER_ERROR: &str = "Expected a user in the path or a user query parameter";
+const UNKNOWN_USER_ERROR: &str = "Unknown user";
+const TAG_PARSE_ERROR: &str = "Couldn't decode tag";
+const AFTER_PARSE_ERROR: &str = "Couldn't decode after";
+const MISSING_QUERY_ERROR: &str = "Expected a tag expression in the q query parameter";
+const LIMIT_PARSE_ERROR: &str = "Expected limit to be a positive integer";
+const GROUP_NAME_ERROR: &str = "Expected group names to be non-empty and without ':'";
+const TAG_NAME_ERROR: &str = "Expected tags outside groups to be without ':'";
+const SOURCE_ERROR: &str = "Expected source to be between 1 and 64 bytes";
+const USER_PARSE_ERROR: &str = "Couldn't decode user";
+const NO_HISTORY_ERROR: &str = "History isn't kept by this backend";
+const NO_EVENTS_ERROR: &str = "Events aren't published by this backend";
+const TOO_MANY_STREAMS_ERROR: &str = "Too many event streams are open";
+/// Sent as a `reset` event when a client has missed events and must read tags afresh.
+const EVENTS_MISSED: &str = "Events were missed, reload any tags you depend on";
+
+/// Sources recorded for requests that don't name one.
+const API_SOURCE: &str = "api";
+const BATCH_SOURCE: &str = "batch";
+const MAX_SOURCE_LEN: usize = 64;
+
+/// Event streams served at once, each of which holds a worker for as long as it's open.
+const MAX_EVENT_STREAMS: usize = 16;
+/// How long an event stream may sit idle before a comment is sent to keep it open.
+const EVENT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+const DEFAULT_PAGE_LIMIT: usize = 100;
+const MAX_PAGE_LIMIT: usize = 1000;
+
+const DATA_DIR: &str = "tag-data";
+/// Environment variable choosing the storage backend: `memory` (the default) or `lsm`.
+const BACKEND_VAR: &str = "TAG_BACKEND";
+/// Environment variable naming a JSON file listing the webhooks to post tag changes to, as
+/// `[{"url": ..., "secret": ...}]`.
+const WEBHOOKS_VAR: &str = "TAG_WEBHOOKS";
+const WEBHOOK_DEAD_LETTERS: &str = "webhooks.dead";
+/// Environment variable listing the other nodes to replicate with, as comma-separated base URLs
+/// such as `http://10.0.0.2:8080`. Every node must list every other.
+const PEERS_VAR: &str = "TAG_PEERS";
+/// Environment variable setting the address to listen on, `127.0.0.1:8080` by default.
+const ADDR_VAR: &str = "TAG_ADDR";
+const DEFAULT_ADDR: &str = "127.0.0.1:8080";
+const WAL_SYNC_INTERVAL: Duration = Duration::from_millis(100);
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const GC_INTERVAL: Duration = Duration::from_secs(60);
+/// How often each node compares Merkle trees with its peers to repair anything replication lost.
+const REPAIR_INTERVAL: Duration = Duration::from_secs(60);
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+/// How long tombstones are kept, and so how late an operation can arrive and still apply.
+const GC_HORIZON: Duration = Duration::from_secs(7 * 24 * 60 * 60);
 
 impl Handler for TagHandler {
     fn handle(&self, request: &mut Request) -> Result<(), Error> {
-        let length = {
-            if let Some(length) = request.get_request_header("Content-Length") {
-                if let Ok(length) = length.parse::<i32>() {
-                    length
-                } else {
-                    -1
-                }
-            } else {
-                -1
-            }
-        };
-
-        if length >= 0 {
-            let mut vec = vec![b'\n'; length as usize];
-            let buf = vec.as_mut_slice();
-            request.reader.read_exact(buf)?;
+        if request.has_body() {
+            let body = request.read_body()?;
 
-            let tag_request: TagRequest = match serde_json::from_slice(buf) {
+            let tag_request: TagRequest = match serde_json::from_slice(&body) {
                 Ok(tag_request) => tag_request,
                 Err(_) => {
                     let err = JSON_PARSE_ERROR.as_bytes();
                     request.send_preamble(StatusCode::BAD_REQUEST, err.len())?;
-                    request.write(err)?;
+                    request.write_all(err)?;
                     return Ok(())
                 }
             };
 
-            let ts = match tag_request.timestamp.parse::<DateTime<FixedOffset>>() {
-                Ok(datetime) => datetime.timestamp_millis(),
-                Err(_) => {
-                    let err = TS_PARSE_ERROR.as_bytes();
+            let update = match tag_request.parse(API_SOURCE) {
+                Ok(update) => update,
+                Err(err) => {
+                    let err = err.as_bytes();
                     request.send_preamble(StatusCode::BAD_REQUEST, err.len())?;
-                    request.write(err)?;
+                    request.write_all(err)?;
                     return Ok(());
                 },
             };
 
-            for tag in tag_request.add {
-                if !tag_request.remove.contains(&tag) {
-                    self.tag_store.add_tag(&tag_request.user, &tag, ts);
-                }
-            }
-
-            for tag in tag_request.remove {
-                self.tag_store.remove_tag(&tag_request.user, &tag, ts)
-            }
-
-            let response = TagResponse {
-                user: tag_request.user.clone(),
-                tags: self.tag_store.tags_for_user(&tag_request.user)
-            };
-
-            let response = match serde_json::to_vec(&response) {
-                Ok(response) => response,
-                Err(e) => {
-                    request.send_preamble(StatusCode::INTERNAL_SERVER_ERROR, buf.len())?;
-                    return Err(e.into())
-                },
-            };
+            let response = apply_tag_update(&*self.tag_store, update)?;
+            let response = serde_json::to_vec(&response)?;
 
             match request.send_preamble(StatusCode::OK, response.len()) {
                 Ok(()) => {
-                    request.write(&response[..])?;
+                    request.write_all(&response[..])?;
                 }
                 Err(a) => return Err(a)
             }
         } else {
             let err = MISSING_BODY_ERROR.as_bytes();
             request.send_preamble(StatusCode::BAD_REQUEST, err.len())?;
-            request.write(err)?;
+            request.write_all(err)?;
         }
 
         Ok(())
     }
 }
 
-fn main() {
-    let mut router = Router::new();
-    router.add_route("/api/tags", "POST", TagHandler{
-        tag_store: TagStore::new()
-    });
+impl Handler for BatchHandler {
+    fn handle(&self, request: &mut Request) -> Result<(), Error> {
+        if !request.has_body() {
+            return send_text(request, StatusCode::BAD_REQUEST, MISSING_BODY_ERROR);
+        }
 
-    let server = WebServer::new("127.0.0.1:8080", router, 100, 10000, |err| { eprintln!("{}", err) })
-        .expect("Welp");
+        let items = match read_batch(BufReader::new(&mut *request))? {
+            Some(items) => items,
+            None => return send_text(request, StatusCode::BAD_REQUEST, JSON_PARSE_ERROR),
+        };
 
-    server.run();
-}
+        let mut response = BatchResponse {
+            applied: 0,
+            failed: 0,
+            results: Vec::with_capacity(items.len()),
+        };
+
+        for (index, item) in items.into_iter().enumerate() {
+            let result = self.apply_item(index, item);
+            if result.status == StatusCode::OK.as_u16() {
+                response.applied += 1;
+            } else {
+                response.failed += 1;
+            }
+            response.results.push(result);
+        }
+
+        let response = serde_json::to_vec(&response)?;
 
-pub struct TagStore {
-    store: RwLock<HashMap<String, Arc<RwLock<HashMap<String, Arc<AtomicIsize>>>>>>
+        request.send_preamble(StatusCode::OK, response.len())?;
+        request.write_all(&response)
+    }
 }
 
-impl TagStore {
-    pub fn new() -> TagStore {
-        TagStore {
-            store: RwLock::new(HashMap::new()),
+impl BatchHandler {
+    fn apply_item(&self, index: usize, item: Result<TagRequest, serde_json::Error>) -> BatchItemResponse {
+        let failure = |user, status: StatusCode, error: &str| BatchItemResponse {
+            index,
+            user,
+            status: status.as_u16(),
+            error: Some(String::from(error)),
+            tags: None,
+            attributes: None,
+        };
+
+        let tag_request = match item {
+            Ok(tag_request) => tag_request,
+            Err(_) => return failure(None, StatusCode::BAD_REQUEST, JSON_PARSE_ERROR),
+        };
+
+        let user = tag_request.user.clone();
+        let update = match tag_request.parse(BATCH_SOURCE) {
+            Ok(update) => update,
+            Err(err) => return failure(Some(user), StatusCode::BAD_REQUEST, err),
+        };
+
+        match apply_tag_update(&*self.tag_store, update) {
+            Ok(response) => BatchItemResponse {
+                index,
+                user: Some(response.user),
+                status: StatusCode::OK.as_u16(),
+                error: None,
+                tags: Some(response.tags),
+                attributes: Some(response.attributes),
+            },
+            Err(e) => failure(Some(user), StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
         }
     }
+}
+
+impl Handler for UserTagsHandler {
+    fn handle(&self, request: &mut Request) -> Result<(), Error> {
+        let user = match request.get_path_param("user") {
+            Some(user) => percent_decode(user, false),
+            None => request.query_params.get("user")
+                .and_then(|users| users.first())
+                .and_then(|user| percent_decode(user, true)),
+        };
 
-    pub fn tags_for_user(&self, user: &String) -> Vec<String> {
-        match self.store.read().unwrap().get(user) {
-            None => Vec::with_capacity(1),
-            Some(tags) => {
-                let user_tags = tags.read().unwrap();
+        let user = match user {
+            Some(user) => user,
+            None => return send_text(request, StatusCode::BAD_REQUEST, MISSING_USER_ERROR),
+        };
 
-                let mut tags = Vec::with_capacity(user_tags.len());
-                for (tag, ts) in user_tags.iter() {
-                    if ts.load(Ordering::Relaxed) > 0 {
-                        tags.push(tag.clone());
-                    }
-                }
-                tags
+        let (tags, attributes) = match (self.tag_store.user_tags(&user)?, self.tag_store.user_attributes(&user)?) {
+            (None, None) => return send_text(request, StatusCode::NOT_FOUND, UNKNOWN_USER_ERROR),
+            (tags, attributes) => (tags.unwrap_or_default(), attributes.unwrap_or_default()),
+        };
+
+        let response = serde_json::to_vec(&TagResponse {
+            user,
+            tags,
+            attributes: attributes.into_iter().collect(),
+        })?;
+
+        request.send_preamble(StatusCode::OK, response.len())?;
+        request.write_all(&response)
+    }
+}
+
+impl Handler for HistoryHandler {
+    fn handle(&self, request: &mut Request) -> Result<(), Error> {
+        let user = match request.get_path_param("user").and_then(|user| percent_decode(user, false)) {
+            Some(user) => user,
+            None => return send_text(request, StatusCode::BAD_REQUEST, USER_PARSE_ERROR),
+        };
+
+        let changes = match self.tag_store.history(&user) {
+            Ok(Some(changes)) => changes,
+            Ok(None) => return send_text(request, StatusCode::NOT_FOUND, UNKNOWN_USER_ERROR),
+            Err(ref e) if e.kind() == ErrorKind::Unsupported => {
+                return send_text(request, StatusCode::NOT_IMPLEMENTED, NO_HISTORY_ERROR)
             }
+            Err(e) => return Err(e),
+        };
+
+        let changes = changes.into_iter().map(|change| ChangeResponse {
+            op: String::from(match change.kind {
+                ChangeKind::Add => "add",
+                ChangeKind::Remove => "remove",
+                ChangeKind::ReplaceGroup => "replace_group",
+            }),
+            tag: change.tag,
+            timestamp: format_timestamp(change.timestamp),
+            expires_at: change.expires_at.map(format_timestamp),
+            received_at: format_timestamp(change.received_at),
+            source: change.source,
+            outcome: String::from(if change.applied { "applied" } else { "stale" }),
+        }).collect();
+
+        let response = serde_json::to_vec(&HistoryResponse { user, changes })?;
+
+        request.send_preamble(StatusCode::OK, response.len())?;
+        request.write_all(&response)
+    }
+}
+
+impl Handler for UsersByTagHandler {
+    fn handle(&self, request: &mut Request) -> Result<(), Error> {
+        let tag = match request.get_path_param("tag").and_then(|tag| percent_decode(tag, false)) {
+            Some(tag) => tag,
+            None => return send_text(request, StatusCode::BAD_REQUEST, TAG_PARSE_ERROR),
+        };
+
+        let (after, limit) = match page_params(request) {
+            Ok(params) => params,
+            Err(err) => return send_text(request, StatusCode::BAD_REQUEST, err),
+        };
+
+        let page = self.tag_store.users_with_tag(&tag, after.as_deref(), limit)?;
+
+        let response = serde_json::to_vec(&UsersByTagResponse {
+            tag,
+            users: page.users,
+            total: page.total,
+            next: page.next,
+        })?;
+
+        request.send_preamble(StatusCode::OK, response.len())?;
+        request.write_all(&response)
+    }
+}
+
+impl Handler for AudienceHandler {
+    fn handle(&self, request: &mut Request) -> Result<(), Error> {
+        let query = match request.query_params.get("q").and_then(|q| q.first()).and_then(|q| percent_decode(q, true)) {
+            Some(query) => query,
+            None => return send_text(request, StatusCode::BAD_REQUEST, MISSING_QUERY_ERROR),
+        };
+
+        let expr = match Expr::parse(&query) {
+            Ok(expr) => expr,
+            Err(err) => return send_text(request, StatusCode::BAD_REQUEST, &err.to_string()),
+        };
+
+        let (after, limit) = match page_params(request) {
+            Ok(params) => params,
+            Err(err) => return send_text(request, StatusCode::BAD_REQUEST, err),
+        };
+
+        let page = self.tag_store.users_matching(&expr, after.as_deref(), limit)?;
+
+        let response = serde_json::to_vec(&AudienceResponse {
+            query,
+            users: page.users,
+            total: page.total,
+            next: page.next,
+        })?;
+
+        request.send_preamble(StatusCode::OK, response.len())?;
+        request.write_all(&response)
+    }
+}
+
+impl Handler for EventsHandler {
+    fn handle(&self, request: &mut Request) -> Result<(), Error> {
+        let bus = match self.tag_store.events() {
+            Some(bus) => bus,
+            None => return send_text(request, StatusCode::NOT_IMPLEMENTED, NO_EVENTS_ERROR),
+        };
+
+        if self.streams.fetch_add(1, Ordering::SeqCst) >= MAX_EVENT_STREAMS {
+            self.streams.fetch_sub(1, Ordering::SeqCst);
+            return send_text(request, StatusCode::SERVICE_UNAVAILABLE, TOO_MANY_STREAMS_ERROR);
+        }
+
+        let result = stream_events(request, bus);
+        self.streams.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+}
+
+/// Sends events as they're published, until the bus is closed or the client goes away. Without
+/// an ID to resume from, the stream starts with the next event.
+fn stream_events(request: &mut Request, bus: &EventBus) -> Result<(), Error> {
+    let last_event_id = match request.get_request_header("Last-Event-ID") {
+        Some(id) => Some(id.clone()),
+        None => request.query_params.get("last_event_id")
+            .and_then(|ids| ids.first())
+            .and_then(|id| percent_decode(id, true)),
+    };
+
+    // An ID from an earlier run, or one not issued yet, can't be resumed from
+    let latest = bus.latest();
+    let (mut after, missed) = match last_event_id {
+        None => (latest, false),
+        Some(id) => match bus.parse_external_id(&id) {
+            Some(after) if after <= latest => (after, false),
+            _ => (latest, true),
+        },
+    };
+
+    let mut stream = EventStream::start(request)?;
+    if missed {
+        stream.send(None, Some("reset"), EVENTS_MISSED)?;
+    }
+
+    while let Some(batch) = bus.wait(after, EVENT_KEEPALIVE_INTERVAL) {
+        if batch.missed {
+            stream.send(None, Some("reset"), EVENTS_MISSED)?;
+        }
+
+        if batch.events.is_empty() {
+            stream.comment("keepalive")?;
+        }
+
+        for event in batch.events {
+            let data = serde_json::to_string(&TagEventResponse {
+                user: event.user,
+                tag: event.tag,
+                timestamp: format_timestamp(event.timestamp),
+            })?;
+
+            stream.send(Some(&bus.external_id(event.id)), Some(if event.present { "add" } else { "remove" }), &data)?;
+            after = event.id;
         }
     }
 
-    pub fn add_tag(&self, user: &String, tag: &String, ts: i64) {
-        if ts > isize::max_value() as i64 {
-            panic!("This program must be run on a 64bit system")
+    Ok(())
+}
+
+impl Handler for SnapshotHandler {
+    fn handle(&self, request: &mut Request) -> Result<(), Error> {
+        let stats = self.tag_store.snapshot()?;
+
+        let response = serde_json::to_vec(&SnapshotResponse {
+            generation: stats.generation,
+            users: stats.users,
+            entries: stats.entries,
+        })?;
+
+        request.send_preamble(StatusCode::OK, response.len())?;
+        request.write_all(&response)
+    }
+}
+
+impl Handler for GcMetricsHandler {
+    fn handle(&self, request: &mut Request) -> Result<(), Error> {
+        let totals = self.tag_store.gc_totals();
+
+        let response = serde_json::to_vec(&GcResponse {
+            tombstones: totals.tombstones,
+            users: totals.users,
+        })?;
+
+        request.send_preamble(StatusCode::OK, response.len())?;
+        request.write_all(&response)
+    }
+}
+
+impl Handler for ReplicationStatusHandler {
+    fn handle(&self, request: &mut Request) -> Result<(), Error> {
+        let peers: Vec<PeerResponse> = self.replicated.status().into_iter()
+            .map(|status: PeerStatus| PeerResponse {
+                url: status.url,
+                pending: status.pending,
+                sent: status.sent,
+                dropped: status.dropped,
+                rejected: status.rejected,
+                last_error: status.last_error,
+            })
+            .collect();
+        let response = serde_json::to_vec(&peers)?;
+
+        request.send_preamble(StatusCode::OK, response.len())?;
+        request.write_all(&response)
+    }
+}
+
+/// Reads the `after` cursor and page `limit` from the query string.
+fn page_params(request: &Request) -> Result<(Option<String>, usize), &'static str> {
+    let after = match request.query_params.get("after").and_then(|after| after.first()) {
+        Some(after) => Some(percent_decode(after, true).ok_or(AFTER_PARSE_ERROR)?),
+        None => None,
+    };
+
+    let limit = match request.query_params.get("limit").and_then(|limit| limit.first()) {
+        Some(limit) => match limit.parse::<usize>() {
+            Ok(limit) if limit > 0 => limit.min(MAX_PAGE_LIMIT),
+            _ => return Err(LIMIT_PARSE_ERROR),
+        },
+        None => DEFAULT_PAGE_LIMIT,
+    };
+
+    Ok((after, limit))
+}
+
+/// Reads a batch body, either a JSON array of requests or one request per line. Items that don't
+/// parse are kept as errors so the rest still apply, `None` means the body isn't a batch at all.
+fn read_batch<R: BufRead>(mut body: R) -> Result<Option<Vec<Result<TagRequest, serde_json::Error>>>, Error> {
+    let is_array = loop {
+        let buf = body.fill_buf()?;
+        match buf.iter().position(|byte| !byte.is_ascii_whitespace()) {
+            Some(start) => {
+                let is_array = buf[start] == b'[';
+                body.consume(start);
+                break is_array;
+            }
+            None if buf.is_empty() => return Ok(Some(Vec::new())),
+            None => {
+                let len = buf.len();
+                body.consume(len);
+            }
         }
+    };
 
-        let ts = ts as isize;
+    if is_array {
+        let mut json = Vec::new();
+        body.read_to_end(&mut json)?;
 
-        let tag_ts = match self.get_tag(user, tag, ts) {
-            None => return,
-            Some(tag_ts) => tag_ts,
+        let items: Vec<serde_json::Value> = match serde_json::from_slice(&json) {
+            Ok(items) => items,
+            Err(_) => return Ok(None),
         };
 
-        loop {
-            let old_ts = tag_ts.load(Ordering::Acquire).abs();
-            if old_ts >= ts {
-                break;
+        return Ok(Some(items.into_iter().map(serde_json::from_value).collect()));
+    }
+
+    let mut items = Vec::new();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if body.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+
+        if !line.iter().all(u8::is_ascii_whitespace) {
+            items.push(serde_json::from_slice(&line));
+        }
+    }
+
+    Ok(Some(items))
+}
+
+fn parse_timestamp(timestamp: &str) -> Option<i64> {
+    timestamp.parse::<DateTime<FixedOffset>>().ok().map(|datetime| datetime.timestamp_millis())
+}
+
+impl TagRequest {
+    /// Checks the request and parses its times, taking `default_source` as the source if it
+    /// doesn't name one.
+    fn parse(self, default_source: &str) -> Result<TagUpdate, &'static str> {
+        let ts = parse_timestamp(&self.timestamp).ok_or(TS_PARSE_ERROR)?;
+        // Stores tell adds from removes by the sign of their time, and expiries come after it
+        if ts <= 0 {
+            return Err(TS_RANGE_ERROR);
+        }
+
+        let source = self.source.unwrap_or_else(|| String::from(default_source));
+        if source.is_empty() || source.len() > MAX_SOURCE_LEN {
+            return Err(SOURCE_ERROR);
+        }
+
+        // A tag with a separator would read as a member of the group before it
+        let mut add = Vec::with_capacity(self.add.len());
+        for added in self.add {
+            let (tag, expires_at) = added.parse(ts)?;
+            if tag.contains(GROUP_SEPARATOR) {
+                return Err(TAG_NAME_ERROR);
+            }
+            add.push((tag, expires_at));
+        }
+
+        if self.remove.iter().any(|tag| tag.contains(GROUP_SEPARATOR)) {
+            return Err(TAG_NAME_ERROR);
+        }
+
+        let mut remove = self.remove;
+        let mut set_groups = Vec::new();
+        for (group, changes) in self.groups {
+            if group.is_empty() || group.contains(GROUP_SEPARATOR) {
+                return Err(GROUP_NAME_ERROR);
+            }
+
+            let qualify = |tag: &str| format!("{}{}{}", group, GROUP_SEPARATOR, tag);
+            for added in changes.add {
+                let (tag, expires_at) = added.parse(ts)?;
+                add.push((qualify(&tag), expires_at));
             }
+            remove.extend(changes.remove.iter().map(|tag| qualify(tag)));
 
-            if ts == tag_ts.compare_and_swap(old_ts, ts, Ordering::AcqRel) {
-                break;
+            if let Some(tags) = changes.set {
+                set_groups.push((group, tags));
             }
         }
+
+        Ok(TagUpdate {
+            user: self.user,
+            add,
+            remove,
+            set: self.set,
+            unset: self.unset,
+            set_groups,
+            ts,
+            source,
+        })
+    }
+}
+
+/// Applies an update, returning the user's tags and attributes afterwards. Groups are replaced
+/// first. A tag that is both added and removed ends up removed, and likewise an attribute both
+/// set and unset.
+fn apply_tag_update(tag_store: &dyn TagBackend, update: TagUpdate) -> Result<TagResponse, Error> {
+    for (group, tags) in update.set_groups.iter() {
+        tag_store.set_group(&update.user, group, tags, update.ts, &update.source)?;
     }
 
-    pub fn remove_tag(&self, user: &String, tag: &String, ts: i64) {
-        if ts > isize::max_value() as i64 {
-            panic!("This program must be run on a 64bit system")
+    for (tag, expires_at) in update.add.iter() {
+        if update.remove.contains(tag) {
+            continue;
         }
 
-        let ts = ts as isize;
+        match *expires_at {
+            Some(expires_at) => tag_store.add_expiring_tag(&update.user, tag, update.ts, expires_at, &update.source)?,
+            None => tag_store.add_tag(&update.user, tag, update.ts, &update.source)?,
+        }
+    }
 
-        let tag_ts = match self.get_tag(user, tag, -ts) {
-            None => return,
-            Some(tag_ts) => tag_ts,
-        };
+    for tag in update.remove.iter() {
+        tag_store.remove_tag(&update.user, tag, update.ts, &update.source)?;
+    }
 
-        loop {
-            let old_ts = tag_ts.load(Ordering::Acquire);
-            if ts < old_ts.abs() {
-                break;
-            } else if ts == old_ts.abs() && old_ts < 0 {
-                break;
+    for (key, value) in update.set.iter() {
+        if !update.unset.contains(key) {
+            tag_store.set_attribute(&update.user, key, value, update.ts)?;
+        }
+    }
+
+    for key in update.unset.iter() {
+        tag_store.remove_attribute(&update.user, key, update.ts)?;
+    }
+
+    Ok(TagResponse {
+        tags: tag_store.tags_for_user(&update.user)?,
+        attributes: tag_store.user_attributes(&update.user)?.unwrap_or_default().into_iter().collect(),
+        user: update.user,
+    })
+}
+
+fn send_text(request: &mut Request, status: StatusCode, body: &str) -> Result<(), Error> {
+    request.send_preamble(status, body.len())?;
+    request.write_all(body.as_bytes())
+}
+
+/// Decodes `%XX` escapes in a URL component, and `+` for space in query strings. Returns `None`
+/// if they don't decode to UTF-8 or the result is empty.
+fn percent_decode(component: &str, plus_as_space: bool) -> Option<String> {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = component.get(i + 1..i + 3)?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
             }
+        }
+    }
+
+    String::from_utf8(decoded).ok().filter(|decoded| !decoded.is_empty())
+}
+
+/// Routes the tag API and the admin endpoints that don't depend on replication to `tag_store`.
+fn add_api_routes(router: &mut Router, tag_store: &Arc<dyn TagBackend>) {
+    router.add_route("/api/tags", "POST", TagHandler{
+        tag_store: tag_store.clone()
+    });
+    router.add_route("/api/tags/batch", "POST", BatchHandler{
+        tag_store: tag_store.clone()
+    });
+    router.add_route("/api/tags", "GET", UserTagsHandler{
+        tag_store: tag_store.clone()
+    });
+    router.add_route("/api/tags/:user", "GET", UserTagsHandler{
+        tag_store: tag_store.clone()
+    });
+    router.add_route("/api/tags/:user/history", "GET", HistoryHandler{
+        tag_store: tag_store.clone()
+    });
+    router.add_route("/api/tags/by-tag/:tag", "GET", UsersByTagHandler{
+        tag_store: tag_store.clone()
+    });
+    router.add_route("/api/audience", "GET", AudienceHandler{
+        tag_store: tag_store.clone()
+    });
+    router.add_route("/api/events", "GET", EventsHandler{
+        tag_store: tag_store.clone(),
+        streams: AtomicUsize::new(0),
+    });
+    router.add_route("/admin/snapshot", "POST", SnapshotHandler{
+        tag_store: tag_store.clone()
+    });
+    router.add_route("/admin/gc", "GET", GcMetricsHandler{
+        tag_store: tag_store.clone()
+    });
+}
+
+fn main() {
+    let sync = SyncPolicy::Interval(WAL_SYNC_INTERVAL);
+    let mut tag_store: Arc<dyn TagBackend> = match env::var(BACKEND_VAR).as_ref().map(String::as_str) {
+        Ok("lsm") => Arc::new(LsmStore::open(Path::new(DATA_DIR).join("lsm"), sync).expect("Couldn't open tag store")),
+        Ok("memory") | Err(_) => {
+            Arc::new(TagStore::open(Path::new(DATA_DIR).join("memory"), sync).expect("Couldn't open tag store"))
+        }
+        Ok(other) => panic!("Unknown {} {}, expected memory or lsm", BACKEND_VAR, other),
+    };
+
+    let mut router = Router::new();
+
+    let replicated = env::var(PEERS_VAR).ok().map(|peers| {
+        let replicated = Arc::new(ReplicatedBackend::new(tag_store.clone(), ReplicationConfig::default()));
+        for peer in peers.split(',').map(str::trim).filter(|peer| !peer.is_empty()) {
+            replicated.add_peer(peer);
+        }
 
-            if -ts == tag_ts.compare_and_swap(old_ts, -ts, Ordering::AcqRel) {
-                break;
+        router.add_route(REPLICATION_PATH, "POST", ReplicationHandler::new(replicated.clone()));
+        router.add_route(MERKLE_PATH, "POST", MerkleHandler::new(replicated.clone()));
+        router.add_route(BUCKETS_PATH, "POST", BucketsHandler::new(replicated.clone()));
+        router.add_route("/admin/replication", "GET", ReplicationStatusHandler{
+            replicated: replicated.clone()
+        });
+        tag_store = replicated.clone();
+        replicated
+    });
+
+    add_api_routes(&mut router, &tag_store);
+
+    let snapshot_store = tag_store.clone();
+    thread::spawn(move || loop {
+        thread::sleep(SNAPSHOT_INTERVAL);
+        if let Err(e) = snapshot_store.snapshot() {
+            eprintln!("Periodic snapshot failed: {}", e);
+        }
+    });
+
+    let gc_store = tag_store.clone();
+    thread::spawn(move || loop {
+        thread::sleep(GC_INTERVAL);
+        if let Err(e) = gc_store.collect_garbage(GC_HORIZON) {
+            eprintln!("Tombstone collection failed: {}", e);
+        }
+    });
+
+    let expiry_store = tag_store.clone();
+    thread::spawn(move || loop {
+        thread::sleep(EXPIRY_INTERVAL);
+        if let Err(e) = expiry_store.expire_tags() {
+            eprintln!("Expiring tags failed: {}", e);
+        }
+    });
+
+    let webhooks = env::var(WEBHOOKS_VAR).ok().map(|path| {
+        let endpoints: Vec<WebhookEndpoint> = serde_json::from_slice(&fs::read(&path).expect("Couldn't read webhooks"))
+            .expect("Couldn't parse webhooks");
+        let endpoints = endpoints.into_iter()
+            .map(|endpoint| Endpoint { url: endpoint.url, secret: endpoint.secret })
+            .collect();
+
+        WebhookDispatcher::start(tag_store.clone(), endpoints, Path::new(DATA_DIR).join(WEBHOOK_DEAD_LETTERS), WebhookConfig::default())
+            .expect("Couldn't start webhooks")
+    });
+
+    if let Some(replicated) = replicated.clone() {
+        thread::spawn(move || loop {
+            thread::sleep(REPAIR_INTERVAL);
+            if let Err(e) = replicated.repair() {
+                eprintln!("Repair from peers failed: {}", e);
             }
+        });
+    }
+
+    let server = WebServer::new(env::var(ADDR_VAR).unwrap_or_else(|_| String::from(DEFAULT_ADDR)), router, 100, 10000, |err| { eprintln!("{}", err) })
+        .expect("Welp");
+
+    let shutdown = server.shutdown_handle().expect("Couldn't get server address");
+    let events_store = tag_store.clone();
+    ctrlc::set_handler(move || {
+        // Event streams only end when the bus closes, so they'd otherwise hold up the shutdown
+        if let Some(bus) = events_store.events() {
+            bus.close();
+        }
+        shutdown.shutdown();
+    }).expect("Couldn't install SIGTERM/SIGINT handler");
+
+    server.run();
+
+    if let Some(webhooks) = webhooks {
+        webhooks.shutdown();
+    }
+
+    if let Some(replicated) = replicated {
+        replicated.shutdown();
+    }
+
+    if let Err(e) = tag_store.snapshot() {
+        eprintln!("Final snapshot failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_tag_server::httpd::ShutdownHandle;
+    use std::io::{BufReader, Read};
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread::JoinHandle;
+
+    /// The API served on localhost from an in-memory store.
+    struct TestServer {
+        store: Arc<TagStore>,
+        addr: SocketAddr,
+        shutdown: ShutdownHandle,
+        thread: JoinHandle<()>,
+    }
+
+    impl TestServer {
+        fn start() -> TestServer {
+            let store = Arc::new(TagStore::new());
+
+            let mut router = Router::new();
+            add_api_routes(&mut router, &(store.clone() as Arc<dyn TagBackend>));
+
+            // Enough workers to fill every event stream and still answer
+            let server = WebServer::new("127.0.0.1:0", router, MAX_EVENT_STREAMS + 4, 100, |err| eprintln!("{}", err)).unwrap();
+            let addr = server.local_addr().unwrap();
+            let shutdown = server.shutdown_handle().unwrap();
+
+            TestServer { store, addr, shutdown, thread: thread::spawn(move || server.run()) }
         }
+
+        /// Sends a request on its own connection, returning the response's status and body.
+        fn request(&self, method: &str, path: &str, body: &str) -> (u16, String) {
+            let mut stream = TcpStream::connect(self.addr).unwrap();
+            write!(stream, "{} {} HTTP/1.1\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}", method, path, body.len(), body)
+                .unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+
+            let status = response[9..12].parse().unwrap();
+            let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+            (status, String::from(body))
+        }
+
+        fn get(&self, path: &str) -> (u16, String) {
+            self.request("GET", path, "")
+        }
+
+        fn bus(&self) -> &EventBus {
+            self.store.events().unwrap()
+        }
+
+        /// Ends every event stream and stops serving.
+        fn stop(self) {
+            self.bus().close();
+            self.shutdown.shutdown();
+            self.thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn unknown_users_are_not_found() {
+        let server = TestServer::start();
+
+        assert_eq!(server.get("/api/tags/nobody"), (404, String::from(UNKNOWN_USER_ERROR)));
+        assert_eq!(server.get("/api/tags?user=nobody"), (404, String::from(UNKNOWN_USER_ERROR)));
+        assert_eq!(server.get("/api/tags/nobody/history"), (404, String::from(UNKNOWN_USER_ERROR)));
+        assert_eq!(server.get("/api/tags").0, 400);
+
+        server.stop();
+    }
+
+    #[test]
+    fn users_with_no_current_tags_have_an_empty_list() {
+        let server = TestServer::start();
+        server.store.remove_tag("alice", "vip", 1_000, "test").unwrap();
+        server.store.set_attribute("bob", "plan", "gold", 1_000).unwrap();
+
+        let (status, body) = server.get("/api/tags/alice");
+        assert_eq!(status, 200);
+        let response: TagResponse = serde_json::from_str(&body).unwrap();
+        assert!(response.tags.is_empty() && response.attributes.is_empty());
+
+        let (status, body) = server.get("/api/tags/alice/history");
+        assert_eq!(status, 200);
+        let response: HistoryResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(response.changes.len(), 1);
+
+        // Attributes alone make a user known
+        let (status, body) = server.get("/api/tags?user=bob");
+        assert_eq!(status, 200);
+        let response: TagResponse = serde_json::from_str(&body).unwrap();
+        assert!(response.tags.is_empty());
+        assert_eq!(response.attributes["plan"], "gold");
+
+        server.stop();
+    }
+
+    fn add(user: &str, tag: &str) -> String {
+        format!(r#"{{"user": "{}", "add": ["{}"], "remove": [], "timestamp": "2024-01-01T00:00:00Z"}}"#, user, tag)
+    }
+
+    fn users(items: Vec<Result<TagRequest, serde_json::Error>>) -> Vec<Option<String>> {
+        items.into_iter().map(|item| item.ok().map(|request| request.user)).collect()
+    }
+
+    #[test]
+    fn reads_json_arrays_and_ndjson() {
+        let array = format!(" \n\t[{}, {}, 5]", add("alice", "vip"), add("bob", "vip"));
+        let items = read_batch(array.as_bytes()).unwrap().unwrap();
+        assert_eq!(users(items), vec![Some(String::from("alice")), Some(String::from("bob")), None]);
+
+        let ndjson = format!("\n{}\n{}", add("alice", "vip"), add("bob", "vip"));
+        let items = read_batch(ndjson.as_bytes()).unwrap().unwrap();
+        assert_eq!(users(items), vec![Some(String::from("alice")), Some(String::from("bob"))]);
+
+        assert!(read_batch(&b" \r\n "[..]).unwrap().unwrap().is_empty());
+        // An array that doesn't parse can't be split into items
+        assert!(read_batch(&b"[{\"user\": "[..]).unwrap().is_none());
+    }
+
+    #[test]
+    fn skips_blank_ndjson_lines_and_keeps_invalid_ones() {
+        let ndjson = format!("{}\n\n  \r\n{{\"user\": \n{}\r\n", add("alice", "vip"), add("bob", "vip"));
+        let items = read_batch(ndjson.as_bytes()).unwrap().unwrap();
+        assert_eq!(users(items), vec![Some(String::from("alice")), None, Some(String::from("bob"))]);
+    }
+
+    #[test]
+    fn reports_a_result_for_every_item() {
+        let server = TestServer::start();
+
+        let body = [
+            add("alice", "vip"),
+            String::from("not json"),
+            add("bob", "vip").replace("2024-01-01T00:00:00Z", "yesterday"),
+            add("carol", "vip").replace(r#""timestamp""#, r#""groups": {"a:b": {"add": ["x"]}}, "timestamp""#),
+            add("dave", "vip"),
+        ].join("\n");
+
+        let (status, body) = server.request("POST", "/api/tags/batch", &body);
+        assert_eq!(status, 200);
+        let response: BatchResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!((response.applied, response.failed), (2, 3));
+
+        let results: Vec<(usize, Option<&str>, u16, Option<&str>)> = response.results.iter()
+            .map(|result| (result.index, result.user.as_deref(), result.status, result.error.as_deref()))
+            .collect();
+        assert_eq!(results, vec![
+            (0, Some("alice"), 200, None),
+            (1, None, 400, Some(JSON_PARSE_ERROR)),
+            (2, Some("bob"), 400, Some(TS_PARSE_ERROR)),
+            (3, Some("carol"), 400, Some(GROUP_NAME_ERROR)),
+            (4, Some("dave"), 200, None),
+        ]);
+        assert_eq!(response.results[4].tags, Some(vec![String::from("vip")]));
+
+        // Failed items leave the store alone, and don't stop the rest
+        assert_eq!(server.store.user_tags("bob"), None);
+        assert_eq!(server.store.user_tags("carol"), None);
+        assert_eq!(server.store.user_tags("dave"), Some(vec![String::from("vip")]));
+
+        // A body that isn't a batch at all fails whole
+        assert_eq!(server.request("POST", "/api/tags/batch", "[1, 2"), (400, String::from(JSON_PARSE_ERROR)));
+
+        server.stop();
+    }
+
+    #[test]
+    fn rejects_times_before_1970() {
+        let server = TestServer::start();
+
+        let before = add("alice", "vip").replace("2024-01-01T00:00:00Z", "1960-01-01T00:00:00Z");
+        assert_eq!(server.request("POST", "/api/tags", &before), (400, String::from(TS_RANGE_ERROR)));
+        let epoch = add("alice", "vip").replace("2024-01-01T00:00:00Z", "1970-01-01T00:00:00Z");
+        assert_eq!(server.request("POST", "/api/tags", &epoch), (400, String::from(TS_RANGE_ERROR)));
+
+        // Group writes are refused too, as are the items of a batch
+        let grouped = before.replace(r#""timestamp""#, r#""groups": {"team": {"set": ["a"]}}, "timestamp""#);
+        assert_eq!(server.request("POST", "/api/tags", &grouped), (400, String::from(TS_RANGE_ERROR)));
+
+        let (status, body) = server.request("POST", "/api/tags/batch", &[before, add("bob", "vip")].join("\n"));
+        assert_eq!(status, 200);
+        let response: BatchResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!((response.applied, response.failed), (1, 1));
+        assert_eq!(response.results[0].error.as_deref(), Some(TS_RANGE_ERROR));
+
+        assert_eq!(server.store.user_tags("alice"), None);
+
+        server.stop();
+    }
+
+    #[test]
+    fn rejects_separators_in_tags_outside_groups() {
+        let server = TestServer::start();
+        server.store.set_group("alice", "team", &[String::from("x")], 1_000, "test").unwrap();
+
+        assert_eq!(server.request("POST", "/api/tags", &add("alice", "team:y")), (400, String::from(TAG_NAME_ERROR)));
+        let remove = add("alice", "vip").replace(r#""remove": []"#, r#""remove": ["team:x"]"#);
+        assert_eq!(server.request("POST", "/api/tags", &remove), (400, String::from(TAG_NAME_ERROR)));
+        let expiring = add("alice", "vip").replace(r#"["vip"]"#, r#"[{"tag": "team:y", "expires_at": "2025-01-01T00:00:00Z"}]"#);
+        assert_eq!(server.request("POST", "/api/tags", &expiring), (400, String::from(TAG_NAME_ERROR)));
+
+        let (status, body) = server.request("POST", "/api/tags/batch", &add("alice", "team:y"));
+        assert_eq!(status, 200);
+        let response: BatchResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(response.results[0].error.as_deref(), Some(TAG_NAME_ERROR));
+
+        // Tags in a group may still contain one, as they're named without the group
+        let grouped = add("alice", "vip").replace(r#""timestamp""#, r#""groups": {"team": {"add": ["a:b"]}}, "timestamp""#);
+        let (status, body) = server.request("POST", "/api/tags", &grouped);
+        assert_eq!(status, 200);
+        let mut response: TagResponse = serde_json::from_str(&body).unwrap();
+        response.tags.sort();
+        assert_eq!(response.tags, vec!["team:a:b", "team:x", "vip"]);
+
+        server.stop();
     }
 
-    fn get_tag(&self, user: &String, tag: &String, ts: isize) -> Option<Arc<AtomicIsize>> {
-        let user_map = {
-            match self.store.read().unwrap().get(user) {
-                None => None,
-                Some(user_map) => Some(user_map.clone()),
+    /// A client of `/api/events`.
+    struct EventClient {
+        reader: BufReader<TcpStream>,
+    }
+
+    impl EventClient {
+        /// Opens a stream, or returns the status and body of the response refusing it.
+        fn open(server: &TestServer, path: &str, last_event_id: Option<&str>) -> Result<EventClient, (u16, String)> {
+            let mut stream = TcpStream::connect(server.addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let header = last_event_id.map_or(String::new(), |id| format!("Last-Event-ID: {}\r\n", id));
+            write!(stream, "GET {} HTTP/1.1\r\n{}\r\n", path, header).unwrap();
+
+            let mut reader = BufReader::new(stream);
+            let mut status_line = String::new();
+            reader.read_line(&mut status_line).unwrap();
+            let status: u16 = status_line[9..12].parse().unwrap();
+
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(("content-length", value)) = line.to_ascii_lowercase().split_once(": ") {
+                    content_length = value.trim().parse().unwrap();
+                }
             }
-        };
 
-        let user_map = match user_map {
-            None => {
-                let user_map = Arc::new(RwLock::new(HashMap::new()));
-                self.store.write().unwrap().entry(user.clone())
-                    .or_insert(user_map.clone());
-                user_map
+            if status != 200 {
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                return Err((status, String::from_utf8(body).unwrap()));
             }
 
-            Some(user_map) => user_map,
-        };
+            Ok(EventClient { reader })
+        }
 
-        let tag_ts = {
-            match user_map.read().unwrap().get(tag) {
-                None => None,
-                Some(tag_ts) => Some(tag_ts.clone()),
-            }
-        };
+        /// Reads the next message, sent as a chunk of its own, as its `id`, `event` and `data`.
+        fn next(&mut self) -> (String, String, String) {
+            let mut size = String::new();
+            self.reader.read_line(&mut size).unwrap();
+            let mut chunk = vec![0; usize::from_str_radix(size.trim(), 16).unwrap() + 2];
+            self.reader.read_exact(&mut chunk).unwrap();
 
-        match tag_ts {
-            None => {
-                let tag_ts = Arc::new(AtomicIsize::new(ts));
-                user_map.write().unwrap().entry(tag.clone()).or_insert(tag_ts.clone());
-                Some(tag_ts)
+            let mut fields = (String::new(), String::new(), String::new());
+            for line in String::from_utf8(chunk).unwrap().lines() {
+                match line.split_once(": ") {
+                    Some(("id", id)) => fields.0 = String::from(id),
+                    Some(("event", event)) => fields.1 = String::from(event),
+                    Some(("data", data)) => fields.2 = String::from(data),
+                    _ => {}
+                }
             }
-            Some(tag_ts) => Some(tag_ts),
+            fields
+        }
+
+        /// Reads the next message, checking it's an add of `tag` for alice, and returns its ID.
+        fn next_add(&mut self, tag: &str) -> String {
+            let (id, event, data) = self.next();
+            let data: TagEventResponse = serde_json::from_str(&data).unwrap();
+            assert_eq!((event.as_str(), data.user.as_str(), data.tag.as_str()), ("add", "alice", tag));
+            id
+        }
+    }
+
+    #[test]
+    fn resumes_event_streams_from_the_last_event_id() {
+        let server = TestServer::start();
+        for tag in ["a", "b", "c"].iter() {
+            server.store.add_tag("alice", tag, 1_000, "test").unwrap();
+        }
+        let bus = server.bus();
+
+        let mut resumed = EventClient::open(&server, "/api/events", Some(&bus.external_id(1))).unwrap();
+        assert_eq!(resumed.next_add("b"), bus.external_id(2));
+        assert_eq!(resumed.next_add("c"), bus.external_id(3));
+
+        // The query parameter is for clients that can't set headers
+        let path = format!("/api/events?last_event_id={}", bus.external_id(3));
+        let mut from_query = EventClient::open(&server, &path, None).unwrap();
+        // Without an ID, only events published from now on are sent
+        let mut fresh = EventClient::open(&server, "/api/events", None).unwrap();
+
+        // IDs from an earlier run, or not issued yet, start over with a reset
+        let earlier = format!("{}-2", bus.epoch() - 1);
+        let mut stale = EventClient::open(&server, "/api/events", Some(&earlier)).unwrap();
+        let mut ahead = EventClient::open(&server, "/api/events", Some(&bus.external_id(4))).unwrap();
+        for client in [&mut stale, &mut ahead].iter_mut() {
+            assert_eq!(client.next(), (String::new(), String::from("reset"), String::from(EVENTS_MISSED)));
+        }
+
+        server.store.add_tag("alice", "d", 1_000, "test").unwrap();
+        for client in [&mut resumed, &mut from_query, &mut fresh, &mut stale, &mut ahead].iter_mut() {
+            assert_eq!(client.next_add("d"), bus.external_id(4));
+        }
+
+        drop((resumed, from_query, fresh, stale, ahead));
+        server.stop();
+    }
+
+    #[test]
+    fn caps_the_open_event_streams() {
+        let server = TestServer::start();
+
+        let streams: Vec<EventClient> = (0..MAX_EVENT_STREAMS)
+            .map(|_| EventClient::open(&server, "/api/events", None).unwrap())
+            .collect();
+
+        match EventClient::open(&server, "/api/events", None) {
+            Err(refused) => assert_eq!(refused, (503, String::from(TOO_MANY_STREAMS_ERROR))),
+            Ok(_) => panic!("Opened more than {} event streams", MAX_EVENT_STREAMS),
         }
+        // Other requests are still served
+        assert_eq!(server.get("/api/tags/nobody").0, 404);
+
+        drop(streams);
+        server.stop();
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,187 @@
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use request::{HTTP_VERSION, RETURN_NEWLINE};
+
+/// Longest status or header line accepted in a response.
+const MAX_LINE: u64 = 8 * 1024;
+/// Largest response body read.
+const MAX_BODY: u64 = 64 * 1024 * 1024;
+
+/// The status and body of a response to `send`.
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+/// Sends one request to a plain `http://` URL on a new connection, which is closed once the
+/// response has been read. `timeout` applies to connecting and to each read and write.
+pub fn send(method: &str, url: &str, headers: &[(&str, &str)], body: &[u8], timeout: Duration) -> Result<Response, Error> {
+    let rest = url.strip_prefix("http://")
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Only http:// URLs are supported"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    };
+
+    let stream = connect(authority, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut writer = BufWriter::new(&stream);
+    write!(writer, "{} {} {}\r\n", method, path, HTTP_VERSION)?;
+    write!(writer, "Host: {}\r\nConnection: close\r\nContent-Length: {}\r\n", authority, body.len())?;
+    for (name, value) in headers.iter() {
+        write!(writer, "{}: {}\r\n", name, value)?;
+    }
+    writer.write_all(RETURN_NEWLINE)?;
+    writer.write_all(body)?;
+    writer.flush()?;
+    drop(writer);
+
+    let mut reader = BufReader::new(&stream);
+    let status_line = read_line(&mut reader)?;
+    let status = status_line.split(' ').nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Malformed status line"))?;
+
+    let mut content_length = None;
+    let mut chunked = false;
+    loop {
+        let line = read_line(&mut reader)?;
+        if line.is_empty() {
+            break;
+        }
+
+        let (name, value) = line.split_once(':')
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Malformed header"))?;
+        let value = value.trim();
+
+        if name.eq_ignore_ascii_case("Content-Length") {
+            content_length = Some(value.parse::<u64>()
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Malformed Content-Length"))?);
+        } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        }
+    }
+
+    let body = if chunked {
+        read_chunked(&mut reader)?
+    } else {
+        read_sized(&mut reader, content_length)?
+    };
+
+    Ok(Response { status, body })
+}
+
+fn connect(authority: &str, timeout: Duration) -> Result<TcpStream, Error> {
+    let addrs = if authority.rsplit(']').next().is_some_and(|host_port| host_port.contains(':')) {
+        authority.to_socket_addrs()?
+    } else {
+        (authority, 80).to_socket_addrs()?
+    };
+
+    let mut last_err = Error::new(ErrorKind::NotFound, "Host has no addresses");
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e,
+        }
+    }
+
+    Err(last_err)
+}
+
+/// Reads a line of the response head, without its line ending.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, Error> {
+    let mut line = String::new();
+    reader.take(MAX_LINE).read_line(&mut line)?;
+
+    if !line.ends_with('\n') {
+        return Err(Error::new(ErrorKind::InvalidData, "Response line too long or cut short"));
+    }
+
+    Ok(String::from(line.trim_end_matches(['\r', '\n'])))
+}
+
+/// Reads a body of `content_length` bytes, or up to the end of the stream if it has none.
+fn read_sized<R: Read>(reader: R, content_length: Option<u64>) -> Result<Vec<u8>, Error> {
+    if content_length.is_some_and(|len| len > MAX_BODY) {
+        return Err(Error::new(ErrorKind::InvalidData, "Response body too large"));
+    }
+
+    let mut body = Vec::new();
+    reader.take(content_length.unwrap_or(MAX_BODY + 1)).read_to_end(&mut body)?;
+
+    if content_length.is_some_and(|len| body.len() as u64 != len) {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "Response body shorter than Content-Length"));
+    }
+    if body.len() as u64 > MAX_BODY {
+        return Err(Error::new(ErrorKind::InvalidData, "Response body too large"));
+    }
+
+    Ok(body)
+}
+
+fn read_chunked<R: BufRead>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+
+    loop {
+        let line = read_line(reader)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Malformed chunk size"))?;
+
+        if size == 0 {
+            // Skip any trailers
+            while !read_line(reader)?.is_empty() {}
+            return Ok(body);
+        }
+
+        if (body.len() as u64).saturating_add(size) > MAX_BODY {
+            return Err(Error::new(ErrorKind::InvalidData, "Response body too large"));
+        }
+
+        let start = body.len();
+        body.resize(start + size as usize, 0);
+        reader.read_exact(&mut body[start..])?;
+
+        if !read_line(reader)?.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "Chunk not terminated by CRLF"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn reads_chunked_bodies() {
+        let body = read_chunked(&mut &b"3;ext=1\r\nabc\r\n2\r\nde\r\n0\r\nTrailer: x\r\n\r\n"[..]).unwrap();
+        assert_eq!(body, b"abcde");
+    }
+
+    #[test]
+    fn rejects_chunks_past_the_body_limit() {
+        // Sizes that would overflow when added to what has been read so far
+        for raw in [&b"3\r\nabc\r\nffffffffffffffff\r\n"[..], b"fffffffffffffffd\r\n", b"4000001\r\n"].iter() {
+            let err = read_chunked(&mut &raw[..]).unwrap_err();
+            assert_eq!((err.kind(), err.to_string()), (ErrorKind::InvalidData, String::from("Response body too large")));
+        }
+    }
+
+    #[test]
+    fn rejects_content_lengths_past_the_body_limit_before_reading() {
+        assert_eq!(read_sized(&b"abcde"[..], Some(3)).unwrap(), b"abc");
+        assert_eq!(read_sized(&b"abc"[..], Some(5)).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+        // An endless body would never finish reading if the length were trusted
+        for len in [MAX_BODY + 1, u64::MAX].iter() {
+            let err = read_sized(io::repeat(b'a'), Some(*len)).unwrap_err();
+            assert_eq!((err.kind(), err.to_string()), (ErrorKind::InvalidData, String::from("Response body too large")));
+        }
+    }
+}
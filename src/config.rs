@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+/// Tunables for how `WebServer` treats each accepted connection.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Requests served on one connection before it is closed. Zero means no limit.
+    pub max_requests_per_connection: usize,
+    /// How long a kept-alive connection may sit without a new request before it is closed.
+    pub idle_timeout: Duration,
+    /// How long a client has to send the whole request line and headers once it starts a request.
+    pub header_timeout: Duration,
+    /// Timeout for each read of a request body.
+    pub read_timeout: Duration,
+    /// How long a client has to send the whole request body once the head has been read.
+    pub body_timeout: Duration,
+    /// Timeout for each write of a response.
+    pub write_timeout: Duration,
+    /// Longest request line accepted, in bytes. Longer ones are rejected with a 414.
+    pub max_request_line: usize,
+    /// Most headers accepted on one request. More are rejected with a 431.
+    pub max_header_count: usize,
+    /// Most bytes of headers accepted on one request. More are rejected with a 431.
+    pub max_header_bytes: usize,
+    /// Largest request body, in bytes, that will be read. Larger bodies are rejected with a 413.
+    pub max_body_size: u64,
+    /// How long `WebServer::run` waits for queued and in-flight requests once shut down.
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            max_requests_per_connection: 100,
+            idle_timeout: Duration::from_secs(5),
+            header_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            body_timeout: Duration::from_secs(60),
+            write_timeout: Duration::from_secs(30),
+            max_request_line: 8 * 1024,
+            max_header_count: 100,
+            max_header_bytes: 64 * 1024,
+            max_body_size: 10 * 1024 * 1024,
+            shutdown_timeout: Duration::from_secs(30),
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use backend;
+
+/// Events kept for subscribers that fall behind or reconnect.
+const CAPACITY: usize = 10_000;
+/// Most events handed to a subscriber at once.
+const MAX_BATCH: usize = 1000;
+
+/// A user gaining or losing a tag.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TagEvent {
+    /// One more than the previous event's.
+    pub id: u64,
+    pub user: String,
+    pub tag: String,
+    /// Whether the user holds the tag after the change.
+    pub present: bool,
+    /// The timestamp of the operation that made the change.
+    pub timestamp: i64,
+}
+
+/// The events a subscriber has yet to see.
+#[derive(Clone, Debug, Default)]
+pub struct Events {
+    pub events: Vec<TagEvent>,
+    /// Whether events the subscriber hadn't seen were dropped before it asked, in which case
+    /// `events` starts with the oldest still kept.
+    pub missed: bool,
+}
+
+struct Buffer {
+    events: VecDeque<TagEvent>,
+    next_id: u64,
+    closed: bool,
+}
+
+/// Publishes changes to which users hold which tags, keeping the latest few so subscribers can
+/// pick up where they left off. Subscribers poll with the ID of the last event they saw.
+pub struct EventBus {
+    epoch: i64,
+    buffer: Mutex<Buffer>,
+    published: Condvar,
+}
+
+impl Default for EventBus {
+    fn default() -> EventBus {
+        EventBus::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus {
+            epoch: backend::now_millis() as i64,
+            buffer: Mutex::new(Buffer {
+                events: VecDeque::new(),
+                next_id: 1,
+                closed: false,
+            }),
+            published: Condvar::new(),
+        }
+    }
+
+    /// When the bus was created. Event IDs start again from 1 on every bus, so this tells IDs
+    /// from different runs apart.
+    pub fn epoch(&self) -> i64 {
+        self.epoch
+    }
+
+    /// The ID of event `id` as given to clients, `{epoch}-{id}`, so IDs from an earlier run
+    /// aren't mistaken for current ones.
+    pub fn external_id(&self, id: u64) -> String {
+        format!("{}-{}", self.epoch, id)
+    }
+
+    /// The event an external ID refers to, if this bus issued it.
+    pub fn parse_external_id(&self, external_id: &str) -> Option<u64> {
+        let (epoch, id) = external_id.split_once('-')?;
+        if epoch.parse::<i64>().ok()? != self.epoch {
+            return None;
+        }
+        id.parse().ok()
+    }
+
+    /// The ID of the latest event, or 0 if there hasn't been one.
+    pub fn latest(&self) -> u64 {
+        self.buffer.lock().unwrap().next_id - 1
+    }
+
+    pub(crate) fn publish(&self, user: &str, tag: &str, present: bool, timestamp: i64) {
+        let mut buffer = self.buffer.lock().unwrap();
+
+        let id = buffer.next_id;
+        buffer.next_id += 1;
+
+        if buffer.events.len() == CAPACITY {
+            buffer.events.pop_front();
+        }
+        buffer.events.push_back(TagEvent {
+            id,
+            user: String::from(user),
+            tag: String::from(tag),
+            present,
+            timestamp,
+        });
+
+        self.published.notify_all();
+    }
+
+    /// The events after the one with ID `after`, waiting up to `timeout` for one to be published
+    /// if there are none yet. Returns `None` once the bus is closed.
+    pub fn wait(&self, after: u64, timeout: Duration) -> Option<Events> {
+        let deadline = Instant::now() + timeout;
+        let mut buffer = self.buffer.lock().unwrap();
+
+        loop {
+            if buffer.closed {
+                return None;
+            }
+
+            if after + 1 < buffer.next_id {
+                break;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Some(Events::default());
+            }
+
+            buffer = self.published.wait_timeout(buffer, deadline - now).unwrap().0;
+        }
+
+        let first = buffer.events.front().map_or(buffer.next_id, |event| event.id);
+        let skip = (after + 1).saturating_sub(first) as usize;
+
+        Some(Events {
+            events: buffer.events.iter().skip(skip).take(MAX_BATCH).cloned().collect(),
+            missed: after + 1 < first,
+        })
+    }
+
+    /// Wakes every subscriber and has `wait` return `None` from then on, such as when shutting
+    /// down.
+    pub fn close(&self) {
+        self.buffer.lock().unwrap().closed = true;
+        self.published.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn publish(bus: &EventBus, count: usize) {
+        for i in 0..count {
+            bus.publish("alice", &format!("tag-{}", i), true, 1_000);
+        }
+    }
+
+    #[test]
+    fn external_ids_carry_the_epoch() {
+        let bus = EventBus::new();
+        let id = bus.external_id(42);
+        assert_eq!(id, format!("{}-42", bus.epoch()));
+        assert_eq!(bus.parse_external_id(&id), Some(42));
+
+        assert_eq!(bus.parse_external_id(&format!("{}-42", bus.epoch() - 1)), None);
+        assert_eq!(bus.parse_external_id("42"), None);
+        assert_eq!(bus.parse_external_id(&format!("{}-x", bus.epoch())), None);
+    }
+
+    #[test]
+    fn resumes_after_the_last_event_seen() {
+        let bus = EventBus::new();
+        assert_eq!(bus.latest(), 0);
+        publish(&bus, 3);
+
+        let events = bus.wait(1, Duration::from_secs(1)).unwrap();
+        assert!(!events.missed);
+        assert_eq!(events.events.iter().map(|event| event.id).collect::<Vec<_>>(), vec![2, 3]);
+
+        // Nothing new within the timeout
+        let events = bus.wait(3, Duration::from_millis(10)).unwrap();
+        assert!(events.events.is_empty() && !events.missed);
+
+        bus.close();
+        assert!(bus.wait(0, Duration::from_secs(1)).is_none());
+    }
+
+    #[test]
+    fn reports_events_dropped_from_a_full_buffer() {
+        let bus = EventBus::new();
+        publish(&bus, CAPACITY + 5);
+
+        let events = bus.wait(0, Duration::from_secs(1)).unwrap();
+        assert!(events.missed);
+        assert_eq!(events.events.len(), MAX_BATCH);
+        assert_eq!(events.events[0].id, 6);
+
+        // The oldest event kept follows straight on from the one seen
+        let events = bus.wait(5, Duration::from_secs(1)).unwrap();
+        assert!(!events.missed);
+        assert_eq!(events.events[0].id, 6);
+
+        let events = bus.wait(CAPACITY as u64, Duration::from_secs(1)).unwrap();
+        assert!(!events.missed);
+        assert_eq!(events.events.iter().map(|event| event.id).collect::<Vec<_>>(), (CAPACITY as u64 + 1..=CAPACITY as u64 + 5).collect::<Vec<_>>());
+    }
+}
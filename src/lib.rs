@@ -1,26 +1,77 @@
 extern crate core;
 extern crate http;
+extern crate rustls;
+extern crate rustls_pemfile;
+extern crate crc32fast;
+extern crate ring;
+extern crate chrono;
+extern crate serde_json;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 
 mod threadpool;
 mod request;
 mod router;
+mod config;
+mod middleware;
+mod shutdown;
+mod stream;
+mod sse;
+mod tag_store;
+mod wal;
+mod snapshot;
+mod backend;
+mod lsm;
+mod tag_index;
+mod tag_expr;
+mod events;
+mod client;
+mod webhooks;
+mod replication;
+mod merkle;
+
+pub mod tags {
+    pub use backend::{TagBackend, SnapshotStats, GcStats, UserPage, Change, ChangeKind, Operation, GROUP_SEPARATOR,
+                      format_timestamp};
+    pub use merkle::{Digest, bucket_of, LEAVES, NODES};
+    pub use tag_store::TagStore;
+    pub use tag_expr::{Expr, ParseError};
+    pub use events::{EventBus, TagEvent, Events};
+    pub use webhooks::{WebhookDispatcher, Endpoint, WebhookConfig, SIGNATURE_HEADER, EVENT_ID_HEADER};
+    pub use replication::{ReplicatedBackend, ReplicationConfig, ReplicationHandler, MerkleHandler, BucketsHandler,
+                          PeerStatus, RepairStats, Batch, REPLICATION_PATH, MERKLE_PATH, BUCKETS_PATH};
+    pub use lsm::LsmStore;
+    pub use wal::SyncPolicy;
+}
 
 pub mod httpd {
-    use std::net::{TcpListener, ToSocketAddrs};
-    use std::io::{Write, BufReader, BufWriter, Error};
+    use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+    use std::io::{Write, BufReader, BufWriter, Error, ErrorKind};
+    use std::path::Path;
     use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
 
     use threadpool::ThreadPool;
+    use stream::{Stream, load_tls_config};
 
-    pub use request::Request;
-    pub use router::Router;
+    pub use request::{Request, ParseError};
+    pub use router::{Router, PathParams};
     pub use router::Handler;
-    use request::{RETURN_NEWLINE, CONTENT_LENGTH, COLON, SPACE};
-    use std::error::Error as StdError;
+    pub use config::ServerConfig;
+    pub use middleware::{Middleware, Flow};
+    pub use shutdown::ShutdownHandle;
+    pub use sse::EventStream;
+    use middleware::run_chain;
+    use request::{RETURN_NEWLINE, CONTENT_LENGTH, CONNECTION, SPACE};
 
 
-    const BAD_REQUEST: &str = "HTTP/1.1 400 BAD REQUEST\r\n\r\n";
-    const SERVER_ERROR: &str = "HTTP/1.1 500 INTERNAL SERVER ERROR\r\n\r\n";
+    const BAD_REQUEST: &str = "HTTP/1.1 400 BAD REQUEST";
+    const REQUEST_TIMEOUT: &str = "HTTP/1.1 408 REQUEST TIMEOUT";
+    const PAYLOAD_TOO_LARGE: &str = "HTTP/1.1 413 PAYLOAD TOO LARGE";
+    const URI_TOO_LONG: &str = "HTTP/1.1 414 URI TOO LONG";
+    const HEADERS_TOO_LARGE: &str = "HTTP/1.1 431 REQUEST HEADER FIELDS TOO LARGE";
+    const SERVER_ERROR: &str = "HTTP/1.1 500 INTERNAL SERVER ERROR";
     const SERVICE_UNAVAILABLE: &str = "HTTP/1.1 503 SERVICE UNAVAILABLE\r\n\r\n";
 
     pub struct WebServer<L: Fn(&str) + Send + Sync + 'static> {
@@ -28,6 +79,10 @@ pub mod httpd {
         router: Arc<Router>,
         threadpool: ThreadPool,
         err_logger: Arc<L>,
+        config: Arc<ServerConfig>,
+        middleware: Vec<Arc<dyn Middleware>>,
+        shutdown: Arc<AtomicBool>,
+        tls_config: Option<Arc<rustls::ServerConfig>>,
     }
 
     impl<L: Fn(&str) + Send + Sync + 'static> WebServer<L> {
@@ -41,28 +96,65 @@ pub mod httpd {
                     router: Arc::new(router),
                     threadpool: ThreadPool::new(workers, request_queue),
                     err_logger: Arc::new(err_logger),
+                    config: Arc::new(ServerConfig::default()),
+                    middleware: Vec::new(),
+                    shutdown: Arc::new(AtomicBool::new(false)),
+                    tls_config: None,
                 }
             )
         }
 
+        pub fn with_config(mut self, config: ServerConfig) -> WebServer<L> {
+            self.config = Arc::new(config);
+            self
+        }
+
+        /// Registers middleware that wraps every request, outside any registered on the router.
+        pub fn with_middleware<M: Middleware + 'static>(mut self, middleware: M) -> WebServer<L> {
+            self.middleware.push(Arc::new(middleware));
+            self
+        }
+
+        pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+            self.listener.local_addr()
+        }
+
+        pub fn shutdown_handle(&self) -> Result<ShutdownHandle, Error> {
+            Ok(ShutdownHandle::new(self.shutdown.clone(), self.listener.local_addr()?))
+        }
+
+        /// Serves HTTPS using a PEM certificate chain, leaf first, and PEM private key.
+        pub fn with_tls<P: AsRef<Path>>(mut self, cert_chain: P, private_key: P) -> Result<WebServer<L>, Error> {
+            self.tls_config = Some(load_tls_config(cert_chain, private_key)?);
+            Ok(self)
+        }
+
+        /// Accepts and serves connections until shut down through a `ShutdownHandle`, then waits
+        /// up to the configured shutdown timeout for queued and in-flight requests to finish.
         pub fn run(self) {
+            let server_middleware = Arc::new(self.middleware.clone());
+
             loop {
-                let mut stream = match self.listener.accept() {
-                    Ok((stream, _)) => stream,
-                    Err(_) => continue
-                };
+                let accepted = self.listener.accept();
 
-                let mut err_stream = match stream.try_clone() {
-                    Ok(cloned) => cloned,
-                    Err(_) => continue,
+                if self.shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let tcp = match accepted {
+                    Ok((tcp, _)) => tcp,
+                    Err(_) => continue
                 };
 
-                let reader = match stream.try_clone() {
-                    Ok(cloned) => cloned,
-                    Err(_) => continue,
+                let stream = match Stream::new(tcp, self.tls_config.as_ref()) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        (self.err_logger)(&format!("Failed to set up connection: {}", e));
+                        continue;
+                    }
                 };
 
-                let writer = match stream.try_clone() {
+                let mut err_stream = match stream.try_clone() {
                     Ok(cloned) => cloned,
                     Err(_) => continue,
                 };
@@ -71,82 +163,27 @@ pub mod httpd {
 
                 let err_logger = self.err_logger.clone();
 
+                let config = self.config.clone();
+
+                let middleware = server_middleware.clone();
+
+                let shutdown = self.shutdown.clone();
+
                 let dispatched = self.threadpool.execute(move || {
-                    let reader = BufReader::new(reader);
-                    let writer = BufWriter::new(writer);
-
-                    match Request::parse_request(reader, writer) {
-                        Err(err) => {
-                            let err = err.as_bytes();
-
-                            let mut err_write_result = || {
-                                err_stream.write(BAD_REQUEST.as_bytes())?;
-                                err_stream.write(RETURN_NEWLINE)?;
-                                err_stream.write(CONTENT_LENGTH.as_bytes())?;
-                                err_stream.write(COLON)?;
-                                err_stream.write(SPACE)?;
-                                err_stream.write(err.len().to_string().as_bytes())?;
-                                err_stream.write(RETURN_NEWLINE)?;
-                                err_stream.write(err)?;
-
-                                err_stream.flush()
-                            };
-
-                            if let Err(e) = err_write_result() {
-                                err_logger(&format!("Failed to write 400: {}", e))
-                            }
-
-                            return;
-                        }
+                    let connection = Connection {
+                        router: &router,
+                        middleware: &middleware,
+                        config: &config,
+                        shutdown: &shutdown,
+                        err_logger: &*err_logger,
+                    };
 
-                        Ok(mut request) => {
-                            let mut handle_request = || {
-                                let handle_result = match router.get_handler(&request.path, &request.verb) {
-                                    Err(status_code) => {
-                                        request.send_preamble(status_code, 0)
-                                    }
-                                    Ok(handler) => {
-                                        match handler.handle(&mut request) {
-                                            Ok(_) => request.flush(),
-                                            Err(err) => Err(err)
-                                        }
-                                    }
-                                };
-
-                                if let Err(err) = handle_result {
-                                    if !request.response_headers_sent() {
-                                        let err = err.description().as_bytes();
-
-                                        let mut err_write_result = || {
-                                            err_stream.write(SERVER_ERROR.as_bytes())?;
-                                            err_stream.write(RETURN_NEWLINE)?;
-                                            err_stream.write(CONTENT_LENGTH.as_bytes())?;
-                                            err_stream.write(COLON)?;
-                                            err_stream.write(SPACE)?;
-                                            err_stream.write(err.len().to_string().as_bytes())?;
-                                            err_stream.write(RETURN_NEWLINE)?;
-                                            err_stream.write(err)?;
-
-                                            err_stream.flush()
-                                        };
-
-                                        return err_write_result();
-                                    }
-                                }
-
-                                Ok(())
-                            };
-
-                            if let Err(err) = handle_request() {
-                                err_logger(&format!("Failed to handle request: {}", err))
-                            }
-                        }
-                    }
+                    connection.serve(stream);
                 });
 
                 if !dispatched {
-                    match stream.write(SERVICE_UNAVAILABLE.as_bytes()) {
-                        Ok(_) => match stream.flush() {
+                    match err_stream.write_all(SERVICE_UNAVAILABLE.as_bytes()) {
+                        Ok(_) => match err_stream.flush() {
                             Ok(_) => {},
                             Err(e) => (self.err_logger)(&format!("Failed to flush 503: {}", e)),
                         }
@@ -154,6 +191,142 @@ pub mod httpd {
                     }
                 }
             }
+
+            drop(self.listener);
+
+            if !self.threadpool.shutdown(self.config.shutdown_timeout) {
+                (self.err_logger)("Shutdown timed out with requests still in flight");
+            }
         }
     }
-}
\ No newline at end of file
+
+    /// Everything a worker needs to serve the requests on one connection.
+    struct Connection<'a, L: Fn(&str)> {
+        router: &'a Router,
+        middleware: &'a [Arc<dyn Middleware>],
+        config: &'a ServerConfig,
+        shutdown: &'a AtomicBool,
+        err_logger: &'a L,
+    }
+
+    impl<'a, L: Fn(&str)> Connection<'a, L> {
+        fn serve(&self, stream: Stream) {
+            if let Err(e) = self.serve_requests(&stream) {
+                (self.err_logger)(&e);
+            }
+
+            stream.close();
+        }
+
+        /// Serves requests until the connection should close, returning an error to log if it
+        /// ended abnormally.
+        fn serve_requests(&self, stream: &Stream) -> Result<(), String> {
+            let config = self.config;
+            let clone = || stream.try_clone().map_err(|e| format!("Failed to clone connection: {}", e));
+
+            let mut err_stream = clone()?;
+            let mut reader = BufReader::new(clone()?);
+            let mut writer = BufWriter::new(clone()?);
+            let mut served = 0;
+
+            if let Err(e) = stream.handshake(config.idle_timeout) {
+                return Err(format!("TLS handshake failed: {}", e));
+            }
+
+            stream.set_write_timeout(Some(config.write_timeout))
+                .map_err(|e| format!("Failed to set write timeout: {}", e))?;
+
+            loop {
+                if served > 0 && self.shutdown.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+
+                stream.set_read_timeout(Some(config.idle_timeout))
+                    .map_err(|e| format!("Failed to set idle timeout: {}", e))?;
+
+                let mut request = match Request::parse_request(reader, writer, config) {
+                    Ok(request) => request,
+                    Err(ParseError::ConnectionClosed) => return Ok(()),
+                    Err(err) => {
+                        let (status_line, message) = match err {
+                            ParseError::BadRequest(message) => (BAD_REQUEST, message),
+                            ParseError::Timeout => (REQUEST_TIMEOUT, "Timed out reading request"),
+                            ParseError::RequestLineTooLong => (URI_TOO_LONG, "Request line too long"),
+                            ParseError::HeadersTooLarge => (HEADERS_TOO_LARGE, "Request headers too large"),
+                            ParseError::ConnectionClosed => unreachable!(),
+                        };
+
+                        return write_error(&mut err_stream, status_line, message)
+                            .map_err(|e| format!("Failed to write error response: {}", e));
+                    }
+                };
+
+                if !request.limit_body_size(config.max_body_size) {
+                    return write_error(&mut err_stream, PAYLOAD_TOO_LARGE, "Request body too large")
+                        .map_err(|e| format!("Failed to write 413: {}", e));
+                }
+
+                served += 1;
+                if config.max_requests_per_connection != 0 && served >= config.max_requests_per_connection {
+                    request.set_keep_alive(false);
+                }
+
+                if self.shutdown.load(Ordering::SeqCst) {
+                    request.set_keep_alive(false);
+                }
+
+                let router = self.router;
+                let handle_result = run_chain(self.middleware, &mut request, |request| router.dispatch(request))
+                    .and_then(|_| request.finish());
+
+                if let Err(err) = handle_result {
+                    if request.response_headers_sent() {
+                        return Err(format!("Failed to handle request: {}", err));
+                    }
+
+                    let status_line = if request.body_too_large() {
+                        PAYLOAD_TOO_LARGE
+                    } else if err.kind() == ErrorKind::TimedOut || err.kind() == ErrorKind::WouldBlock {
+                        REQUEST_TIMEOUT
+                    } else {
+                        SERVER_ERROR
+                    };
+
+                    return write_error(&mut err_stream, status_line, &err.to_string())
+                        .map_err(|e| format!("Failed to handle request: {}", e));
+                }
+
+                if !request.keep_alive() {
+                    return Ok(());
+                }
+
+                request.discard_body()
+                    .map_err(|e| format!("Failed to discard unread request body: {}", e))?;
+
+                let (next_reader, next_writer) = request.into_parts();
+                reader = next_reader;
+                writer = next_writer;
+            }
+        }
+    }
+
+    /// Writes a plain text error response directly to the socket. The connection is always closed
+    /// afterwards, since the request stream can't be trusted once parsing or handling has failed.
+    fn write_error(stream: &mut Stream, status_line: &str, err: &str) -> Result<(), Error> {
+        let err = err.as_bytes();
+
+        stream.write_all(status_line.as_bytes())?;
+        stream.write_all(RETURN_NEWLINE)?;
+        stream.write_all(CONNECTION.as_bytes())?;
+        stream.write_all(b" close")?;
+        stream.write_all(RETURN_NEWLINE)?;
+        stream.write_all(CONTENT_LENGTH.as_bytes())?;
+        stream.write_all(SPACE)?;
+        stream.write_all(err.len().to_string().as_bytes())?;
+        stream.write_all(RETURN_NEWLINE)?;
+        stream.write_all(RETURN_NEWLINE)?;
+        stream.write_all(err)?;
+
+        stream.flush()
+    }
+}
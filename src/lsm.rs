@@ -0,0 +1,833 @@
+use std::collections::{btree_map, BTreeMap, BTreeSet, HashMap};
+use std::collections::hash_map::Entry;
+use std::collections::Bound::{Included, Unbounded};
+use std::fs::{self, File};
+use std::io::{Seek, SeekFrom, Error, ErrorKind, BufReader};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use backend::{self, TagBackend, SnapshotStats, UserPage, GROUP_SEPARATOR, attribute_wins};
+use snapshot::{self, SnapshotReader, SnapshotWriter};
+use wal::{self, Wal, SyncPolicy, LogEntry, Op};
+use tag_expr::Expr;
+
+const TABLE_PREFIX: &str = "table.";
+/// Entries between the keys kept in a table's in-memory index.
+const INDEX_INTERVAL: u64 = 64;
+/// Entries the memtable holds before it is flushed to a table.
+const MEMTABLE_LIMIT: usize = 64 * 1024;
+/// Tables allowed to pile up before they are merged into one.
+const MAX_TABLES: usize = 8;
+
+/// Whether an entry is about a group, a tag or an attribute, which live side by side under each
+/// user. Groups come first, so a scan knows when each of a user's groups was replaced before it
+/// reaches their tags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Kind {
+    Group,
+    Tag,
+    Attribute,
+}
+
+impl Kind {
+    fn of(op: Op) -> Kind {
+        match op {
+            Op::Add | Op::Remove => Kind::Tag,
+            Op::Set | Op::Unset => Kind::Attribute,
+            Op::ClearGroup => Kind::Group,
+        }
+    }
+}
+
+/// Entries are ordered by user, then kind, then tag or key.
+type Key = (String, Kind, String);
+
+/// The merged state of a tag, attribute or group: the signed timestamp of its latest operation,
+/// the latest expiry any add carried or 0, and the value an attribute was last set to. A group's
+/// timestamp is the latest time it was replaced at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct State {
+    ts: isize,
+    expires: isize,
+    value: String,
+}
+
+impl State {
+    fn of(entry: &LogEntry) -> State {
+        let ts = entry.timestamp as isize;
+
+        State {
+            ts: match entry.op {
+                Op::Add | Op::Set | Op::ClearGroup => ts,
+                Op::Remove | Op::Unset => -ts,
+            },
+            expires: entry.expires_at.map_or(0, |expires_at| expires_at as isize),
+            value: entry.value.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Merges in another state under last-write-wins. Tags have no value, so for them this is
+    /// just the newer timestamp, with a remove winning a tie.
+    fn merge(&mut self, other: State) {
+        let expires = self.expires.max(other.expires);
+        if attribute_wins(other.ts, &other.value, self.ts, &self.value) {
+            *self = other;
+        }
+        self.expires = expires;
+    }
+
+    /// Whether the tag `tag` is held at `now`, given when each of the user's groups was last
+    /// replaced.
+    fn present(&self, tag: &str, groups: &HashMap<String, isize>, now: isize) -> bool {
+        let cleared = backend::group_of(tag).and_then(|group| groups.get(group))
+            .is_some_and(|cleared| backend::group_clears(*cleared, self.ts));
+
+        !cleared && backend::is_present(self.ts, self.expires, now)
+    }
+
+    /// The state with an expiry that is due applied as the remove it stands for, and any expiry
+    /// that can no longer win dropped.
+    fn settle(self, now: isize) -> State {
+        if self.expires < self.ts.abs() {
+            State { expires: 0, ..self }
+        } else if self.expires <= now {
+            State { ts: -self.expires, expires: 0, value: self.value }
+        } else {
+            self
+        }
+    }
+
+    fn entry(&self, user: &str, kind: Kind, tag: &str) -> LogEntry {
+        let op = match (kind, self.ts > 0) {
+            (Kind::Tag, true) => Op::Add,
+            (Kind::Tag, false) => Op::Remove,
+            (Kind::Attribute, true) => Op::Set,
+            (Kind::Attribute, false) => Op::Unset,
+            (Kind::Group, _) => Op::ClearGroup,
+        };
+
+        LogEntry {
+            op,
+            user: String::from(user),
+            tag: String::from(tag),
+            timestamp: self.ts.abs() as i64,
+            expires_at: Some(self.expires as i64).filter(|expires_at| *expires_at > 0),
+            value: Some(self.value.clone()).filter(|_| op == Op::Set),
+        }
+    }
+}
+
+/// An immutable file of entries sorted by user, kind, then tag or key, written in the snapshot
+/// format.
+struct Table {
+    generation: u64,
+    path: PathBuf,
+    file: Mutex<File>,
+    /// The user of every `INDEX_INTERVAL`th entry and where that entry starts.
+    index: Vec<(String, u64)>,
+    /// Set once the table has been merged into another, so its file is deleted when dropped.
+    obsolete: AtomicBool,
+}
+
+impl Table {
+    fn open(path: PathBuf) -> Result<Table, Error> {
+        let mut reader = SnapshotReader::open(&path)?;
+
+        let mut index = Vec::new();
+        let mut offset = snapshot::HEADER_LEN;
+        let mut position = 0;
+        while let Some((entry, len)) = reader.next_entry()? {
+            if position % INDEX_INTERVAL == 0 {
+                index.push((entry.user, offset));
+            }
+
+            offset += len as u64;
+            position += 1;
+        }
+
+        Ok(Table {
+            generation: reader.generation(),
+            file: Mutex::new(File::open(&path)?),
+            path,
+            index,
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    /// Merges the entries stored for `user` into `states`.
+    fn read_user(&self, user: &str, states: &mut HashMap<(Kind, String), State>) -> Result<(), Error> {
+        // Every entry for the user comes after the last indexed entry for an earlier user
+        let start = match self.index.partition_point(|(indexed, _)| indexed.as_str() < user) {
+            0 => snapshot::HEADER_LEN,
+            idx => self.index[idx - 1].1,
+        };
+
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(start))?;
+        let mut reader = BufReader::new(&mut *file);
+
+        while let Some((entry, _)) = wal::read_record(&mut reader)? {
+            if entry.user.as_str() > user {
+                break;
+            }
+
+            if entry.user == user {
+                let state = State::of(&entry);
+                let key = (Kind::of(entry.op), entry.tag);
+                match states.entry(key) {
+                    Entry::Occupied(mut current) => current.get_mut().merge(state),
+                    Entry::Vacant(vacant) => {
+                        vacant.insert(state);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::SeqCst) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+type Source<'a> = Box<dyn Iterator<Item = Result<LogEntry, Error>> + 'a>;
+
+/// Walks several sources sorted by key in step, yielding each key once with the states from every
+/// source merged.
+struct Merge<'a> {
+    sources: Vec<Source<'a>>,
+    heads: Vec<Option<LogEntry>>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(mut sources: Vec<Source<'a>>) -> Result<Merge<'a>, Error> {
+        let heads = sources.iter_mut()
+            .map(|source| source.next().transpose())
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(Merge { sources, heads })
+    }
+
+    /// Returns the next key, with its merged state.
+    fn next_entry(&mut self) -> Result<Option<(Key, State)>, Error> {
+        let key = self.heads.iter()
+            .filter_map(|head| head.as_ref().map(|entry| (&entry.user, Kind::of(entry.op), &entry.tag)))
+            .min()
+            .map(|(user, kind, tag)| (user.clone(), kind, tag.clone()));
+
+        let key = match key {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+
+        let mut state: Option<State> = None;
+        for (head, source) in self.heads.iter_mut().zip(self.sources.iter_mut()) {
+            let entry_state = match head.as_ref() {
+                Some(entry) if entry.user == key.0 && Kind::of(entry.op) == key.1 && entry.tag == key.2 => {
+                    State::of(entry)
+                }
+                _ => continue,
+            };
+
+            match state.as_mut() {
+                Some(state) => state.merge(entry_state),
+                None => state = Some(entry_state),
+            }
+            *head = source.next().transpose()?;
+        }
+
+        Ok(state.map(|state| (key, state)))
+    }
+}
+
+fn table_entries(table: &Table) -> Result<Source<'static>, Error> {
+    let mut reader = SnapshotReader::open(&table.path)?;
+    Ok(Box::new(iter::from_fn(move || reader.next_entry().transpose().map(|entry| entry.map(|(entry, _)| entry)))))
+}
+
+/// An embedded on-disk `TagBackend` in the style of a log-structured merge tree. Writes go to a
+/// write-ahead log and a sorted in-memory table, which is flushed to an immutable sorted file
+/// when it fills up. Lookups merge the memtable with every file, and files are merged together
+/// once too many accumulate. Only a sparse index of each file is kept in memory. Expired tags are
+/// hidden on every read, and merging tables turns them into tombstones. Writes never read what
+/// is already stored, so they can't tell applied operations from stale ones and keep no history.
+pub struct LsmStore {
+    dir: PathBuf,
+    /// Also serializes writes with flushes, so every logged entry is in the memtable or a table.
+    wal: Mutex<Wal>,
+    memtable: RwLock<BTreeMap<Key, State>>,
+    tables: RwLock<Vec<Arc<Table>>>,
+}
+
+impl LsmStore {
+    /// Opens the store kept in `dir`, creating it if needed, and replays the log written since
+    /// the newest table into the memtable.
+    pub fn open<P: AsRef<Path>>(dir: P, policy: SyncPolicy) -> Result<LsmStore, Error> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let mut tables = Vec::new();
+        for generation in list_tables(dir)? {
+            tables.push(Arc::new(Table::open(table_path(dir, generation))?));
+        }
+
+        let generation = tables.iter().map(|table| table.generation).max().unwrap_or(0);
+
+        let mut memtable = BTreeMap::new();
+        let wal = Wal::open(dir, policy, generation, |entry| insert(&mut memtable, entry))?;
+
+        Ok(LsmStore {
+            dir: dir.to_path_buf(),
+            wal: Mutex::new(wal),
+            memtable: RwLock::new(memtable),
+            tables: RwLock::new(tables),
+        })
+    }
+
+    fn log_and_apply(&self, entry: LogEntry) -> Result<(), Error> {
+        self.log_and_apply_all(vec![entry])
+    }
+
+    /// Logs and applies several entries, which readers see either all or none of.
+    fn log_and_apply_all(&self, entries: Vec<LogEntry>) -> Result<(), Error> {
+        for entry in entries.iter() {
+            if entry.timestamp > isize::MAX as i64 || entry.expires_at.is_some_and(|expires_at| expires_at > isize::MAX as i64) {
+                panic!("This program must be run on a 64bit system")
+            }
+        }
+
+        let mut wal = self.wal.lock().unwrap();
+        for entry in entries.iter() {
+            wal.append(entry)?;
+        }
+
+        let full = {
+            let mut memtable = self.memtable.write().unwrap();
+            for entry in entries {
+                insert(&mut memtable, entry);
+            }
+            memtable.len() >= MEMTABLE_LIMIT
+        };
+
+        if full {
+            self.flush(&mut wal)?;
+
+            if self.tables.read().unwrap().len() > MAX_TABLES {
+                self.compact()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every group, tag and attribute stored for the user, removed ones included.
+    fn user_states(&self, user: &str) -> Result<HashMap<(Kind, String), State>, Error> {
+        let mut states = HashMap::new();
+
+        // The memtable goes first, since a flush adds its table before clearing it
+        {
+            let memtable = self.memtable.read().unwrap();
+            let from = (String::from(user), Kind::Group, String::new());
+
+            for ((entry_user, kind, tag), state) in memtable.range((Included(from), Unbounded)) {
+                if entry_user != user {
+                    break;
+                }
+
+                states.insert((*kind, tag.clone()), state.clone());
+            }
+        }
+
+        let tables = self.tables.read().unwrap().clone();
+        for table in tables.iter() {
+            table.read_user(user, &mut states)?;
+        }
+
+        Ok(states)
+    }
+
+    /// Calls `live` with each user and tag currently held, ordered by user then tag, optionally
+    /// only for one tag. Scans the memtable and every table, so this costs a full read of the store.
+    fn scan<F: FnMut(String, String)>(&self, only_tag: Option<&str>, mut live: F) -> Result<(), Error> {
+        let memtable: Vec<LogEntry> = self.memtable.read().unwrap().iter()
+            .filter(|((_, kind, tag), _)| *kind == Kind::Group || (*kind == Kind::Tag && only_tag.is_none_or(|only_tag| tag == only_tag)))
+            .map(|((user, kind, tag), state)| state.entry(user, *kind, tag))
+            .collect();
+
+        let mut sources: Vec<Source> = vec![Box::new(memtable.into_iter().map(Ok))];
+        for table in self.tables.read().unwrap().clone().iter() {
+            sources.push(table_entries(table)?);
+        }
+
+        let now = backend::now_millis();
+        let mut merge = Merge::new(sources)?;
+        let mut groups_of: Option<String> = None;
+        let mut groups = HashMap::new();
+
+        while let Some(((user, kind, tag), state)) = merge.next_entry()? {
+            if groups_of.as_ref() != Some(&user) {
+                groups.clear();
+                groups_of = Some(user.clone());
+            }
+
+            match kind {
+                Kind::Group => {
+                    groups.insert(tag, state.ts);
+                }
+                Kind::Tag if state.present(&tag, &groups, now) && only_tag.is_none_or(|only_tag| tag == only_tag) => {
+                    live(user, tag);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the memtable out as a new table and drops the log segments it covered.
+    fn flush(&self, wal: &mut Wal) -> Result<(), Error> {
+        let generation = wal.rotate()?;
+
+        {
+            let memtable = self.memtable.read().unwrap();
+            let mut writer = SnapshotWriter::create_named(&self.dir, &table_name(generation), generation)?;
+
+            for ((user, kind, tag), state) in memtable.iter() {
+                writer.write(&state.entry(user, *kind, tag))?;
+            }
+
+            writer.commit()?;
+        }
+
+        // Readers may briefly see entries in both places, which merging makes harmless
+        let table = Table::open(table_path(&self.dir, generation))?;
+        self.tables.write().unwrap().push(Arc::new(table));
+        self.memtable.write().unwrap().clear();
+
+        wal.remove_before(generation)
+    }
+
+    /// Merges every table into one, returning how many users and entries it holds. Expiries that
+    /// have passed are written as the tombstones they stand for.
+    fn compact(&self) -> Result<(usize, u64), Error> {
+        let tables = self.tables.read().unwrap().clone();
+
+        let generation = match tables.iter().map(|table| table.generation).max() {
+            Some(generation) => generation,
+            None => return Ok((0, 0)),
+        };
+
+        let sources = tables.iter().map(|table| table_entries(table)).collect::<Result<Vec<_>, Error>>()?;
+        let mut merge = Merge::new(sources)?;
+
+        let mut writer = SnapshotWriter::create_named(&self.dir, &table_name(generation), generation)?;
+        let mut users = 0;
+        let mut last_user: Option<String> = None;
+        let now = backend::now_millis();
+
+        while let Some(((user, kind, tag), state)) = merge.next_entry()? {
+            if last_user.as_ref() != Some(&user) {
+                users += 1;
+            }
+
+            writer.write(&state.settle(now).entry(&user, kind, &tag))?;
+            last_user = Some(user);
+        }
+
+        let entries = writer.commit()?;
+
+        let merged = Arc::new(Table::open(table_path(&self.dir, generation))?);
+        {
+            let mut current = self.tables.write().unwrap();
+            current.retain(|table| !tables.iter().any(|merged| Arc::ptr_eq(table, merged)));
+            current.push(merged);
+        }
+
+        // The merged table replaced the newest input's file, the rest go once nothing reads them
+        for table in tables.iter().filter(|table| table.generation != generation) {
+            table.obsolete.store(true, Ordering::SeqCst);
+        }
+
+        Ok((users, entries))
+    }
+}
+
+impl TagBackend for LsmStore {
+    fn add_tag(&self, user: &str, tag: &str, ts: i64, _source: &str) -> Result<(), Error> {
+        self.log_and_apply(LogEntry {
+            op: Op::Add,
+            user: String::from(user),
+            tag: String::from(tag),
+            timestamp: ts,
+            expires_at: None,
+            value: None,
+        })
+    }
+
+    fn remove_tag(&self, user: &str, tag: &str, ts: i64, _source: &str) -> Result<(), Error> {
+        self.log_and_apply(LogEntry {
+            op: Op::Remove,
+            user: String::from(user),
+            tag: String::from(tag),
+            timestamp: ts,
+            expires_at: None,
+            value: None,
+        })
+    }
+
+    fn add_expiring_tag(&self, user: &str, tag: &str, ts: i64, expires_at: i64, _source: &str) -> Result<(), Error> {
+        self.log_and_apply(LogEntry {
+            op: Op::Add,
+            user: String::from(user),
+            tag: String::from(tag),
+            timestamp: ts,
+            expires_at: Some(expires_at),
+            value: None,
+        })
+    }
+
+    fn set_group(&self, user: &str, group: &str, tags: &[String], ts: i64, _source: &str) -> Result<(), Error> {
+        if !backend::is_group_name(group) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid group name {:?}", group)));
+        }
+
+        let clear = LogEntry {
+            op: Op::ClearGroup,
+            user: String::from(user),
+            tag: String::from(group),
+            timestamp: ts,
+            expires_at: None,
+            value: None,
+        };
+
+        let adds: Vec<LogEntry> = tags.iter()
+            .map(|tag| LogEntry { op: Op::Add, tag: format!("{}{}{}", group, GROUP_SEPARATOR, tag), ..clear.clone() })
+            .collect();
+
+        let mut entries = vec![clear];
+        entries.extend(adds);
+
+        self.log_and_apply_all(entries)
+    }
+
+    fn user_tags(&self, user: &str) -> Result<Option<Vec<String>>, Error> {
+        let states = self.user_states(user)?;
+        if !states.keys().any(|(kind, _)| *kind != Kind::Attribute) {
+            return Ok(None);
+        }
+
+        let groups: HashMap<String, isize> = states.iter()
+            .filter(|((kind, _), _)| *kind == Kind::Group)
+            .map(|((_, group), state)| (group.clone(), state.ts))
+            .collect();
+
+        let now = backend::now_millis();
+        Ok(Some(states.iter()
+            .filter(|((kind, tag), state)| *kind == Kind::Tag && state.present(tag, &groups, now))
+            .map(|((_, tag), _)| tag.clone())
+            .collect()))
+    }
+
+    fn set_attribute(&self, user: &str, key: &str, value: &str, ts: i64) -> Result<(), Error> {
+        self.log_and_apply(LogEntry {
+            op: Op::Set,
+            user: String::from(user),
+            tag: String::from(key),
+            timestamp: ts,
+            expires_at: None,
+            value: Some(String::from(value)),
+        })
+    }
+
+    fn remove_attribute(&self, user: &str, key: &str, ts: i64) -> Result<(), Error> {
+        self.log_and_apply(LogEntry {
+            op: Op::Unset,
+            user: String::from(user),
+            tag: String::from(key),
+            timestamp: ts,
+            expires_at: None,
+            value: None,
+        })
+    }
+
+    fn user_attributes(&self, user: &str) -> Result<Option<HashMap<String, String>>, Error> {
+        let states = self.user_states(user)?;
+        if !states.keys().any(|(kind, _)| *kind == Kind::Attribute) {
+            return Ok(None);
+        }
+
+        Ok(Some(states.into_iter()
+            .filter(|((kind, _), state)| *kind == Kind::Attribute && state.ts > 0)
+            .map(|((_, key), state)| (key, state.value))
+            .collect()))
+    }
+
+    /// Scans the memtable and every table for each page, since no index of users by tag is kept.
+    fn users_with_tag(&self, tag: &str, after: Option<&str>, limit: usize) -> Result<UserPage, Error> {
+        let mut page = UserPage::default();
+
+        self.scan(Some(tag), |user, _| {
+            page.total += 1;
+            if after.is_some_and(|after| user.as_str() <= after) {
+                return;
+            }
+
+            if page.users.len() < limit {
+                page.users.push(user);
+            } else if page.next.is_none() {
+                page.next = page.users.last().cloned();
+            }
+        })?;
+
+        Ok(page)
+    }
+
+    /// Gathers the users of every tag in `expr` in a single scan of the store.
+    fn audience(&self, expr: &Expr) -> Result<BTreeSet<String>, Error> {
+        let wanted = expr.tags();
+        let mut members: HashMap<String, BTreeSet<String>> = HashMap::new();
+        let mut everyone = BTreeSet::new();
+
+        self.scan(None, |user, tag| {
+            if wanted.contains(tag.as_str()) {
+                members.entry(tag).or_default().insert(user.clone());
+            }
+            everyone.insert(user);
+        })?;
+
+        Ok(expr.evaluate(&|tag| members.get(tag).cloned().unwrap_or_default(), || everyone))
+    }
+
+    /// Flushes the memtable and merges every table into one.
+    fn snapshot(&self) -> Result<SnapshotStats, Error> {
+        let mut wal = self.wal.lock().unwrap();
+        self.flush(&mut wal)?;
+
+        let (users, entries) = self.compact()?;
+
+        Ok(SnapshotStats {
+            generation: self.tables.read().unwrap().iter().map(|table| table.generation).max().unwrap_or(0),
+            users,
+            entries,
+        })
+    }
+}
+
+fn insert(memtable: &mut BTreeMap<Key, State>, entry: LogEntry) {
+    let state = State::of(&entry);
+    let key = (entry.user, Kind::of(entry.op), entry.tag);
+
+    match memtable.entry(key) {
+        btree_map::Entry::Occupied(mut current) => current.get_mut().merge(state),
+        btree_map::Entry::Vacant(vacant) => {
+            vacant.insert(state);
+        }
+    }
+}
+
+fn table_name(generation: u64) -> String {
+    format!("{}{:020}", TABLE_PREFIX, generation)
+}
+
+fn table_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(table_name(generation))
+}
+
+/// Returns the generations of the tables in `dir`, oldest first.
+fn list_tables(dir: &Path) -> Result<Vec<u64>, Error> {
+    let mut generations = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let generation = name.to_str()
+            .and_then(|name| name.strip_prefix(TABLE_PREFIX))
+            .and_then(|generation| generation.parse::<u64>().ok());
+
+        if let Some(generation) = generation {
+            generations.push(generation);
+        }
+    }
+
+    generations.sort_unstable();
+    Ok(generations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wal::tests::scratch_dir;
+
+    fn open(dir: &Path) -> LsmStore {
+        LsmStore::open(dir, SyncPolicy::Never).unwrap()
+    }
+
+    fn flush(store: &LsmStore) {
+        store.flush(&mut store.wal.lock().unwrap()).unwrap();
+    }
+
+    fn sorted_tags(store: &LsmStore, user: &str) -> Option<Vec<String>> {
+        store.user_tags(user).unwrap().map(|mut tags| {
+            tags.sort();
+            tags
+        })
+    }
+
+    fn tags(tags: &[&str]) -> Option<Vec<String>> {
+        Some(tags.iter().map(|tag| String::from(*tag)).collect())
+    }
+
+    #[test]
+    fn tombstones_shadow_older_adds_in_other_tables() {
+        let dir = scratch_dir("lsm-tombstones");
+        let store = open(&dir);
+
+        store.add_tag("alice", "vip", 1_000, "test").unwrap();
+        store.add_tag("alice", "beta", 2_000, "test").unwrap();
+        flush(&store);
+        store.remove_tag("alice", "vip", 1_001, "test").unwrap();
+        // Older than the add in the first table, so it loses wherever it's stored
+        store.remove_tag("alice", "beta", 1_999, "test").unwrap();
+        flush(&store);
+        assert_eq!(store.tables.read().unwrap().len(), 2);
+
+        assert_eq!(sorted_tags(&store, "alice"), tags(&["beta"]));
+        assert_eq!(store.users_with_tag("vip", None, 10).unwrap().users, Vec::<String>::new());
+
+        // A tombstone in the memtable shadows tables too
+        store.remove_tag("alice", "beta", 2_000, "test").unwrap();
+        assert_eq!(sorted_tags(&store, "alice"), tags(&[]));
+    }
+
+    #[test]
+    fn compaction_keeps_the_winning_state() {
+        let dir = scratch_dir("lsm-compaction");
+        let store = open(&dir);
+
+        store.add_tag("alice", "vip", 1_000, "test").unwrap();
+        store.set_attribute("alice", "plan", "gold", 1_000).unwrap();
+        store.set_group("bob", "device", &[String::from("ios")], 1_000, "test").unwrap();
+        flush(&store);
+        store.remove_tag("alice", "vip", 2_000, "test").unwrap();
+        store.add_tag("alice", "beta", 1_000, "test").unwrap();
+        store.set_attribute("alice", "plan", "silver", 999).unwrap();
+        store.set_group("bob", "device", &[String::from("web")], 2_000, "test").unwrap();
+        flush(&store);
+        // Stale by the time it's flushed, but newer tables don't always win
+        store.add_tag("alice", "vip", 1_500, "test").unwrap();
+        store.add_tag("bob", "device:ios", 1_500, "test").unwrap();
+        flush(&store);
+
+        let before = (sorted_tags(&store, "alice"), sorted_tags(&store, "bob"), store.user_attributes("alice").unwrap());
+        assert_eq!(before.0, tags(&["beta"]));
+        assert_eq!(before.1, tags(&["device:web"]));
+
+        let paths: Vec<PathBuf> = store.tables.read().unwrap().iter().map(|table| table.path.clone()).collect();
+        assert_eq!(store.compact().unwrap(), (2, 6));
+        assert_eq!(store.tables.read().unwrap().len(), 1);
+        assert_eq!((sorted_tags(&store, "alice"), sorted_tags(&store, "bob"), store.user_attributes("alice").unwrap()), before);
+        assert_eq!(store.user_attributes("alice").unwrap().unwrap()["plan"], "gold");
+
+        // Only the newest input's file is kept, under the merged table
+        assert_eq!(paths.iter().filter(|path| path.exists()).count(), 1);
+        drop(store);
+
+        let store = open(&dir);
+        assert_eq!((sorted_tags(&store, "alice"), sorted_tags(&store, "bob"), store.user_attributes("alice").unwrap()), before);
+    }
+
+    #[test]
+    fn reopens_tables_and_the_log_after_a_flush() {
+        let dir = scratch_dir("lsm-reopen");
+        let store = open(&dir);
+
+        store.add_tag("alice", "vip", 1_000, "test").unwrap();
+        store.set_attribute("alice", "plan", "gold", 1_000).unwrap();
+        flush(&store);
+        store.add_tag("alice", "beta", 1_000, "test").unwrap();
+        store.remove_tag("bob", "vip", 1_000, "test").unwrap();
+        drop(store);
+
+        let store = open(&dir);
+        assert_eq!(store.tables.read().unwrap().len(), 1);
+        assert_eq!(store.memtable.read().unwrap().len(), 2);
+        assert_eq!(sorted_tags(&store, "alice"), tags(&["beta", "vip"]));
+        assert_eq!(sorted_tags(&store, "bob"), tags(&[]));
+        assert_eq!(store.user_attributes("alice").unwrap().unwrap()["plan"], "gold");
+
+        // Writes carry on in the log after reopening
+        store.remove_tag("alice", "vip", 1_001, "test").unwrap();
+        drop(store);
+        assert_eq!(sorted_tags(&open(&dir), "alice"), tags(&["beta"]));
+    }
+
+    #[test]
+    fn finds_users_between_indexed_keys() {
+        let dir = scratch_dir("lsm-index");
+        let store = open(&dir);
+
+        let users = INDEX_INTERVAL as usize * 3;
+        for i in 0..users {
+            // Two entries each, so users straddle the indexed positions
+            store.add_tag(&format!("user-{:04}", i * 2), "vip", 1_000, "test").unwrap();
+            store.add_tag(&format!("user-{:04}", i * 2), "beta", 1_000, "test").unwrap();
+        }
+        flush(&store);
+        assert_eq!(store.tables.read().unwrap()[0].index.len(), 6);
+
+        for i in 0..users {
+            assert_eq!(sorted_tags(&store, &format!("user-{:04}", i * 2)), tags(&["beta", "vip"]), "user {}", i * 2);
+            // Sorts between two stored users
+            assert_eq!(sorted_tags(&store, &format!("user-{:04}", i * 2 + 1)), None);
+        }
+
+        // Before the first indexed key and after the last
+        assert_eq!(sorted_tags(&store, "a"), None);
+        assert_eq!(sorted_tags(&store, "user-9999"), None);
+    }
+
+    #[test]
+    fn pages_through_users_across_tables() {
+        let dir = scratch_dir("lsm-paging");
+        let store = open(&dir);
+
+        for user in ["alice", "carol", "erin"].iter() {
+            store.add_tag(user, "vip", 1_000, "test").unwrap();
+        }
+        flush(&store);
+        for user in ["bob", "dave"].iter() {
+            store.add_tag(user, "vip", 1_000, "test").unwrap();
+        }
+        store.remove_tag("carol", "vip", 1_001, "test").unwrap();
+
+        let page = store.users_with_tag("vip", None, 2).unwrap();
+        assert_eq!(page.users, vec!["alice", "bob"]);
+        assert_eq!((page.total, page.next.as_deref()), (4, Some("bob")));
+
+        let page = store.users_with_tag("vip", Some("bob"), 2).unwrap();
+        assert_eq!(page.users, vec!["dave", "erin"]);
+        assert_eq!((page.total, page.next.as_deref()), (4, None));
+
+        let page = store.users_with_tag("vip", Some("carol"), 1).unwrap();
+        assert_eq!(page.users, vec!["dave"]);
+        assert_eq!(page.next.as_deref(), Some("dave"));
+    }
+
+    #[test]
+    fn rejects_invalid_group_names() {
+        let dir = scratch_dir("lsm-groups");
+        let store = open(&dir);
+
+        for group in ["", "device:ios"].iter() {
+            let err = store.set_group("alice", group, &[String::from("web")], 1_000, "test").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+        assert_eq!(sorted_tags(&store, "alice"), None);
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+
+use ring::digest::{self, Context, SHA256};
+
+/// Users are spread over `2^LEAF_BITS` buckets, one per leaf of the tree.
+const LEAF_BITS: u32 = 10;
+pub const LEAVES: usize = 1 << LEAF_BITS;
+/// Nodes are numbered from 1 at the root, with the children of node `n` at `2n` and `2n + 1`, so
+/// the leaf for bucket `b` is node `LEAVES + b` and every node is below `NODES`.
+pub const NODES: usize = 2 * LEAVES;
+
+/// A node's SHA-256, all zeros for a subtree holding no users.
+pub type Digest = [u8; 32];
+
+const EMPTY: Digest = [0; 32];
+
+/// The bucket a user's state is kept in, which is the same on every node.
+pub fn bucket_of(user: &str) -> usize {
+    let hash = digest::digest(&SHA256, user.as_bytes());
+    let hash = hash.as_ref();
+    (u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) >> (32 - LEAF_BITS)) as usize
+}
+
+/// A Merkle tree over users' state, bucketed by `bucket_of`. Writers `touch` the users they
+/// change, and the leaves they dirtied are rehashed the next time digests are read.
+pub(crate) struct MerkleTree {
+    leaves: Vec<Mutex<Leaf>>,
+    /// Every node's digest as of the last refresh, indexed by node.
+    nodes: Mutex<Vec<Digest>>,
+}
+
+#[derive(Default)]
+struct Leaf {
+    /// Users that may have state in the bucket.
+    users: BTreeSet<String>,
+    /// Whether a user in the bucket changed since it was last hashed.
+    dirty: bool,
+}
+
+impl MerkleTree {
+    pub fn new() -> MerkleTree {
+        MerkleTree {
+            leaves: (0..LEAVES).map(|_| Mutex::new(Leaf::default())).collect(),
+            nodes: Mutex::new(vec![EMPTY; NODES]),
+        }
+    }
+
+    /// Notes that the user's state changed. Call after the change is visible to readers.
+    pub fn touch(&self, user: &str) {
+        let mut leaf = self.leaves[bucket_of(user)].lock().unwrap();
+        if !leaf.users.contains(user) {
+            leaf.users.insert(String::from(user));
+        }
+        leaf.dirty = true;
+    }
+
+    /// The users that may have state in `bucket`, in order.
+    pub fn users(&self, bucket: usize) -> Vec<String> {
+        self.leaves[bucket].lock().unwrap().users.iter().cloned().collect()
+    }
+
+    /// The digests of `nodes`, after rehashing any dirty leaves. `state` encodes a user's state
+    /// the same way on every node, or returns `None` if the user has none left.
+    pub fn digests<F>(&self, nodes: &[usize], state: F) -> Vec<Digest> where F: Fn(&str) -> Option<Vec<u8>> {
+        let mut tree = self.nodes.lock().unwrap();
+
+        let mut changed = false;
+        for (bucket, leaf) in self.leaves.iter().enumerate() {
+            let users = {
+                let mut leaf = leaf.lock().unwrap();
+                if !leaf.dirty {
+                    continue;
+                }
+
+                // Cleared first, so a change landing while the bucket is hashed dirties it again
+                leaf.dirty = false;
+                leaf.users.iter().cloned().collect::<Vec<String>>()
+            };
+
+            let mut context = Context::new(&SHA256);
+            let mut gone = Vec::new();
+            for user in users.iter() {
+                match state(user) {
+                    Some(state) => {
+                        context.update(&(user.len() as u32).to_le_bytes());
+                        context.update(user.as_bytes());
+                        context.update(&(state.len() as u32).to_le_bytes());
+                        context.update(&state);
+                    }
+                    None => gone.push(user),
+                }
+            }
+
+            let digest = if gone.len() == users.len() { EMPTY } else { to_digest(context.finish()) };
+
+            if !gone.is_empty() {
+                let mut leaf = leaf.lock().unwrap();
+                // A user that was dirtied again meanwhile may have state by now
+                if !leaf.dirty {
+                    for user in gone {
+                        leaf.users.remove(user);
+                    }
+                }
+            }
+
+            tree[LEAVES + bucket] = digest;
+            changed = true;
+        }
+
+        if changed {
+            for node in (1..LEAVES).rev() {
+                let (left, right) = (tree[2 * node], tree[2 * node + 1]);
+                tree[node] = if left == EMPTY && right == EMPTY {
+                    EMPTY
+                } else {
+                    let mut context = Context::new(&SHA256);
+                    context.update(&left);
+                    context.update(&right);
+                    to_digest(context.finish())
+                };
+            }
+        }
+
+        nodes.iter().map(|node| tree[*node]).collect()
+    }
+}
+
+fn to_digest(digest: digest::Digest) -> Digest {
+    let mut bytes = EMPTY;
+    bytes.copy_from_slice(digest.as_ref());
+    bytes
+}
@@ -0,0 +1,59 @@
+use request::Request;
+use std::io::Error;
+use std::sync::Arc;
+
+/// What should happen after a middleware's `before` hook runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flow {
+    /// Carry on to the next middleware, and eventually the handler.
+    Continue,
+    /// Skip the rest of the chain and the handler. The middleware must have sent a response.
+    Halt,
+}
+
+/// Cross-cutting logic run around handlers, such as auth, logging or timing. Middleware can be
+/// registered on a `WebServer`, a `Router`, or a single route, and runs in that order. `after` is
+/// called in reverse order for every middleware whose `before` ran, even if a later one halted.
+pub trait Middleware: Send + Sync {
+    fn before(&self, _request: &mut Request) -> Result<Flow, Error> {
+        Ok(Flow::Continue)
+    }
+
+    fn after(&self, _request: &Request, _result: &Result<(), Error>) {}
+}
+
+/// Runs `inner` wrapped in each of `middleware`, outermost first.
+pub(crate) fn run_chain<F>(middleware: &[Arc<dyn Middleware>], request: &mut Request, inner: F) -> Result<(), Error>
+    where F: FnOnce(&mut Request) -> Result<(), Error>
+{
+    let mut entered = 0;
+    let mut result = Ok(());
+    let mut halted = false;
+
+    for layer in middleware.iter() {
+        entered += 1;
+
+        match layer.before(request) {
+            Ok(Flow::Continue) => {}
+            Ok(Flow::Halt) => {
+                halted = true;
+                break;
+            }
+            Err(err) => {
+                result = Err(err);
+                halted = true;
+                break;
+            }
+        }
+    }
+
+    if !halted {
+        result = inner(request);
+    }
+
+    for layer in middleware[..entered].iter().rev() {
+        layer.after(request, &result);
+    }
+
+    result
+}
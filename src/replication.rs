@@ -0,0 +1,604 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use http::StatusCode;
+use serde_json;
+
+use backend::{self, TagBackend, SnapshotStats, GcStats, UserPage, Change, Operation};
+use client;
+use events::EventBus;
+use merkle::{Digest, LEAVES, NODES};
+use request::Request;
+use router::Handler;
+use tag_expr::Expr;
+
+/// Where peers accept batches of operations, as a `POST` of a JSON `Batch`.
+pub const REPLICATION_PATH: &str = "/replication/ops";
+/// Where peers answer `POST`s of `{"nodes": [...]}` with the hex digests of those nodes of their
+/// Merkle tree, as `{"digests": [...]}` in the same order.
+pub const MERKLE_PATH: &str = "/replication/merkle";
+/// Where peers answer `POST`s of `{"buckets": [...]}` with a `Batch` recreating everything they
+/// hold for the users in those buckets.
+pub const BUCKETS_PATH: &str = "/replication/buckets";
+
+/// Buckets fetched in each request during repair.
+const REPAIR_BUCKETS: usize = 64;
+
+/// Operations sent to a peer in one request.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Batch {
+    pub ops: Vec<Operation>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DigestRequest {
+    nodes: Vec<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DigestResponse {
+    digests: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BucketRequest {
+    buckets: Vec<usize>,
+}
+
+/// What a repair found differed from a peer and fetched.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RepairStats {
+    /// Buckets whose digests differed.
+    pub buckets: usize,
+    /// Operations fetched for them and merged.
+    pub operations: usize,
+}
+
+/// Tunables for how `ReplicatedBackend` ships operations to each peer.
+#[derive(Clone, Debug)]
+pub struct ReplicationConfig {
+    /// Most operations sent to a peer in one request.
+    pub batch_size: usize,
+    /// How long to wait before resending a batch that failed. Each later retry waits twice as
+    /// long as the last.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Timeout for connecting to a peer and for each read and write.
+    pub request_timeout: Duration,
+    /// Operations waiting for each peer. More are dropped and counted in `PeerStatus::dropped`.
+    pub queue_limit: usize,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> ReplicationConfig {
+        ReplicationConfig {
+            batch_size: 500,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            request_timeout: Duration::from_secs(10),
+            queue_limit: 100_000,
+        }
+    }
+}
+
+/// How replication to one peer is getting on.
+#[derive(Clone, Debug)]
+pub struct PeerStatus {
+    pub url: String,
+    /// Operations waiting to be sent, including any in flight.
+    pub pending: usize,
+    pub sent: u64,
+    /// Operations dropped because the peer's queue was full.
+    pub dropped: u64,
+    /// Operations dropped because the peer refused the batch they were in with a 4xx status,
+    /// which resending wouldn't change.
+    pub rejected: u64,
+    /// Why the last attempt to send failed, until one succeeds.
+    pub last_error: Option<String>,
+}
+
+/// A `TagBackend` that ships every write made through it to its peers, which apply them with
+/// the same last-write-wins rules. Since those rules make operations commutative and idempotent,
+/// replicas that have seen the same operations hold the same tags, whatever order they arrived
+/// in. Operations received from peers are applied to the wrapped backend directly rather than
+/// being passed on, so every node must list every other node as a peer.
+///
+/// Each peer has its own queue and sender, so an unreachable peer only holds up its own
+/// operations, which are resent with exponential backoff until it accepts them or refuses them
+/// with a 4xx status. Queues are only kept in memory, so operations still waiting when a node
+/// stops are lost, as are ones dropped from a full queue or refused. `repair` makes up for them by
+/// comparing Merkle trees with each peer.
+pub struct ReplicatedBackend {
+    inner: Arc<dyn TagBackend>,
+    config: ReplicationConfig,
+    peers: Mutex<Vec<(Arc<Peer>, JoinHandle<()>)>>,
+    stopping: Arc<AtomicBool>,
+}
+
+struct Peer {
+    url: String,
+    /// Where batches for the peer are sent.
+    endpoint: String,
+    queue: Mutex<PeerQueue>,
+    /// Signalled when an operation is queued or replication stops.
+    changed: Condvar,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    rejected: AtomicU64,
+}
+
+#[derive(Default)]
+struct PeerQueue {
+    ops: VecDeque<Arc<Operation>>,
+    last_error: Option<String>,
+}
+
+impl ReplicatedBackend {
+    /// Wraps `inner`, which receives every operation, local or replicated. Peers are added with
+    /// `add_peer`.
+    pub fn new(inner: Arc<dyn TagBackend>, config: ReplicationConfig) -> ReplicatedBackend {
+        ReplicatedBackend {
+            inner,
+            config,
+            peers: Mutex::new(Vec::new()),
+            stopping: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Starts shipping operations made from now on to the node at `url`, such as
+    /// `http://10.0.0.2:8080`, which serves a `ReplicationHandler` at `REPLICATION_PATH`.
+    pub fn add_peer(&self, url: &str) {
+        let peer = Arc::new(Peer {
+            url: String::from(url),
+            endpoint: format!("{}{}", url.trim_end_matches('/'), REPLICATION_PATH),
+            queue: Mutex::new(PeerQueue::default()),
+            changed: Condvar::new(),
+            sent: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        });
+
+        let sender = {
+            let peer = peer.clone();
+            let stopping = self.stopping.clone();
+            let config = self.config.clone();
+            thread::spawn(move || send(&peer, &stopping, &config))
+        };
+
+        self.peers.lock().unwrap().push((peer, sender));
+    }
+
+    /// How replication to each peer is getting on, in the order they were added.
+    pub fn status(&self) -> Vec<PeerStatus> {
+        self.peers.lock().unwrap().iter()
+            .map(|(peer, _)| {
+                let queue = peer.queue.lock().unwrap();
+                PeerStatus {
+                    url: peer.url.clone(),
+                    pending: queue.ops.len(),
+                    sent: peer.sent.load(Ordering::Relaxed),
+                    dropped: peer.dropped.load(Ordering::Relaxed),
+                    rejected: peer.rejected.load(Ordering::Relaxed),
+                    last_error: queue.last_error.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Applies operations received from a peer, without passing them on. Fails without applying
+    /// any if one of them is invalid.
+    pub fn apply_batch(&self, batch: &Batch) -> Result<(), Error> {
+        if let Some(err) = batch.ops.iter().find_map(Operation::invalid) {
+            return Err(Error::new(ErrorKind::InvalidInput, err));
+        }
+
+        for op in batch.ops.iter() {
+            op.apply(&*self.inner)?;
+        }
+
+        Ok(())
+    }
+
+    /// Compares the wrapped backend's Merkle tree with each peer's and merges in everything the
+    /// peer holds for the users in buckets that differ. Each side only fetches, so both sides
+    /// must repair to converge. Tries every peer, failing with the last error if any failed.
+    pub fn repair(&self) -> Result<RepairStats, Error> {
+        let urls: Vec<String> = self.peers.lock().unwrap().iter().map(|(peer, _)| peer.url.clone()).collect();
+
+        let mut stats = RepairStats::default();
+        let mut result = Ok(());
+        for url in urls {
+            match repair_from(&*self.inner, &url, self.config.request_timeout) {
+                Ok(repaired) => {
+                    stats.buckets += repaired.buckets;
+                    stats.operations += repaired.operations;
+                }
+                Err(e) => result = Err(e),
+            }
+        }
+
+        result.map(|_| stats)
+    }
+
+    /// Stops sending to peers and waits for the senders to finish. A request already under way
+    /// is completed first. Operations still queued are dropped.
+    pub fn shutdown(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+
+        let peers: Vec<(Arc<Peer>, JoinHandle<()>)> = self.peers.lock().unwrap().drain(..).collect();
+        for (peer, _) in peers.iter() {
+            // Taking the lock means no sender is between checking the flag and waiting
+            let _queue = peer.queue.lock().unwrap();
+            peer.changed.notify_all();
+        }
+
+        for (_, sender) in peers {
+            let _ = sender.join();
+        }
+    }
+
+    /// Applies a local write, then queues it for every peer if it succeeded.
+    fn replicate(&self, op: Operation) -> Result<(), Error> {
+        op.apply(&*self.inner)?;
+
+        let op = Arc::new(op);
+        for (peer, _) in self.peers.lock().unwrap().iter() {
+            let mut queue = peer.queue.lock().unwrap();
+            if queue.ops.len() >= self.config.queue_limit {
+                peer.dropped.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+
+            queue.ops.push_back(op.clone());
+            peer.changed.notify_all();
+        }
+
+        Ok(())
+    }
+}
+
+/// Sends the peer's queued operations in batches until replication stops, resending a batch
+/// until the peer accepts it or refuses it for good.
+fn send(peer: &Peer, stopping: &AtomicBool, config: &ReplicationConfig) {
+    let mut backoff = config.initial_backoff;
+
+    loop {
+        // Left queued until sent, so `status` counts them as pending
+        let batch = {
+            let mut queue = peer.queue.lock().unwrap();
+            while queue.ops.is_empty() && !stopping.load(Ordering::SeqCst) {
+                queue = peer.changed.wait(queue).unwrap();
+            }
+
+            if stopping.load(Ordering::SeqCst) {
+                return;
+            }
+
+            Batch {
+                ops: queue.ops.iter().take(config.batch_size).map(|op| (**op).clone()).collect(),
+            }
+        };
+
+        let body = serde_json::to_vec(&batch).expect("Batches always encode");
+        let headers = [("Content-Type", "application/json")];
+
+        let (error, refused) = match client::send("POST", &peer.endpoint, &headers, &body, config.request_timeout) {
+            Ok(ref response) if (200..300).contains(&response.status) => (None, false),
+            Ok(response) => (Some(rejection(&response)), refuses_for_good(response.status)),
+            Err(e) => (Some(e.to_string()), false),
+        };
+
+        let mut queue = peer.queue.lock().unwrap();
+        match error {
+            None => {
+                queue.ops.drain(..batch.ops.len());
+                queue.last_error = None;
+                peer.sent.fetch_add(batch.ops.len() as u64, Ordering::Relaxed);
+                backoff = config.initial_backoff;
+            }
+            // Left as the last error, so the refusal shows up in `status`
+            Some(error) if refused => {
+                queue.ops.drain(..batch.ops.len());
+                queue.last_error = Some(error);
+                peer.rejected.fetch_add(batch.ops.len() as u64, Ordering::Relaxed);
+                backoff = config.initial_backoff;
+            }
+            Some(error) => {
+                queue.last_error = Some(error);
+
+                let deadline = Instant::now() + backoff;
+                while !stopping.load(Ordering::SeqCst) {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    queue = peer.changed.wait_timeout(queue, deadline - now).unwrap().0;
+                }
+                backoff = (backoff * 2).min(config.max_backoff);
+            }
+        }
+    }
+}
+
+/// Walks down the Merkle trees of `local` and the peer at `url` from the root, a level at a time,
+/// then fetches and merges the peer's contents for the buckets whose leaves differ.
+fn repair_from(local: &dyn TagBackend, url: &str, timeout: Duration) -> Result<RepairStats, Error> {
+    let base = url.trim_end_matches('/');
+
+    let mut nodes = vec![1];
+    let mut buckets = Vec::new();
+    while !nodes.is_empty() {
+        let remote: DigestResponse = post_json(&format!("{}{}", base, MERKLE_PATH), &DigestRequest { nodes: nodes.clone() }, timeout)?;
+        if remote.digests.len() != nodes.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "Peer sent the wrong number of digests"));
+        }
+
+        let mut next = Vec::new();
+        for ((node, digest), remote) in nodes.iter().zip(local.merkle_digests(&nodes)?).zip(remote.digests) {
+            if backend::hex(&digest) == remote {
+                continue;
+            }
+
+            if *node >= LEAVES {
+                buckets.push(node - LEAVES);
+            } else {
+                next.push(2 * node);
+                next.push(2 * node + 1);
+            }
+        }
+        nodes = next;
+    }
+
+    let mut stats = RepairStats { buckets: buckets.len(), operations: 0 };
+    for chunk in buckets.chunks(REPAIR_BUCKETS) {
+        let batch: Batch = post_json(&format!("{}{}", base, BUCKETS_PATH), &BucketRequest { buckets: chunk.to_vec() }, timeout)?;
+        if let Some(err) = batch.ops.iter().find_map(Operation::invalid) {
+            return Err(Error::new(ErrorKind::InvalidData, err));
+        }
+
+        local.merge(&batch.ops)?;
+        stats.operations += batch.ops.len();
+    }
+
+    Ok(stats)
+}
+
+/// Posts `body` as JSON and parses the JSON response, failing unless the status is 2xx.
+fn post_json<T: ::serde::Serialize, R: ::serde::de::DeserializeOwned>(url: &str, body: &T, timeout: Duration) -> Result<R, Error> {
+    let body = serde_json::to_vec(body)?;
+    let response = client::send("POST", url, &[("Content-Type", "application/json")], &body, timeout)?;
+
+    if !(200..300).contains(&response.status) {
+        return Err(Error::other(rejection(&response)));
+    }
+    serde_json::from_slice(&response.body).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Whether a peer responding with `status` would refuse the same request again, as it does to a
+/// batch it finds invalid. Timeouts and rate limits are worth retrying.
+fn refuses_for_good(status: u16) -> bool {
+    (400..500).contains(&status) && status != 408 && status != 429
+}
+
+/// Describes a response a peer failed a request with.
+fn rejection(response: &client::Response) -> String {
+    format!("Peer responded {}: {}", response.status, String::from_utf8_lossy(&response.body).trim())
+}
+
+impl TagBackend for ReplicatedBackend {
+    fn add_tag(&self, user: &str, tag: &str, ts: i64, source: &str) -> Result<(), Error> {
+        self.replicate(Operation::Add {
+            user: String::from(user),
+            tag: String::from(tag),
+            timestamp: ts,
+            expires_at: None,
+            source: String::from(source),
+        })
+    }
+
+    fn remove_tag(&self, user: &str, tag: &str, ts: i64, source: &str) -> Result<(), Error> {
+        self.replicate(Operation::Remove {
+            user: String::from(user),
+            tag: String::from(tag),
+            timestamp: ts,
+            source: String::from(source),
+        })
+    }
+
+    fn add_expiring_tag(&self, user: &str, tag: &str, ts: i64, expires_at: i64, source: &str) -> Result<(), Error> {
+        self.replicate(Operation::Add {
+            user: String::from(user),
+            tag: String::from(tag),
+            timestamp: ts,
+            expires_at: Some(expires_at),
+            source: String::from(source),
+        })
+    }
+
+    fn set_group(&self, user: &str, group: &str, tags: &[String], ts: i64, source: &str) -> Result<(), Error> {
+        self.replicate(Operation::SetGroup {
+            user: String::from(user),
+            group: String::from(group),
+            tags: tags.to_vec(),
+            timestamp: ts,
+            source: String::from(source),
+        })
+    }
+
+    fn user_tags(&self, user: &str) -> Result<Option<Vec<String>>, Error> {
+        self.inner.user_tags(user)
+    }
+
+    fn set_attribute(&self, user: &str, key: &str, value: &str, ts: i64) -> Result<(), Error> {
+        self.replicate(Operation::SetAttribute {
+            user: String::from(user),
+            key: String::from(key),
+            value: String::from(value),
+            timestamp: ts,
+        })
+    }
+
+    fn remove_attribute(&self, user: &str, key: &str, ts: i64) -> Result<(), Error> {
+        self.replicate(Operation::RemoveAttribute {
+            user: String::from(user),
+            key: String::from(key),
+            timestamp: ts,
+        })
+    }
+
+    fn user_attributes(&self, user: &str) -> Result<Option<HashMap<String, String>>, Error> {
+        self.inner.user_attributes(user)
+    }
+
+    fn tags_for_user(&self, user: &str) -> Result<Vec<String>, Error> {
+        self.inner.tags_for_user(user)
+    }
+
+    fn users_with_tag(&self, tag: &str, after: Option<&str>, limit: usize) -> Result<UserPage, Error> {
+        self.inner.users_with_tag(tag, after, limit)
+    }
+
+    fn audience(&self, expr: &Expr) -> Result<BTreeSet<String>, Error> {
+        self.inner.audience(expr)
+    }
+
+    fn users_matching(&self, expr: &Expr, after: Option<&str>, limit: usize) -> Result<UserPage, Error> {
+        self.inner.users_matching(expr, after, limit)
+    }
+
+    fn history(&self, user: &str) -> Result<Option<Vec<Change>>, Error> {
+        self.inner.history(user)
+    }
+
+    fn events(&self) -> Option<&EventBus> {
+        self.inner.events()
+    }
+
+    // Every node sweeps its own expiries, which the expiry carried by the add makes identical
+    fn expire_tags(&self) -> Result<u64, Error> {
+        self.inner.expire_tags()
+    }
+
+    fn snapshot(&self) -> Result<SnapshotStats, Error> {
+        self.inner.snapshot()
+    }
+
+    fn collect_garbage(&self, horizon: Duration) -> Result<GcStats, Error> {
+        self.inner.collect_garbage(horizon)
+    }
+
+    fn gc_totals(&self) -> GcStats {
+        self.inner.gc_totals()
+    }
+
+    fn merkle_digests(&self, nodes: &[usize]) -> Result<Vec<Digest>, Error> {
+        self.inner.merkle_digests(nodes)
+    }
+
+    fn bucket_contents(&self, buckets: &[usize]) -> Result<Vec<Operation>, Error> {
+        self.inner.bucket_contents(buckets)
+    }
+
+    // Merged rather than replicated, since every peer repairs for itself
+    fn merge(&self, ops: &[Operation]) -> Result<(), Error> {
+        self.inner.merge(ops)
+    }
+}
+
+/// Applies batches of operations `POST`ed by peers at `REPLICATION_PATH`.
+pub struct ReplicationHandler {
+    backend: Arc<ReplicatedBackend>,
+}
+
+impl ReplicationHandler {
+    pub fn new(backend: Arc<ReplicatedBackend>) -> ReplicationHandler {
+        ReplicationHandler { backend }
+    }
+}
+
+impl Handler for ReplicationHandler {
+    fn handle(&self, request: &mut Request) -> Result<(), Error> {
+        let result = read_json(request).and_then(|batch: Batch| {
+            self.backend.apply_batch(&batch)?;
+            Ok(serde_json::json!({ "applied": batch.ops.len() }))
+        });
+
+        respond(request, result)
+    }
+}
+
+/// Answers peers comparing Merkle trees at `MERKLE_PATH`.
+pub struct MerkleHandler {
+    backend: Arc<dyn TagBackend>,
+}
+
+impl MerkleHandler {
+    pub fn new(backend: Arc<dyn TagBackend>) -> MerkleHandler {
+        MerkleHandler { backend }
+    }
+}
+
+impl Handler for MerkleHandler {
+    fn handle(&self, request: &mut Request) -> Result<(), Error> {
+        let result = read_json(request).and_then(|digest_request: DigestRequest| {
+            if digest_request.nodes.len() > LEAVES || !digest_request.nodes.iter().all(|node| (1..NODES).contains(node)) {
+                let err = format!("Expected up to {} nodes, each from 1 to {}", LEAVES, NODES - 1);
+                return Err(Error::new(ErrorKind::InvalidInput, err));
+            }
+
+            let digests = self.backend.merkle_digests(&digest_request.nodes)?;
+            Ok(DigestResponse { digests: digests.iter().map(|digest| backend::hex(digest)).collect() })
+        });
+
+        respond(request, result)
+    }
+}
+
+/// Sends peers repairing from this node the contents of buckets at `BUCKETS_PATH`.
+pub struct BucketsHandler {
+    backend: Arc<dyn TagBackend>,
+}
+
+impl BucketsHandler {
+    pub fn new(backend: Arc<dyn TagBackend>) -> BucketsHandler {
+        BucketsHandler { backend }
+    }
+}
+
+impl Handler for BucketsHandler {
+    fn handle(&self, request: &mut Request) -> Result<(), Error> {
+        let result = read_json(request).and_then(|bucket_request: BucketRequest| {
+            if bucket_request.buckets.len() > LEAVES || !bucket_request.buckets.iter().all(|bucket| *bucket < LEAVES) {
+                let err = format!("Expected up to {} buckets, each from 0 to {}", LEAVES, LEAVES - 1);
+                return Err(Error::new(ErrorKind::InvalidInput, err));
+            }
+
+            Ok(Batch { ops: self.backend.bucket_contents(&bucket_request.buckets)? })
+        });
+
+        respond(request, result)
+    }
+}
+
+fn read_json<T: ::serde::de::DeserializeOwned>(request: &mut Request) -> Result<T, Error> {
+    let body = request.read_body()?;
+    serde_json::from_slice(&body).map_err(|_| Error::new(ErrorKind::InvalidInput, "Couldn't parse request JSON"))
+}
+
+/// Sends `result` as JSON, or a plain text error for an invalid request or one the backend
+/// doesn't support.
+fn respond<T: ::serde::Serialize>(request: &mut Request, result: Result<T, Error>) -> Result<(), Error> {
+    let (status, body) = match result {
+        Ok(response) => (StatusCode::OK, serde_json::to_vec(&response)?),
+        Err(ref e) if e.kind() == ErrorKind::InvalidInput => (StatusCode::BAD_REQUEST, e.to_string().into_bytes()),
+        Err(ref e) if e.kind() == ErrorKind::Unsupported => (StatusCode::NOT_IMPLEMENTED, e.to_string().into_bytes()),
+        Err(e) => return Err(e),
+    };
+
+    request.send_preamble(status, body.len())?;
+    request.write_all(&body)
+}
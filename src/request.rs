@@ -1,35 +1,296 @@
-use std::net::TcpStream;
 use std::collections::HashMap;
-use std::io::{Write, Error, BufReader, BufWriter, BufRead};
+use std::cmp;
+use std::io::{Read, Write, Error, ErrorKind, BufReader, BufWriter, BufRead};
+use std::time::{Duration, Instant};
 use http::StatusCode;
 
+use config::ServerConfig;
+use stream::Stream;
 
-pub const SPACE: &[u8] = &[b' '];
-pub const COLON: &[u8] = &[b':'];
+
+pub const SPACE: &[u8] = b" ";
+pub const COLON: &[u8] = b":";
 pub const HTTP_VERSION: &str = "HTTP/1.1";
+pub const HTTP_VERSION_1_0: &str = "HTTP/1.0";
 pub const CONTENT_LENGTH: &str = "Content-Length:";
-pub const NEWLINE: &[u8] = &[b'\n'];
-pub const RETURN_NEWLINE: &[u8] = &[b'\r', b'\n'];
+pub const CONNECTION: &str = "Connection:";
+pub const TRANSFER_ENCODING: &str = "Transfer-Encoding:";
+pub const NEWLINE: &[u8] = b"\n";
+pub const RETURN_NEWLINE: &[u8] = b"\r\n";
+
+
+/// Reasons `Request::parse_request` can fail to produce a request.
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// The peer closed the connection, or went idle, before sending a request line.
+    ConnectionClosed,
+    /// The request was malformed and should be answered with a 400.
+    BadRequest(&'static str),
+    /// The request head wasn't received within the header timeout, answered with a 408.
+    Timeout,
+    /// The request line exceeded the configured maximum, answered with a 414.
+    RequestLineTooLong,
+    /// There were too many headers, or too many header bytes, answered with a 431.
+    HeadersTooLarge,
+}
+
+/// Failures while reading one line of the request head.
+enum HeadError {
+    /// The connection closed before the line began.
+    Closed,
+    /// The connection closed partway through the line.
+    Truncated,
+    Timeout,
+    TooLong,
+    Io,
+    InvalidUtf8,
+}
+
+/// Reads the request line and headers, bounding both the bytes consumed and the total time taken
+/// so a client trickling bytes can't hold a worker indefinitely.
+struct HeadReader<'a> {
+    reader: &'a mut BufReader<Stream>,
+    deadline: Instant,
+    remaining_bytes: usize,
+}
+
+impl<'a> HeadReader<'a> {
+    fn read_line(&mut self, max_len: usize) -> Result<String, HeadError> {
+        let max_len = cmp::min(max_len, self.remaining_bytes);
+        let mut line = Vec::new();
+
+        loop {
+            if self.reader.buffer().is_empty() {
+                let now = Instant::now();
+                if now >= self.deadline {
+                    return Err(HeadError::Timeout);
+                }
+
+                if self.reader.get_ref().set_read_timeout(Some(self.deadline - now)).is_err() {
+                    return Err(HeadError::Io);
+                }
+            }
+
+            let (found_newline, used) = {
+                let buf = match self.reader.fill_buf() {
+                    Ok(buf) => buf,
+                    Err(ref e) if is_timeout_error(e) => return Err(HeadError::Timeout),
+                    Err(_) => return Err(HeadError::Io),
+                };
+
+                if buf.is_empty() {
+                    return Err(if line.is_empty() { HeadError::Closed } else { HeadError::Truncated });
+                }
 
+                match buf.iter().position(|b| *b == b'\n') {
+                    Some(idx) => {
+                        line.extend_from_slice(&buf[..=idx]);
+                        (true, idx + 1)
+                    }
+                    None => {
+                        line.extend_from_slice(buf);
+                        (false, buf.len())
+                    }
+                }
+            };
+
+            self.reader.consume(used);
+
+            if line.len() > max_len {
+                return Err(HeadError::TooLong);
+            }
+
+            if found_newline {
+                self.remaining_bytes -= line.len();
+                return String::from_utf8(line).map_err(|_| HeadError::InvalidUtf8);
+            }
+        }
+    }
+}
+
+/// Reads the request body, failing with `TimedOut` once the body deadline passes so a client
+/// trickling bytes can't hold a worker indefinitely.
+struct BodyReader<'a> {
+    reader: &'a mut BufReader<Stream>,
+    deadline: Instant,
+    read_timeout: Duration,
+}
+
+impl<'a> BodyReader<'a> {
+    /// Bounds the next read from the socket by whatever is left until the deadline.
+    fn arm(&mut self) -> Result<(), Error> {
+        if !self.reader.buffer().is_empty() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Err(Error::new(ErrorKind::TimedOut, "Timed out reading request body"));
+        }
+
+        self.reader.get_ref().set_read_timeout(Some(cmp::min(self.read_timeout, self.deadline - now)))
+    }
+}
+
+impl<'a> Read for BodyReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.arm()?;
+        self.reader.read(buf)
+    }
+}
+
+impl<'a> BufRead for BodyReader<'a> {
+    fn fill_buf(&mut self) -> Result<&[u8], Error> {
+        self.arm()?;
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt)
+    }
+}
+
+/// How the response body is delimited on the wire, fixed once the preamble is sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ResponseBody {
+    /// `Content-Length` was sent, writes pass straight through.
+    Sized,
+    /// `Transfer-Encoding: chunked`, every write is framed as its own chunk.
+    Chunked,
+    /// The client can't decode chunks (HTTP/1.0), the body runs until the connection closes.
+    UntilClose,
+}
+
+/// How the request body is delimited on the wire, determined from the request headers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RequestBody {
+    /// `Content-Length` bytes, of which `remaining` are still unread.
+    Sized { remaining: u64 },
+    /// `Transfer-Encoding: chunked`, `remaining` bytes are left in the current chunk.
+    Chunked { remaining: u64, finished: bool },
+}
+
+/// Longest chunk size line accepted in a chunked request body, extensions included.
+const MAX_CHUNK_LINE: u64 = 1024;
+
+const TRUNCATED_HEAD: &str = "Connection closed before the end of the request head";
 
 pub struct Request {
     pub request_headers: HashMap<String, Vec<String>>,
     pub query_params: HashMap<String, Vec<String>>,
     pub path: String,
     pub verb: String,
-    pub reader: BufReader<TcpStream>,
-    writer: BufWriter<TcpStream>,
+    pub version: String,
+    pub path_params: HashMap<String, String>,
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+    request_body: RequestBody,
+    body_read: u64,
+    body_deadline: Instant,
+    read_timeout: Duration,
+    max_body_size: u64,
+    body_too_large: bool,
     response_headers: HashMap<String, Vec<String>>,
     response_headers_sent: bool,
+    response_body: ResponseBody,
+    response_finished: bool,
+    keep_alive: bool,
 }
 
-impl<'a> Write for Request {
+/// Reads the request body, decoding chunked transfer-encoding and stopping at the end of the body
+/// so the next request on the connection is left untouched.
+impl Read for Request {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self.request_body {
+            RequestBody::Sized { remaining } => {
+                if remaining == 0 || buf.is_empty() {
+                    return Ok(0);
+                }
+
+                let max = cmp::min(buf.len() as u64, remaining) as usize;
+                let read = self.body_reader().read(&mut buf[..max])?;
+                if read == 0 {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "Request body shorter than Content-Length"));
+                }
+
+                self.body_read += read as u64;
+                self.request_body = RequestBody::Sized { remaining: remaining - read as u64 };
+                Ok(read)
+            }
+
+            RequestBody::Chunked { finished: true, .. } => Ok(0),
+
+            RequestBody::Chunked { remaining, .. } => {
+                if buf.is_empty() {
+                    return Ok(0);
+                }
+
+                let remaining = if remaining == 0 {
+                    let size = self.read_chunk_size()?;
+                    if size == 0 {
+                        self.skip_trailers()?;
+                        self.request_body = RequestBody::Chunked { remaining: 0, finished: true };
+                        return Ok(0);
+                    }
+
+                    if self.body_read.saturating_add(size) > self.max_body_size {
+                        self.body_too_large = true;
+                        return Err(Error::new(ErrorKind::InvalidData, "Request body too large"));
+                    }
+
+                    size
+                } else {
+                    remaining
+                };
+
+                let max = cmp::min(buf.len() as u64, remaining) as usize;
+                let read = self.body_reader().read(&mut buf[..max])?;
+                if read == 0 {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "Request body ended mid-chunk"));
+                }
+
+                let remaining = remaining - read as u64;
+                if remaining == 0 {
+                    let mut crlf = [0; 2];
+                    self.body_reader().read_exact(&mut crlf)?;
+                    if crlf != RETURN_NEWLINE {
+                        return Err(Error::new(ErrorKind::InvalidData, "Chunk not terminated by CRLF"));
+                    }
+                }
+
+                self.body_read += read as u64;
+                self.request_body = RequestBody::Chunked { remaining, finished: false };
+                Ok(read)
+            }
+        }
+    }
+}
+
+impl Write for Request {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
         if !self.response_headers_sent {
             panic!("Attempted to write body before begin_response called")
         }
 
-        self.writer.write(buf)
+        if self.response_finished {
+            panic!("Attempted to write body after finish called")
+        }
+
+        match self.response_body {
+            ResponseBody::Chunked => {
+                // An empty chunk would terminate the body, so there is nothing to frame
+                if buf.is_empty() {
+                    return Ok(0);
+                }
+
+                self.writer.write_all(format!("{:X}", buf.len()).as_bytes())?;
+                self.writer.write_all(RETURN_NEWLINE)?;
+                self.writer.write_all(buf)?;
+                self.writer.write_all(RETURN_NEWLINE)?;
+                Ok(buf.len())
+            }
+            ResponseBody::Sized | ResponseBody::UntilClose => self.writer.write(buf),
+        }
     }
 
     fn flush(&mut self) -> Result<(), Error> {
@@ -37,54 +298,94 @@ impl<'a> Write for Request {
     }
 }
 
-impl<'a> Request {
-    fn new(
-        request_headers: HashMap<String, Vec<String>>,
-        query_params: HashMap<String, Vec<String>>,
-        path: String,
-        verb: String,
-        reader: BufReader<TcpStream>,
-        writer: BufWriter<TcpStream>,
-        response_headers: HashMap<String, Vec<String>>,
-        response_body_writeable: bool) -> Request {
-        Request {
-            request_headers,
-            query_params,
-            path,
-            verb,
-            reader,
-            writer,
-            response_headers,
-            response_headers_sent: response_body_writeable,
-        }
-    }
-
+impl Request {
     pub fn response_headers_sent(&self) -> bool {
         self.response_headers_sent
     }
 
-    pub fn parse_request(reader: BufReader<TcpStream>, writer: BufWriter<TcpStream>) -> Result<Request, &'static str> {
+    /// Whether the connection will be reused for another request once this one is answered.
+    pub fn keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    /// Overrides whether the connection is kept open after this response. Must be called before
+    /// `send_preamble` so the `Connection` header matches.
+    pub fn set_keep_alive(&mut self, keep_alive: bool) {
+        if self.response_headers_sent {
+            panic!("Attempted to change keep-alive after begin_response called")
+        }
+
+        self.keep_alive = keep_alive;
+    }
+
+    /// Parses the request line and headers from the connection, enforcing the head size limits
+    /// and timeouts in `config`. The socket's existing read timeout bounds the wait for the first
+    /// byte, once the head has been read `config.read_timeout` applies to each body read and
+    /// `config.body_timeout` to the body as a whole.
+    pub fn parse_request(reader: BufReader<Stream>, writer: BufWriter<Stream>, config: &ServerConfig)
+                         -> Result<Request, ParseError> {
         let mut reader = reader;
         let writer = writer;
 
-        let mut request_line = String::new();
-        if let Err(_) = reader.read_line(&mut request_line) {
-            return Err("Couldn't read request line");
+        match reader.fill_buf() {
+            Ok(buf) => if buf.is_empty() {
+                return Err(ParseError::ConnectionClosed);
+            },
+            Err(ref e) if is_idle_error(e) => return Err(ParseError::ConnectionClosed),
+            Err(_) => return Err(ParseError::BadRequest("Couldn't read request line")),
+        }
+
+        let (request_line, request_headers) = {
+            let mut head = HeadReader {
+                reader: &mut reader,
+                deadline: Instant::now() + config.header_timeout,
+                remaining_bytes: config.max_request_line + config.max_header_bytes,
+            };
+
+            let mut request_line;
+            loop {
+                request_line = match head.read_line(config.max_request_line) {
+                    Ok(line) => line,
+                    Err(HeadError::Closed) => return Err(ParseError::ConnectionClosed),
+                    Err(HeadError::Truncated) => return Err(ParseError::BadRequest(TRUNCATED_HEAD)),
+                    Err(HeadError::Timeout) => return Err(ParseError::Timeout),
+                    Err(HeadError::TooLong) => return Err(ParseError::RequestLineTooLong),
+                    Err(HeadError::InvalidUtf8) => return Err(ParseError::BadRequest("Request line is not valid UTF-8")),
+                    Err(HeadError::Io) => return Err(ParseError::BadRequest("Couldn't read request line")),
+                };
+
+                // Clients may send stray CRLFs between pipelined requests, skip them
+                if !request_line.trim().is_empty() {
+                    break;
+                }
+            }
+
+            head.remaining_bytes = cmp::min(head.remaining_bytes, config.max_header_bytes);
+            let request_headers = Request::parse_headers(&mut head, config.max_header_count)?;
+
+            (request_line, request_headers)
+        };
+
+        if reader.get_ref().set_read_timeout(Some(config.read_timeout)).is_err() {
+            return Err(ParseError::BadRequest("Couldn't set read timeout"));
         }
 
         let mut request_parts: Vec<&str> = request_line.split_whitespace().collect();
 
         if request_parts.len() != 3 {
-            return Err("Couldn't parse request line");
+            return Err(ParseError::BadRequest("Couldn't parse request line"));
         }
 
-        request_parts.pop(); //Discard http version
-
+        let version = request_parts.pop().unwrap();
         let path_and_params = request_parts.pop().unwrap();
         let verb = request_parts.pop().unwrap();
 
+        if !version.starts_with("HTTP/") {
+            return Err(ParseError::BadRequest("Couldn't parse HTTP version"));
+        }
+
         if !path_and_params.starts_with('/') {
-            return Err("Request path must start with a '/'");
+            return Err(ParseError::BadRequest("Request path must start with a '/'"));
         }
 
         let mut path_and_params: Vec<&str> = path_and_params.splitn(2, '?').collect();
@@ -97,23 +398,179 @@ impl<'a> Request {
             len => panic!("Unexpected path and param split length {} from request line {}", len, request_line)
         };
 
-        let request_headers: HashMap<String, Vec<String>> = match Request::parse_headers(&mut reader) {
-            Ok(map) => map,
-            Err(err) => return Err(err),
+        let mut request = Request {
+            request_headers,
+            query_params,
+            path: String::from(path),
+            verb: String::from(verb),
+            version: String::from(version),
+            path_params: HashMap::new(),
+            reader,
+            writer,
+            request_body: RequestBody::Sized { remaining: 0 },
+            body_read: 0,
+            body_deadline: Instant::now() + config.body_timeout,
+            read_timeout: config.read_timeout,
+            max_body_size: u64::MAX,
+            body_too_large: false,
+            response_headers: HashMap::new(),
+            response_headers_sent: false,
+            response_body: ResponseBody::Sized,
+            response_finished: false,
+            keep_alive: false,
+        };
+        request.keep_alive = request.client_wants_keep_alive();
+        request.request_body = request.parse_body_framing().map_err(ParseError::BadRequest)?;
+
+        Ok(request)
+    }
+
+    /// Whether the request carries a body, either chunked or with a non-zero `Content-Length`.
+    pub fn has_body(&self) -> bool {
+        match self.request_body {
+            RequestBody::Sized { remaining } => remaining > 0 || self.body_read > 0,
+            RequestBody::Chunked { .. } => true,
+        }
+    }
+
+    /// Reads the whole request body into memory, failing if it is larger than the server's
+    /// configured maximum.
+    pub fn read_body(&mut self) -> Result<Vec<u8>, Error> {
+        let capacity = match self.request_body {
+            RequestBody::Sized { remaining } => remaining as usize,
+            RequestBody::Chunked { .. } => 0,
         };
 
-        Ok(
-            Request::new(
-                request_headers,
-                query_params,
-                String::from(path),
-                String::from(verb),
-                reader,
-                writer,
-                HashMap::new(),
-                false,
-            )
-        )
+        let mut body = Vec::with_capacity(capacity);
+        self.read_to_end(&mut body)?;
+        Ok(body)
+    }
+
+    /// Whether reading the body failed because it exceeded the configured maximum.
+    pub fn body_too_large(&self) -> bool {
+        self.body_too_large
+    }
+
+    /// Applies the server's body size limit. Returns false if the declared `Content-Length`
+    /// already exceeds it, chunked bodies are checked as they are read.
+    pub(crate) fn limit_body_size(&mut self, max_body_size: u64) -> bool {
+        self.max_body_size = max_body_size;
+
+        if let RequestBody::Sized { remaining } = self.request_body {
+            if remaining > max_body_size {
+                self.body_too_large = true;
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Reads and discards whatever part of the body the handler left unread, so the connection is
+    /// positioned at the start of the next request.
+    pub(crate) fn discard_body(&mut self) -> Result<(), Error> {
+        let mut buf = [0; 4096];
+        while self.read(&mut buf)? > 0 {}
+        Ok(())
+    }
+
+    fn parse_body_framing(&self) -> Result<RequestBody, &'static str> {
+        let content_length = self.request_headers.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+            .map(|(_, values)| values);
+
+        if let Some(encoding) = self.get_request_header("Transfer-Encoding") {
+            if content_length.is_some() {
+                return Err("Request has both Content-Length and Transfer-Encoding");
+            }
+
+            if !encoding.eq_ignore_ascii_case("chunked") {
+                return Err("Unsupported Transfer-Encoding");
+            }
+
+            return Ok(RequestBody::Chunked { remaining: 0, finished: false });
+        }
+
+        let remaining = match content_length {
+            None => 0,
+            Some(values) => {
+                let mut length = None;
+                for value in values {
+                    let parsed = match value.parse::<u64>() {
+                        Ok(parsed) => parsed,
+                        Err(_) => return Err("Invalid Content-Length"),
+                    };
+
+                    if length.is_some() && length != Some(parsed) {
+                        return Err("Conflicting Content-Length headers");
+                    }
+                    length = Some(parsed);
+                }
+
+                length.unwrap_or(0)
+            }
+        };
+
+        Ok(RequestBody::Sized { remaining })
+    }
+
+    fn body_reader(&mut self) -> BodyReader<'_> {
+        BodyReader {
+            reader: &mut self.reader,
+            deadline: self.body_deadline,
+            read_timeout: self.read_timeout,
+        }
+    }
+
+    fn read_chunk_size(&mut self) -> Result<u64, Error> {
+        let mut line = String::new();
+        self.body_reader().take(MAX_CHUNK_LINE).read_line(&mut line)?;
+
+        if !line.ends_with('\n') {
+            return Err(Error::new(ErrorKind::InvalidData, "Malformed chunk size line"));
+        }
+
+        // Chunk extensions follow a ';' and are ignored
+        let size = line.split(';').next().unwrap_or("").trim();
+        u64::from_str_radix(size, 16)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Malformed chunk size"))
+    }
+
+    fn skip_trailers(&mut self) -> Result<(), Error> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            self.body_reader().take(MAX_CHUNK_LINE).read_line(&mut line)?;
+
+            if !line.ends_with('\n') {
+                return Err(Error::new(ErrorKind::InvalidData, "Malformed chunked body trailer"));
+            }
+
+            if line.trim().is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Hands the connection back so the next request can be parsed from it.
+    pub(crate) fn into_parts(self) -> (BufReader<Stream>, BufWriter<Stream>) {
+        (self.reader, self.writer)
+    }
+
+    fn client_wants_keep_alive(&self) -> bool {
+        if let Some(connection) = self.get_request_header("Connection") {
+            for token in connection.split(',') {
+                let token = token.trim();
+                if token.eq_ignore_ascii_case("close") {
+                    return false;
+                } else if token.eq_ignore_ascii_case("keep-alive") {
+                    return true;
+                }
+            }
+        }
+
+        // HTTP/1.1 connections are persistent unless told otherwise, earlier versions are not
+        self.version != HTTP_VERSION_1_0 && self.version != "HTTP/0.9"
     }
 
     fn parse_query_params(params: &str) -> HashMap<String, Vec<String>> {
@@ -131,108 +588,441 @@ impl<'a> Request {
                 Some(param) => String::from(param),
             };
 
-            let values = query_params.entry(param).or_insert(Vec::new());
+            let values: &mut Vec<String> = query_params.entry(param).or_default();
             values.push(value)
         };
 
         query_params
     }
 
-    fn parse_headers(reader: &mut BufReader<TcpStream>) -> Result<HashMap<String, Vec<String>>, &'static str> {
+    fn parse_headers(head: &mut HeadReader, max_header_count: usize) -> Result<HashMap<String, Vec<String>>, ParseError> {
         let mut headers = HashMap::new();
-        let mut line = String::new();
+        let mut header_count = 0;
         loop {
-            line.clear();
-            match reader.read_line(&mut line) {
-                Ok(cnt) => if cnt == 0 {
-                    break;
-                },
-                Err(_) => return Err("Error reading headers"),
-            }
+            let line = match head.read_line(usize::MAX) {
+                Ok(line) => line,
+                // The peer went away before the blank line ending the head
+                Err(HeadError::Closed) | Err(HeadError::Truncated) => return Err(ParseError::BadRequest(TRUNCATED_HEAD)),
+                Err(HeadError::Timeout) => return Err(ParseError::Timeout),
+                Err(HeadError::TooLong) => return Err(ParseError::HeadersTooLarge),
+                Err(HeadError::InvalidUtf8) => return Err(ParseError::BadRequest("Header is not valid UTF-8")),
+                Err(HeadError::Io) => return Err(ParseError::BadRequest("Error reading headers")),
+            };
 
-            if line.eq("\r\n") {
+            if line.eq("\r\n") || line.eq("\n") {
                 break;
             }
 
+            header_count += 1;
+            if header_count > max_header_count {
+                return Err(ParseError::HeadersTooLarge);
+            }
+
             let mut header_and_value: Vec<&str> = line.splitn(2, ':').collect();
 
             let value = match header_and_value.pop() {
                 Some(value) => String::from(value.trim()),
-                None => return Err("Malformed header line"),
+                None => return Err(ParseError::BadRequest("Malformed header line")),
             };
 
             let header = match header_and_value.pop() {
                 Some(header) => String::from(header.trim()),
-                None => return Err("Malformed header line"),
+                None => return Err(ParseError::BadRequest("Malformed header line")),
             };
 
             if header.is_empty() {
-                return Err("Empty header name");
+                return Err(ParseError::BadRequest("Empty header name"));
             }
 
-            let values = headers.entry(header).or_insert(Vec::new());
+            let values: &mut Vec<String> = headers.entry(header).or_default();
             values.push(value)
         }
 
         Ok(headers)
     }
 
+    /// Returns the first value of a request header. Header names are matched case-insensitively.
     pub fn get_request_header(&self, header: &str) -> Option<&String> {
-        self.request_headers.get(&String::from(header))
-            .and_then(|v| { v.get(0) })
+        let values = match self.request_headers.get(header) {
+            Some(values) => Some(values),
+            None => self.request_headers.iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(header))
+                .map(|(_, values)| values),
+        };
+
+        values.and_then(|v| v.first())
+    }
+
+    /// Returns a value captured by a `:name` or `*name` segment of the matched route.
+    pub fn get_path_param(&self, name: &str) -> Option<&String> {
+        self.path_params.get(name)
     }
 
-    pub fn add_response_header(&mut self, header: &'a str, value: &'a str) {
+    pub fn add_response_header(&mut self, header: &str, value: &str) {
         if self.response_headers_sent {
             panic!("Attempted to add header after begin_response called")
         }
 
-        let headers = self.response_headers.entry(String::from(header)).or_insert(vec![]);
+        let headers = self.response_headers.entry(String::from(header)).or_default();
         headers.push(String::from(value));
     }
 
     pub fn send_preamble(&mut self, code: StatusCode, body_size: usize) -> Result<(), Error> {
+        self.write_preamble(code, ResponseBody::Sized)?;
+
+        self.writer.write_all(CONTENT_LENGTH.as_bytes())?;
+        self.writer.write_all(SPACE)?;
+        self.writer.write_all(body_size.to_string().as_bytes())?;
+        self.writer.write_all(RETURN_NEWLINE)?;
+        self.writer.write_all(RETURN_NEWLINE)?;
+        self.writer.flush()
+    }
+
+    /// Begins a response whose size isn't known up front. Each subsequent `write` is sent as one
+    /// chunk, and `finish` writes the terminating chunk. HTTP/1.0 clients can't decode chunks, so
+    /// they get the raw body and the connection is closed to mark its end.
+    pub fn send_chunked_preamble(&mut self, code: StatusCode) -> Result<(), Error> {
+        if self.version == HTTP_VERSION_1_0 {
+            self.keep_alive = false;
+            self.write_preamble(code, ResponseBody::UntilClose)?;
+        } else {
+            self.write_preamble(code, ResponseBody::Chunked)?;
+
+            self.writer.write_all(TRANSFER_ENCODING.as_bytes())?;
+            self.writer.write_all(b" chunked")?;
+            self.writer.write_all(RETURN_NEWLINE)?;
+        }
+
+        self.writer.write_all(RETURN_NEWLINE)?;
+        self.writer.flush()
+    }
+
+    /// Completes the response, writing the terminating chunk for chunked bodies, and flushes it to
+    /// the client. Called by the server once the handler returns, calling it again is a no-op.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        if self.response_headers_sent && !self.response_finished {
+            self.response_finished = true;
+
+            if self.response_body == ResponseBody::Chunked {
+                self.writer.write_all(b"0")?;
+                self.writer.write_all(RETURN_NEWLINE)?;
+                self.writer.write_all(RETURN_NEWLINE)?;
+            }
+        }
+
+        self.writer.flush()
+    }
+
+    fn write_preamble(&mut self, code: StatusCode, response_body: ResponseBody) -> Result<(), Error> {
         if self.response_headers_sent {
             panic!("begin_response called twice!")
         }
 
         self.response_headers_sent = true;
+        self.response_body = response_body;
 
         if self.response_headers.contains_key("Content-Length") {
             panic!("Attempted to add explicit Content-Length header!")
         }
 
-        let writes: Result<usize, Error> = {
-            self.writer.write(HTTP_VERSION.as_bytes())?;
-            self.writer.write(SPACE)?;
-            self.writer.write(code.as_str().as_bytes())?;
-            self.writer.write(SPACE)?;
-            self.writer.write(code.canonical_reason().unwrap_or("UNKNOWN").as_bytes())?;
-            self.writer.write(RETURN_NEWLINE)?;
-
-            for (header, values) in self.response_headers.iter() {
-                for value in values.iter() {
-                    self.writer.write(header.as_bytes())?;
-                    self.writer.write(COLON)?;
-                    self.writer.write(SPACE)?;
-                    self.writer.write(value.as_bytes())?;
-                    self.writer.write(NEWLINE)?;
+        if self.response_headers.contains_key("Transfer-Encoding") {
+            panic!("Attempted to add explicit Transfer-Encoding header, use send_chunked_preamble instead!")
+        }
+
+        if self.response_headers.contains_key("Connection") {
+            panic!("Attempted to add explicit Connection header, use set_keep_alive instead!")
+        }
+
+        self.writer.write_all(HTTP_VERSION.as_bytes())?;
+        self.writer.write_all(SPACE)?;
+        self.writer.write_all(code.as_str().as_bytes())?;
+        self.writer.write_all(SPACE)?;
+        self.writer.write_all(code.canonical_reason().unwrap_or("UNKNOWN").as_bytes())?;
+        self.writer.write_all(RETURN_NEWLINE)?;
+
+        for (header, values) in self.response_headers.iter() {
+            for value in values.iter() {
+                self.writer.write_all(header.as_bytes())?;
+                self.writer.write_all(COLON)?;
+                self.writer.write_all(SPACE)?;
+                self.writer.write_all(value.as_bytes())?;
+                self.writer.write_all(NEWLINE)?;
+            }
+        }
+
+        if !self.keep_alive {
+            self.writer.write_all(CONNECTION.as_bytes())?;
+            self.writer.write_all(b" close")?;
+            self.writer.write_all(RETURN_NEWLINE)?;
+        } else if self.version == HTTP_VERSION_1_0 {
+            self.writer.write_all(CONNECTION.as_bytes())?;
+            self.writer.write_all(b" keep-alive")?;
+            self.writer.write_all(RETURN_NEWLINE)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn is_timeout_error(err: &Error) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+fn is_idle_error(err: &Error) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::ConnectionReset | ErrorKind::UnexpectedEof)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::thread;
+
+    /// A loopback connection with `raw` already sent, as the server's reader and writer and the
+    /// client's end.
+    fn connect(raw: &[u8]) -> (BufReader<Stream>, BufWriter<Stream>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(raw).unwrap();
+
+        let stream = Stream::Plain(listener.accept().unwrap().0);
+        (BufReader::new(stream.try_clone().unwrap()), BufWriter::new(stream), client)
+    }
+
+    /// Parses a request from `raw`. The client end is returned too, so the connection stays open
+    /// for as long as the test needs it.
+    fn parse(raw: &[u8]) -> (Result<Request, ParseError>, TcpStream) {
+        parse_with(raw, &ServerConfig::default())
+    }
+
+    fn parse_with(raw: &[u8], config: &ServerConfig) -> (Result<Request, ParseError>, TcpStream) {
+        let (reader, writer, client) = connect(raw);
+        (Request::parse_request(reader, writer, config), client)
+    }
+
+    /// Parses a request from `raw`, sent by a client that then closed its end.
+    fn parse_closed(raw: &[u8]) -> Result<Request, ParseError> {
+        let (reader, writer, client) = connect(raw);
+        client.shutdown(Shutdown::Write).unwrap();
+        Request::parse_request(reader, writer, &ServerConfig::default())
+    }
+
+    fn parse_err(raw: &[u8], config: &ServerConfig) -> ParseError {
+        match parse_with(raw, config).0 {
+            Ok(request) => panic!("Parsed {} {}", request.verb, request.path),
+            Err(err) => err,
+        }
+    }
+
+    /// Parses the request following `request` on its connection.
+    fn next(request: Request) -> Result<Request, ParseError> {
+        let (reader, writer) = request.into_parts();
+        Request::parse_request(reader, writer, &ServerConfig::default())
+    }
+
+    #[test]
+    fn decodes_chunks_with_extensions_and_trailers() {
+        let (request, _client) = parse(b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;name=value\r\nhello\r\n6 ; quoted=\"a;b\"\r\n world\r\n0\r\nX-Checksum: abc\r\nX-Other: 1\r\n\r\n\
+            GET /next HTTP/1.1\r\n\r\n");
+        let mut request = request.unwrap();
+
+        assert!(request.has_body());
+        assert_eq!(request.read_body().unwrap(), b"hello world");
+        // Trailers are consumed with the body rather than read as the next request's head
+        assert_eq!(next(request).unwrap().path, "/next");
+    }
+
+    #[test]
+    fn rejects_malformed_chunk_sizes() {
+        for body in [&b"zz\r\nhello\r\n0\r\n\r\n"[..], b"-5\r\nhello\r\n0\r\n\r\n", b"\r\n", b"5\r\nhelloXX0\r\n\r\n"].iter() {
+            let mut raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+            raw.extend_from_slice(body);
+
+            let (request, _client) = parse(&raw);
+            let err = request.unwrap().read_body().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "Accepted {:?}", String::from_utf8_lossy(body));
+        }
+
+        // A size line that never ends is cut off rather than buffered
+        let mut raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        raw.extend(std::iter::repeat_n(b'0', MAX_CHUNK_LINE as usize + 1));
+        let (request, _client) = parse(&raw);
+        assert_eq!(request.unwrap().read_body().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_chunks_past_the_body_limit() {
+        let (request, _client) = parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            4\r\nfour\r\n10\r\nsixteen bytes...\r\n0\r\n\r\n");
+        let mut request = request.unwrap();
+
+        assert!(request.limit_body_size(8));
+        let mut body = [0; 4];
+        request.read_exact(&mut body).unwrap();
+
+        // Rejected from its size line, before any of it is read
+        assert_eq!(request.read(&mut body).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(request.body_too_large());
+
+        // A size too large for a u64 is malformed rather than wrapping around
+        let (request, _client) = parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            1ffffffffffffffff\r\n");
+        let mut request = request.unwrap();
+        assert!(request.limit_body_size(8));
+        assert_eq!(request.read_body().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_declared_lengths_past_the_body_limit() {
+        let (request, _client) = parse(b"POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n123456789");
+        let mut request = request.unwrap();
+
+        assert!(!request.limit_body_size(8));
+        assert!(request.body_too_large());
+    }
+
+    #[test]
+    fn discards_unread_bodies_before_the_next_request() {
+        let (request, _client) = parse(b"POST /sized HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello world\
+            POST /chunked HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n\
+            GET /last HTTP/1.1\r\n\r\n");
+        let mut request = request.unwrap();
+
+        let mut start = [0; 2];
+        request.read_exact(&mut start).unwrap();
+        request.discard_body().unwrap();
+
+        let mut request = next(request).unwrap();
+        assert_eq!(request.path, "/chunked");
+        request.read_exact(&mut start).unwrap();
+        assert_eq!(&start, b"he");
+        request.discard_body().unwrap();
+
+        let mut request = next(request).unwrap();
+        assert_eq!(request.path, "/last");
+        assert!(!request.has_body());
+        request.discard_body().unwrap();
+    }
+
+    #[test]
+    fn rejects_ambiguous_body_framing() {
+        let framings = [
+            "Content-Length: 5\r\nTransfer-Encoding: chunked\r\n",
+            "Transfer-Encoding: gzip\r\n",
+            "Content-Length: 5\r\nContent-Length: 6\r\n",
+            "Content-Length: -1\r\n",
+        ];
+
+        for framing in framings.iter() {
+            let (request, _client) = parse(format!("POST / HTTP/1.1\r\n{}\r\n", framing).as_bytes());
+            match request {
+                Err(ParseError::BadRequest(_)) => {}
+                Err(err) => panic!("Unexpected error {:?} for {:?}", err, framing),
+                Ok(_) => panic!("Accepted {:?}", framing),
+            }
+        }
+    }
+
+    #[test]
+    fn times_out_slow_heads() {
+        let config = ServerConfig { header_timeout: Duration::from_millis(100), ..ServerConfig::default() };
+
+        let start = Instant::now();
+        assert_eq!(parse_err(b"GET / HTTP/1.1\r\nHost: loc", &config), ParseError::Timeout);
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        assert_eq!(parse_err(b"GET / HT", &config), ParseError::Timeout);
+    }
+
+    #[test]
+    fn times_out_trickled_heads() {
+        let config = ServerConfig { header_timeout: Duration::from_millis(200), ..ServerConfig::default() };
+        let (reader, writer, mut client) = connect(b"GET / HTTP/1.1\r\n");
+
+        // Each byte arrives well within a read timeout, but the head as a whole takes too long
+        let trickle = thread::spawn(move || {
+            for byte in b"X-Slow: 0123456789\r\n".iter() {
+                thread::sleep(Duration::from_millis(20));
+                if client.write_all(&[*byte]).is_err() {
+                    break;
                 }
             }
+            client
+        });
 
-            self.writer.write(CONTENT_LENGTH.as_bytes())?;
-            self.writer.write(SPACE)?;
-            self.writer.write(body_size.to_string().as_bytes())?;
-            self.writer.write(RETURN_NEWLINE)?;
-            self.writer.write(RETURN_NEWLINE)?;
-            self.writer.flush()?;
+        let start = Instant::now();
+        assert_eq!(Request::parse_request(reader, writer, &config).err(), Some(ParseError::Timeout));
+        assert!(start.elapsed() < Duration::from_millis(400), "Took {:?}", start.elapsed());
+        trickle.join().unwrap();
+    }
 
-            Ok(0)
-        };
+    #[test]
+    fn rejects_long_request_lines() {
+        let config = ServerConfig { max_request_line: 32, ..ServerConfig::default() };
 
-        match writes {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e),
+        assert!(parse_with(b"GET /short HTTP/1.1\r\n\r\n", &config).0.is_ok());
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(32));
+        assert_eq!(parse_err(long.as_bytes(), &config), ParseError::RequestLineTooLong);
+        // Caught without waiting for the line to end
+        let unterminated = format!("GET /{}", "a".repeat(64));
+        assert_eq!(parse_err(unterminated.as_bytes(), &config), ParseError::RequestLineTooLong);
+    }
+
+    #[test]
+    fn rejects_large_heads() {
+        let config = ServerConfig { max_header_count: 3, max_header_bytes: 64, ..ServerConfig::default() };
+
+        assert!(parse_with(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n", &config).0.is_ok());
+        assert_eq!(parse_err(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\n\r\n", &config),
+                   ParseError::HeadersTooLarge);
+
+        let large = format!("GET / HTTP/1.1\r\nA: {}\r\n\r\n", "x".repeat(64));
+        assert_eq!(parse_err(large.as_bytes(), &config), ParseError::HeadersTooLarge);
+        let spread = format!("GET / HTTP/1.1\r\nA: {0}\r\nB: {0}\r\n\r\n", "x".repeat(30));
+        assert_eq!(parse_err(spread.as_bytes(), &config), ParseError::HeadersTooLarge);
+    }
+
+    #[test]
+    fn rejects_truncated_heads() {
+        assert_eq!(parse_closed(b"").err(), Some(ParseError::ConnectionClosed));
+        assert_eq!(parse_closed(b"\r\n").err(), Some(ParseError::ConnectionClosed));
+
+        for raw in [&b"GET / HT"[..], b"GET / HTTP/1.1\r\n", b"GET / HTTP/1.1\r\nHost: x\r\n", b"GET / HTTP/1.1\r\nHost: x"].iter() {
+            match parse_closed(raw) {
+                Err(ParseError::BadRequest(_)) => {}
+                Err(err) => panic!("Unexpected error {:?} for {:?}", err, String::from_utf8_lossy(raw)),
+                Ok(_) => panic!("Dispatched truncated head {:?}", String::from_utf8_lossy(raw)),
+            }
         }
     }
+
+    #[test]
+    fn times_out_trickled_bodies() {
+        let config = ServerConfig {
+            read_timeout: Duration::from_secs(5),
+            body_timeout: Duration::from_millis(200),
+            ..ServerConfig::default()
+        };
+        let (reader, writer, mut client) = connect(b"POST / HTTP/1.1\r\nContent-Length: 100\r\n\r\n");
+        let mut request = Request::parse_request(reader, writer, &config).unwrap();
+
+        let trickle = thread::spawn(move || {
+            for _ in 0..100 {
+                thread::sleep(Duration::from_millis(20));
+                if client.write_all(b"x").is_err() {
+                    break;
+                }
+            }
+            client
+        });
+
+        let start = Instant::now();
+        // Either the deadline check or a socket read bounded by it gives up first
+        assert!(is_timeout_error(&request.read_body().unwrap_err()));
+        assert!(start.elapsed() < Duration::from_millis(400), "Took {:?}", start.elapsed());
+
+        drop(request);
+        trickle.join().unwrap();
+    }
 }
@@ -1,6 +1,7 @@
 extern crate core;
 
 use request::Request;
+use middleware::{self, Middleware};
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::io::Error;
@@ -10,47 +11,477 @@ pub trait Handler: Send + Sync {
     fn handle(&self, request: &mut Request) -> Result<(), Error>;
 }
 
+/// Values captured from `:name` and `*name` segments of the matched route.
+pub type PathParams = HashMap<String, String>;
+
+type VerbMap = HashMap<String, Arc<dyn Handler>>;
+
+enum Segment<'a> {
+    Static(&'a str),
+    Param(&'a str),
+    Wildcard(&'a str),
+}
+
+/// A node in the route tree. Static edges are compressed, so a child's label may span several
+/// path segments joined by '/'. No two children of a node share a first segment, and children are
+/// kept sorted by it so lookups can binary search.
+struct Node {
+    children: Vec<Edge>,
+    param: Option<(String, Box<Node>)>,
+    wildcard: Option<(String, VerbMap)>,
+    handlers: Option<VerbMap>,
+}
+
+struct Edge {
+    label: String,
+    segments: usize,
+    node: Node,
+}
+
+impl Edge {
+    fn first_segment(&self) -> &str {
+        first_segment(&self.label)
+    }
+}
+
+/// The state of a lookup while it walks the tree.
+struct Search<'n, 'p> {
+    verb: &'p str,
+    /// The deepest route found so far with a handler for the verb.
+    best: Option<Candidate<'n, 'p>>,
+    /// How many segments the deepest route matching the path consumed, whatever its verbs.
+    deepest: Option<usize>,
+}
+
+struct Candidate<'n, 'p> {
+    consumed: usize,
+    handler: &'n Arc<dyn Handler>,
+    params: Vec<(&'n str, &'p str)>,
+}
+
+impl<'n, 'p> Search<'n, 'p> {
+    /// Records a route that matched the first `consumed` segments, unless it has no handler for
+    /// the verb or a deeper one was found already.
+    fn offer<F>(&mut self, consumed: usize, handlers: &'n VerbMap, params: F)
+        where F: FnOnce() -> Vec<(&'n str, &'p str)>
+    {
+        self.deepest = self.deepest.max(Some(consumed));
+
+        if let Some(handler) = handlers.get(self.verb) {
+            if self.best.as_ref().is_none_or(|best| consumed > best.consumed) {
+                self.best = Some(Candidate { consumed, handler, params: params() });
+            }
+        }
+    }
+}
+
+impl Node {
+    fn new() -> Node {
+        Node {
+            children: Vec::new(),
+            param: None,
+            wildcard: None,
+            handlers: None,
+        }
+    }
+
+    fn insert(&mut self, segments: &[Segment], verb: String, handler: Arc<dyn Handler>) {
+        let static_run = segments.iter()
+            .take_while(|segment| matches!(segment, Segment::Static(_)))
+            .count();
+
+        if static_run > 0 {
+            let labels: Vec<&str> = segments[..static_run].iter().map(|segment| match segment {
+                Segment::Static(label) => *label,
+                _ => unreachable!(),
+            }).collect();
+
+            let child = self.static_child(&labels);
+            return child.insert(&segments[static_run..], verb, handler);
+        }
+
+        match segments.first() {
+            None => {
+                self.handlers.get_or_insert_with(HashMap::new).insert(verb, handler);
+            }
+            Some(Segment::Param(name)) => {
+                let (existing, child) = self.param.get_or_insert_with(|| (String::from(*name), Box::new(Node::new())));
+                assert!(existing == name, "Conflicting path parameter names :{} and :{}", existing, name);
+                child.insert(&segments[1..], verb, handler);
+            }
+            Some(Segment::Wildcard(name)) => {
+                let (existing, handlers) = self.wildcard.get_or_insert_with(|| (String::from(*name), HashMap::new()));
+                assert!(existing == name, "Conflicting wildcard names *{} and *{}", existing, name);
+                handlers.insert(verb, handler);
+            }
+            Some(Segment::Static(_)) => unreachable!(),
+        }
+    }
+
+    /// Returns the node reached by following `labels` from here, splitting a compressed edge if
+    /// the labels diverge from it partway through.
+    fn static_child(&mut self, labels: &[&str]) -> &mut Node {
+        let (idx, common) = match self.child_index(labels[0]) {
+            Ok(idx) => {
+                let common = self.children[idx].label.split('/').zip(labels.iter())
+                    .take_while(|(existing, new)| existing == *new)
+                    .count();
+                (idx, common)
+            }
+            Err(idx) => {
+                self.children.insert(idx, Edge {
+                    label: labels.join("/"),
+                    segments: labels.len(),
+                    node: Node::new(),
+                });
+                return &mut self.children[idx].node;
+            }
+        };
+
+        let edge = &mut self.children[idx];
+        if common < edge.segments {
+            let tail_label = edge.label.split('/').skip(common).collect::<Vec<&str>>().join("/");
+            let tail = Edge {
+                label: tail_label,
+                segments: edge.segments - common,
+                node: std::mem::replace(&mut edge.node, Node::new()),
+            };
+
+            edge.label = labels[..common].join("/");
+            edge.segments = common;
+            edge.node.children.push(tail);
+        }
+
+        if common == labels.len() {
+            &mut edge.node
+        } else {
+            edge.node.static_child(&labels[common..])
+        }
+    }
+
+    fn child_index(&self, segment: &str) -> Result<usize, usize> {
+        self.children.binary_search_by(|edge| edge.first_segment().cmp(segment))
+    }
+
+    /// Walks the tree depth first, static edges before parameters before wildcards, recording
+    /// the route with a handler for the verb that consumes the most segments. `rest` is `None`
+    /// once the path is exhausted.
+    fn find<'n, 'p>(&'n self, rest: Option<&'p str>, consumed: usize, params: &mut Vec<(&'n str, &'p str)>,
+                    search: &mut Search<'n, 'p>) {
+        if let Some(handlers) = self.handlers.as_ref() {
+            search.offer(consumed, handlers, || params.clone());
+        }
+
+        if let Some(rest) = rest {
+            if let Ok(idx) = self.child_index(first_segment(rest)) {
+                let edge = &self.children[idx];
+                if let Some(remainder) = strip_segments(rest, &edge.label) {
+                    edge.node.find(remainder, consumed + edge.segments, params, search);
+                }
+            }
+
+            if let Some((name, child)) = self.param.as_ref() {
+                let (segment, remainder) = match rest.find('/') {
+                    Some(idx) => (&rest[..idx], Some(&rest[idx + 1..])),
+                    None => (rest, None),
+                };
+
+                if !segment.is_empty() {
+                    params.push((name, segment));
+                    child.find(remainder, consumed + 1, params, search);
+                    params.pop();
+                }
+            }
+        }
+
+        if let Some((name, handlers)) = self.wildcard.as_ref() {
+            let (consumed, rest) = match rest {
+                None => (consumed, ""),
+                Some(rest) => (consumed + rest.split('/').count(), rest),
+            };
+
+            search.offer(consumed, handlers, || {
+                let mut params = params.clone();
+                params.push((name, rest));
+                params
+            });
+        }
+    }
+}
+
+fn first_segment(path: &str) -> &str {
+    match path.find('/') {
+        Some(idx) => &path[..idx],
+        None => path,
+    }
+}
+
+/// Strips whole segments matching `label` from the front of `rest`, returning what follows.
+fn strip_segments<'p>(rest: &'p str, label: &str) -> Option<Option<&'p str>> {
+    let remainder = rest.strip_prefix(label)?;
+
+    if remainder.is_empty() {
+        Some(None)
+    } else {
+        remainder.strip_prefix('/').map(Some)
+    }
+}
+
+/// A handler wrapped in middleware that only applies to its route.
+struct RouteChain<H: Handler> {
+    middleware: Vec<Arc<dyn Middleware>>,
+    handler: H,
+}
+
+impl<H: Handler> Handler for RouteChain<H> {
+    fn handle(&self, request: &mut Request) -> Result<(), Error> {
+        middleware::run_chain(&self.middleware, request, |request| self.handler.handle(request))
+    }
+}
+
 pub struct Router {
-    routes: HashMap<String, HashMap<String, Arc<Handler>>>
+    root: Node,
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
 }
 
 impl Router {
     pub fn new() -> Router {
         Router {
-            routes: HashMap::new()
+            root: Node::new(),
+            middleware: Vec::new(),
         }
     }
 
-    pub fn  add_route<H: Handler + 'static>(&mut self, path: &str, verb: &str, handler: H) {
-        let path = String::from(path);
-        let verb = String::from(verb);
+    /// Registers a handler for a path and verb. Path segments of the form `:name` match any single
+    /// segment, and a final `*name` segment matches the remainder of the path. Captured values are
+    /// available from `Request::get_path_param`.
+    pub fn add_route<H: Handler + 'static>(&mut self, path: &str, verb: &str, handler: H) {
+        assert!(path.starts_with('/'), "Routes must start with a '/', but got: {}", path);
+
+        let segments = Router::parse_segments(path);
+        self.root.insert(&segments, String::from(verb), Arc::new(handler));
+    }
+
+    /// Registers a handler wrapped in middleware that runs only for this route, inside any
+    /// middleware registered on the router or server.
+    pub fn add_route_with_middleware<H: Handler + 'static>(&mut self, path: &str, verb: &str, handler: H,
+                                                           middleware: Vec<Arc<dyn Middleware>>) {
+        self.add_route(path, verb, RouteChain { middleware, handler });
+    }
+
+    /// Registers middleware that runs for every request routed by this router, including those
+    /// answered with a 404 or 405.
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middleware.push(Arc::new(middleware));
+    }
+
+    /// Routes the request and runs the matched handler inside the router's middleware, answering
+    /// with a bare status code if no handler matched.
+    pub(crate) fn dispatch(&self, request: &mut Request) -> Result<(), Error> {
+        let route = self.get_handler(&request.path, &request.verb);
+
+        let handler = match route {
+            Ok((handler, path_params)) => {
+                request.path_params = path_params;
+                Ok(handler)
+            }
+            Err(status_code) => Err(status_code),
+        };
+
+        middleware::run_chain(&self.middleware, request, |request| match handler {
+            Ok(handler) => handler.handle(request),
+            Err(status_code) => request.send_preamble(status_code, 0),
+        })
+    }
+
+    fn parse_segments(path: &str) -> Vec<Segment<'_>> {
+        let parts: Vec<&str> = path[1..].split('/').collect();
+        let mut segments = Vec::with_capacity(parts.len());
+
+        for (i, part) in parts.iter().enumerate() {
+            let segment = if let Some(name) = part.strip_prefix(':') {
+                assert!(!name.is_empty(), "Path parameter without a name in route: {}", path);
+                Segment::Param(name)
+            } else if let Some(name) = part.strip_prefix('*') {
+                assert!(!name.is_empty(), "Wildcard without a name in route: {}", path);
+                assert!(i + 1 == parts.len(), "Wildcard must be the last segment in route: {}", path);
+                Segment::Wildcard(name)
+            } else {
+                Segment::Static(part)
+            };
 
-        let path_map = self.routes.entry(path).or_insert(HashMap::new());
-        path_map.insert(String::from(verb), Arc::new(handler));
+            segments.push(segment);
+        }
+
+        segments
     }
 
-    pub fn get_handler(&self, path: &String, verb: &String) -> Result<Arc<Handler>, StatusCode> {
+    /// Finds the handler for the longest registered prefix of `path`, along with any path
+    /// parameters it captured. Static routes take precedence over patterns at the same depth,
+    /// unless they lack a handler for `verb`. Fails with a 405 if no route at that depth has one,
+    /// even if a shorter prefix does.
+    pub fn get_handler(&self, path: &str, verb: &str) -> Result<(Arc<dyn Handler>, PathParams), StatusCode> {
         assert!(path.starts_with('/'), "Routes must be canonical, but got: {}", path);
         assert!(!verb.is_empty());
 
-        let mut path= &path[..];
-        while !path.is_empty() {
-            if let Some(verb_map) = self.routes.get(path) {
-                if let Some(handler) = verb_map.get(verb) {
-                    return Ok(handler.clone());
-                } else {
-                    return Err(StatusCode::METHOD_NOT_ALLOWED)
-                }
-            }
+        let mut search = Search { verb, best: None, deepest: None };
+        self.root.find(Some(&path[1..]), 0, &mut Vec::new(), &mut search);
 
-            let splits: Vec<&str> = path.rsplitn(2, '/').collect();
+        match search.best {
+            Some(ref best) if Some(best.consumed) != search.deepest => Err(StatusCode::METHOD_NOT_ALLOWED),
+            None if search.deepest.is_some() => Err(StatusCode::METHOD_NOT_ALLOWED),
+            None => Err(StatusCode::NOT_FOUND),
+            Some(best) => {
+                let params = best.params.iter()
+                    .map(|(name, value)| (String::from(*name), String::from(*value)))
+                    .collect();
 
-            match splits.last() {
-                None => break,
-                Some(new_path) => path = new_path,
+                Ok((best.handler.clone(), params))
             }
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoopHandler;
+
+    impl Handler for NoopHandler {
+        fn handle(&self, _request: &mut Request) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    /// Registers a route as `add_route` does, returning its handler so lookups can be checked
+    /// against it.
+    fn route(router: &mut Router, path: &str, verb: &str) -> Arc<dyn Handler> {
+        let handler: Arc<dyn Handler> = Arc::new(NoopHandler);
+        router.root.insert(&Router::parse_segments(path), String::from(verb), handler.clone());
+        handler
+    }
+
+    fn assert_routes(router: &Router, path: &str, verb: &str, expected: &Arc<dyn Handler>, params: &[(&str, &str)]) {
+        let (handler, found) = match router.get_handler(path, verb) {
+            Ok(found) => found,
+            Err(status) => panic!("{} {} wasn't routed: {}", verb, path, status),
+        };
+
+        assert!(Arc::ptr_eq(&handler, expected), "{} {} was routed to the wrong handler", verb, path);
+
+        let params: PathParams = params.iter().map(|(name, value)| (String::from(*name), String::from(*value))).collect();
+        assert_eq!(found, params, "{} {} captured the wrong parameters", verb, path);
+    }
+
+    fn assert_fails(router: &Router, path: &str, verb: &str, status: StatusCode) {
+        assert_eq!(router.get_handler(path, verb).err(), Some(status), "{} {}", verb, path);
+    }
+
+    #[test]
+    fn matches_static_routes_by_longest_prefix() {
+        let mut router = Router::new();
+        let tags = route(&mut router, "/api/tags", "GET");
+        let admin = route(&mut router, "/admin/gc", "GET");
+        let snapshot = route(&mut router, "/admin/snapshot", "POST");
+
+        assert_routes(&router, "/api/tags", "GET", &tags, &[]);
+        assert_routes(&router, "/api/tags/extra/segments", "GET", &tags, &[]);
+        assert_routes(&router, "/admin/gc", "GET", &admin, &[]);
+        assert_routes(&router, "/admin/snapshot", "POST", &snapshot, &[]);
+
+        // Only whole segments match
+        assert_fails(&router, "/api/tagsmore", "GET", StatusCode::NOT_FOUND);
+        assert_fails(&router, "/admin", "GET", StatusCode::NOT_FOUND);
+        assert_fails(&router, "/", "GET", StatusCode::NOT_FOUND);
+        assert_fails(&router, "/api/tags", "DELETE", StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn captures_params_and_wildcards() {
+        let mut router = Router::new();
+        let user = route(&mut router, "/api/tags/:user", "GET");
+        let history = route(&mut router, "/api/tags/:user/history", "GET");
+        let files = route(&mut router, "/files/*path", "GET");
 
-        Err(StatusCode::NOT_FOUND)
+        assert_routes(&router, "/api/tags/alice", "GET", &user, &[("user", "alice")]);
+        assert_routes(&router, "/api/tags/alice/history", "GET", &history, &[("user", "alice")]);
+        assert_routes(&router, "/files/a/b/c.txt", "GET", &files, &[("path", "a/b/c.txt")]);
+        assert_routes(&router, "/files", "GET", &files, &[("path", "")]);
+
+        // A parameter never matches an empty segment
+        assert_fails(&router, "/api/tags/", "GET", StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn prefers_static_then_params_then_wildcards() {
+        let mut router = Router::new();
+        let wildcard = route(&mut router, "/api/*rest", "GET");
+        let param = route(&mut router, "/api/:name", "GET");
+        let literal = route(&mut router, "/api/status", "GET");
+        let deeper = route(&mut router, "/api/:name/detail", "GET");
+
+        assert_routes(&router, "/api/status", "GET", &literal, &[]);
+        assert_routes(&router, "/api/other", "GET", &param, &[("name", "other")]);
+        assert_routes(&router, "/api/status/detail", "GET", &deeper, &[("name", "status")]);
+        assert_routes(&router, "/api/other/more/segments", "GET", &wildcard, &[("rest", "other/more/segments")]);
+    }
+
+    #[test]
+    fn falls_back_from_static_routes_without_the_verb_at_the_same_depth() {
+        let mut router = Router::new();
+        let batch = route(&mut router, "/api/tags/batch", "POST");
+        let user = route(&mut router, "/api/tags/:user", "GET");
+        let by_tag = route(&mut router, "/api/tags/by-tag/:tag", "GET");
+        let files = route(&mut router, "/files/*path", "GET");
+        let upload = route(&mut router, "/files/upload", "POST");
+
+        assert_routes(&router, "/api/tags/batch", "POST", &batch, &[]);
+        assert_routes(&router, "/api/tags/batch", "GET", &user, &[("user", "batch")]);
+        assert_routes(&router, "/api/tags/by-tag", "GET", &user, &[("user", "by-tag")]);
+        assert_routes(&router, "/api/tags/by-tag/vip", "GET", &by_tag, &[("tag", "vip")]);
+        assert_routes(&router, "/files/upload", "POST", &upload, &[]);
+        assert_routes(&router, "/files/upload", "GET", &files, &[("path", "upload")]);
+
+        // Still a 405 when nothing on the path has the verb
+        assert_fails(&router, "/api/tags/batch", "DELETE", StatusCode::METHOD_NOT_ALLOWED);
+        assert_fails(&router, "/api/tags/alice", "POST", StatusCode::METHOD_NOT_ALLOWED);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn answers_405_for_the_deepest_match_without_the_verb() {
+        let mut router = Router::new();
+        let tags = route(&mut router, "/api/tags", "GET");
+        route(&mut router, "/api/tags/batch", "POST");
+        let files = route(&mut router, "/files/*path", "GET");
+        route(&mut router, "/files/:name/meta", "POST");
+
+        // Shorter prefixes with the verb aren't fallen back to
+        assert_fails(&router, "/api/tags/batch", "GET", StatusCode::METHOD_NOT_ALLOWED);
+        assert_fails(&router, "/api/tags/batch/more", "GET", StatusCode::METHOD_NOT_ALLOWED);
+        assert_routes(&router, "/api/tags/other", "GET", &tags, &[]);
+
+        // A wildcard consumes the whole path, so it is as deep as anything it overlaps
+        assert_routes(&router, "/files/a/meta", "GET", &files, &[("path", "a/meta")]);
+        assert_fails(&router, "/files/a/meta", "DELETE", StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn splits_compressed_edges() {
+        let mut router = Router::new();
+        let long = route(&mut router, "/a/b/c/d", "GET");
+        let short = route(&mut router, "/a/b", "GET");
+        let branch = route(&mut router, "/a/b/x", "GET");
+
+        assert_routes(&router, "/a/b/c/d", "GET", &long, &[]);
+        assert_routes(&router, "/a/b/c", "GET", &short, &[]);
+        assert_routes(&router, "/a/b/x/y", "GET", &branch, &[]);
+        assert_fails(&router, "/a", "GET", StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,44 @@
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Stops a running `WebServer`. Cloneable and safe to trigger from any thread, e.g. a signal
+/// handler. Once triggered the server stops accepting connections, closes kept-alive ones after
+/// their current request, and `run` returns when queued work drains or the shutdown timeout passes.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    flag: Arc<AtomicBool>,
+    addr: SocketAddr,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(flag: Arc<AtomicBool>, addr: SocketAddr) -> ShutdownHandle {
+        ShutdownHandle {
+            flag,
+            addr,
+        }
+    }
+
+    pub fn shutdown(&self) {
+        if self.flag.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        // The accept loop only checks the flag between connections, so give it one to wake on
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(if addr.is_ipv4() {
+                [127, 0, 0, 1].into()
+            } else {
+                [0, 0, 0, 0, 0, 0, 0, 1].into()
+            });
+        }
+
+        let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
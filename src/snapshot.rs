@@ -0,0 +1,152 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write, Seek, SeekFrom, Error, ErrorKind, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use wal::{self, LogEntry};
+
+const SNAPSHOT_FILE: &str = "snapshot";
+const MAGIC: &[u8; 8] = b"TAGSNAP2";
+/// Offset of the garbage collection watermark in the header, after the magic and log generation.
+const COLLECTED_OFFSET: u64 = 16;
+/// Where the first entry starts, after the watermark and the entry count.
+pub const HEADER_LEN: u64 = 32;
+
+/// Streams a snapshot to a temporary file. Nothing replaces the file being written until
+/// `commit`, so a crash partway through leaves the previous one intact.
+pub struct SnapshotWriter {
+    dir: PathBuf,
+    file_name: String,
+    writer: BufWriter<File>,
+    collected_before: i64,
+    count: u64,
+    buf: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    /// Starts a snapshot of the state covering every log segment before `generation`.
+    pub fn create(dir: &Path, generation: u64) -> Result<SnapshotWriter, Error> {
+        SnapshotWriter::create_named(dir, SNAPSHOT_FILE, generation)
+    }
+
+    /// Starts a snapshot that will be committed as `file_name` in `dir`.
+    pub fn create_named(dir: &Path, file_name: &str, generation: u64) -> Result<SnapshotWriter, Error> {
+        let tmp_path = dir.join(format!("{}.tmp", file_name));
+        let file = OpenOptions::new().write(true).create(true).truncate(true).open(tmp_path)?;
+        let mut writer = BufWriter::new(file);
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&generation.to_le_bytes())?;
+        writer.write_all(&[0; 16])?;
+
+        Ok(SnapshotWriter {
+            dir: dir.to_path_buf(),
+            file_name: String::from(file_name),
+            writer,
+            collected_before: 0,
+            count: 0,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Records that tombstones at or before this timestamp may have been garbage collected.
+    pub fn set_collected_before(&mut self, collected_before: i64) {
+        self.collected_before = collected_before;
+    }
+
+    pub fn write(&mut self, entry: &LogEntry) -> Result<(), Error> {
+        self.buf.clear();
+        entry.encode(&mut self.buf);
+        self.writer.write_all(&self.buf)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Makes the snapshot durable and atomically swaps it in, returning how many entries it holds.
+    pub fn commit(self) -> Result<u64, Error> {
+        let mut file = self.writer.into_inner().map_err(|e| e.into_error())?;
+
+        file.seek(SeekFrom::Start(COLLECTED_OFFSET))?;
+        file.write_all(&self.collected_before.to_le_bytes())?;
+        file.write_all(&self.count.to_le_bytes())?;
+        file.sync_all()?;
+
+        let tmp_path = self.dir.join(format!("{}.tmp", self.file_name));
+        fs::rename(tmp_path, self.dir.join(&self.file_name))?;
+        wal::sync_dir(&self.dir)?;
+
+        Ok(self.count)
+    }
+}
+
+/// Reads a committed snapshot back entry by entry.
+pub struct SnapshotReader<R: Read> {
+    reader: R,
+    generation: u64,
+    collected_before: i64,
+    remaining: u64,
+}
+
+impl SnapshotReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<SnapshotReader<BufReader<File>>, Error> {
+        SnapshotReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> SnapshotReader<R> {
+    pub fn new(mut reader: R) -> Result<SnapshotReader<R>, Error> {
+        let mut header = [0; HEADER_LEN as usize];
+        reader.read_exact(&mut header)?;
+
+        if &header[..8] != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Snapshot file has an unknown format"));
+        }
+
+        let mut generation = [0; 8];
+        let mut collected_before = [0; 8];
+        let mut count = [0; 8];
+        generation.copy_from_slice(&header[8..16]);
+        collected_before.copy_from_slice(&header[16..24]);
+        count.copy_from_slice(&header[24..]);
+
+        Ok(SnapshotReader {
+            reader,
+            generation: u64::from_le_bytes(generation),
+            collected_before: i64::from_le_bytes(collected_before),
+            remaining: u64::from_le_bytes(count),
+        })
+    }
+
+    /// The first log generation the snapshot doesn't cover.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The garbage collection watermark when the snapshot was taken.
+    pub fn collected_before(&self) -> i64 {
+        self.collected_before
+    }
+
+    /// Returns the next entry with its size on disk, or `None` once all have been read.
+    pub fn next_entry(&mut self) -> Result<Option<(LogEntry, usize)>, Error> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+
+        match wal::read_record(&mut self.reader)? {
+            Some(entry) => {
+                self.remaining -= 1;
+                Ok(Some(entry))
+            }
+            None => Err(Error::new(ErrorKind::InvalidData, "Snapshot file is corrupt")),
+        }
+    }
+}
+
+/// Opens the latest snapshot in `dir`, or returns `None` if there isn't one yet.
+pub fn open_latest(dir: &Path) -> Result<Option<SnapshotReader<BufReader<File>>>, Error> {
+    match SnapshotReader::open(&dir.join(SNAPSHOT_FILE)) {
+        Ok(reader) => Ok(Some(reader)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,59 @@
+use http::StatusCode;
+use request::Request;
+use std::io::{Error, Write};
+
+/// A `text/event-stream` response, as read by a browser's `EventSource`. The head is sent when the
+/// stream starts and each event is flushed to the client as soon as it's sent.
+pub struct EventStream<'a> {
+    request: &'a mut Request,
+}
+
+impl<'a> EventStream<'a> {
+    pub fn start(request: &'a mut Request) -> Result<EventStream<'a>, Error> {
+        request.add_response_header("Content-Type", "text/event-stream");
+        request.add_response_header("Cache-Control", "no-cache");
+        request.send_chunked_preamble(StatusCode::OK)?;
+
+        Ok(EventStream { request })
+    }
+
+    /// Sends an event. A client that reconnects passes the last `id` it saw back in the
+    /// `Last-Event-ID` header. `data` may span several lines.
+    pub fn send(&mut self, id: Option<&str>, event: Option<&str>, data: &str) -> Result<(), Error> {
+        let mut message = String::new();
+
+        if let Some(id) = id {
+            assert!(!id.contains(['\r', '\n', '\0']), "Event ID contains a line break or NUL");
+            message.push_str("id: ");
+            message.push_str(id);
+            message.push('\n');
+        }
+
+        if let Some(event) = event {
+            assert!(!event.contains(['\r', '\n']), "Event type contains a line break");
+            message.push_str("event: ");
+            message.push_str(event);
+            message.push('\n');
+        }
+
+        for line in data.lines() {
+            message.push_str("data: ");
+            message.push_str(line);
+            message.push('\n');
+        }
+        message.push('\n');
+
+        self.write_message(&message)
+    }
+
+    /// Sends a comment, which clients ignore, such as to keep an idle connection open.
+    pub fn comment(&mut self, text: &str) -> Result<(), Error> {
+        assert!(!text.contains(['\r', '\n']), "Comment contains a line break");
+        self.write_message(&format!(": {}\n\n", text))
+    }
+
+    fn write_message(&mut self, message: &str) -> Result<(), Error> {
+        self.request.write_all(message.as_bytes())?;
+        self.request.flush()
+    }
+}
@@ -0,0 +1,180 @@
+use std::fs::File;
+use std::io::{Read, Write, Error, ErrorKind, BufReader};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rustls;
+use rustls_pemfile;
+use rustls::{ServerConnection, StreamOwned};
+
+type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// An accepted connection, either plaintext or TLS. Clones share the same underlying socket, and
+/// for TLS the same session, so a request's reader and writer can be handed out separately.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Arc<Mutex<TlsStream>>),
+}
+
+impl Stream {
+    pub fn new(tcp: TcpStream, tls_config: Option<&Arc<rustls::ServerConfig>>) -> Result<Stream, Error> {
+        match tls_config {
+            None => Ok(Stream::Plain(tcp)),
+            Some(tls_config) => {
+                let session = ServerConnection::new(tls_config.clone())
+                    .map_err(|e| Error::other(e.to_string()))?;
+
+                Ok(Stream::Tls(Arc::new(Mutex::new(StreamOwned::new(session, tcp)))))
+            }
+        }
+    }
+
+    pub fn try_clone(&self) -> Result<Stream, Error> {
+        match self {
+            Stream::Plain(tcp) => Ok(Stream::Plain(tcp.try_clone()?)),
+            Stream::Tls(tls) => Ok(Stream::Tls(tls.clone())),
+        }
+    }
+
+    /// Completes the TLS handshake, failing with `TimedOut` if it takes longer than `timeout` in
+    /// all, so a client trickling bytes can't hold a worker indefinitely. Leaves the socket's read
+    /// and write timeouts set to whatever time was left. A no-op for plaintext.
+    pub fn handshake(&self, timeout: Duration) -> Result<(), Error> {
+        if let Stream::Tls(tls) = self {
+            let mut tls = tls.lock().unwrap();
+            let tls = &mut *tls;
+            // complete_io reads until the handshake is done, so the deadline is checked on each read
+            let mut sock = Deadline { sock: &tls.sock, deadline: Instant::now() + timeout };
+            while tls.conn.is_handshaking() {
+                tls.conn.complete_io(&mut sock)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        match self {
+            Stream::Plain(tcp) => tcp.set_read_timeout(timeout),
+            Stream::Tls(tls) => tls.lock().unwrap().sock.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        match self {
+            Stream::Plain(tcp) => tcp.set_write_timeout(timeout),
+            Stream::Tls(tls) => tls.lock().unwrap().sock.set_write_timeout(timeout),
+        }
+    }
+
+    /// Ends the connection, sending a TLS close_notify first where applicable.
+    pub fn close(&self) {
+        match self {
+            Stream::Plain(tcp) => {
+                let _ = tcp.shutdown(Shutdown::Both);
+            }
+            Stream::Tls(tls) => {
+                let mut tls = tls.lock().unwrap();
+                let tls = &mut *tls;
+
+                // Write out the alert directly, since flushing the stream may block reading.
+                tls.conn.send_close_notify();
+                while tls.conn.wants_write() {
+                    if tls.conn.write_tls(&mut tls.sock).is_err() {
+                        break;
+                    }
+                }
+
+                let _ = tls.sock.shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
+/// A socket whose reads and writes fail with `TimedOut` once `deadline` has passed.
+struct Deadline<'a> {
+    sock: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl<'a> Deadline<'a> {
+    /// Bounds the next read or write by whatever is left until the deadline.
+    fn bound(&self) -> Result<(), Error> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Err(Error::new(ErrorKind::TimedOut, "Timed out completing TLS handshake"));
+        }
+
+        let remaining = self.deadline - now;
+        self.sock.set_read_timeout(Some(remaining))?;
+        self.sock.set_write_timeout(Some(remaining))
+    }
+}
+
+impl<'a> Read for Deadline<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.bound()?;
+        self.sock.read(buf)
+    }
+}
+
+impl<'a> Write for Deadline<'a> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.bound()?;
+        self.sock.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Plain(tcp) => tcp.read(buf),
+            Stream::Tls(tls) => tls.lock().unwrap().read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Plain(tcp) => tcp.write(buf),
+            Stream::Tls(tls) => tls.lock().unwrap().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            Stream::Plain(tcp) => tcp.flush(),
+            Stream::Tls(tls) => tls.lock().unwrap().flush(),
+        }
+    }
+}
+
+/// Builds a TLS server config from a PEM certificate chain, leaf first, and a PEM private key.
+pub fn load_tls_config<P: AsRef<Path>>(cert_chain: P, private_key: P) -> Result<Arc<rustls::ServerConfig>, Error> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_chain)?))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    if certs.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, "No certificates found in certificate chain file"));
+    }
+
+    let key = match rustls_pemfile::private_key(&mut BufReader::new(File::open(private_key)?))? {
+        Some(key) => key,
+        None => return Err(Error::new(ErrorKind::InvalidData, "No private key found in key file")),
+    };
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+
+    Ok(Arc::new(config))
+}
@@ -0,0 +1,335 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+/// How deeply `NOT`s and parentheses may nest, so a hostile expression can't exhaust the stack.
+const MAX_DEPTH: usize = 64;
+/// How many tags an expression may mention, which also bounds how deep a chain of `AND`s or
+/// `OR`s gets.
+const MAX_TAGS: usize = 1024;
+
+/// A boolean expression over tags, such as `(vip AND us) OR (beta AND NOT churned)`.
+///
+/// Keywords are case-insensitive and `NOT` binds tighter than `AND`, which binds tighter than
+/// `OR`. A tag containing spaces, parentheses or a keyword can be written in double quotes, with
+/// `\"` and `\\` as escapes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expr {
+    Tag(String),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+/// Why an expression couldn't be parsed, and the byte offset it was noticed at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub position: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.position)
+    }
+}
+
+impl Expr {
+    pub fn parse(input: &str) -> Result<Expr, ParseError> {
+        let mut parser = Parser {
+            tokens: Lexer::new(input).peekable(),
+            end: input.len(),
+            tags: 0,
+        };
+
+        let expr = parser.or(0)?;
+        match parser.tokens.next().transpose()? {
+            None => Ok(expr),
+            Some((position, Token::Close)) => Err(ParseError { position, message: "Unmatched )" }),
+            Some((position, _)) => Err(ParseError { position, message: "Expected AND or OR" }),
+        }
+    }
+
+    /// Every tag the expression mentions, without repeats.
+    pub fn tags(&self) -> BTreeSet<&str> {
+        let mut tags = BTreeSet::new();
+        self.collect_tags(&mut tags);
+        tags
+    }
+
+    fn collect_tags<'a>(&'a self, tags: &mut BTreeSet<&'a str>) {
+        match *self {
+            Expr::Tag(ref tag) => {
+                tags.insert(tag);
+            }
+            Expr::Not(ref inner) => inner.collect_tags(tags),
+            Expr::And(ref left, ref right) | Expr::Or(ref left, ref right) => {
+                left.collect_tags(tags);
+                right.collect_tags(tags);
+            }
+        }
+    }
+
+    /// The users matching the expression, given the users holding each tag. `NOT` is relative to
+    /// `everyone`, which is only asked for when the expression can't be answered without it.
+    pub fn evaluate<F, G>(&self, members: &F, everyone: G) -> BTreeSet<String>
+        where F: Fn(&str) -> BTreeSet<String>, G: FnOnce() -> BTreeSet<String> {
+        match self.matches(members) {
+            Matches::Only(users) => users,
+            Matches::AllBut(excluded) => everyone().difference(&excluded).cloned().collect(),
+        }
+    }
+
+    fn matches<F: Fn(&str) -> BTreeSet<String>>(&self, members: &F) -> Matches {
+        match *self {
+            Expr::Tag(ref tag) => Matches::Only(members(tag)),
+            Expr::Not(ref inner) => match inner.matches(members) {
+                Matches::Only(users) => Matches::AllBut(users),
+                Matches::AllBut(users) => Matches::Only(users),
+            },
+            Expr::And(ref left, ref right) => match (left.matches(members), right.matches(members)) {
+                (Matches::Only(a), Matches::Only(b)) => Matches::Only(&a & &b),
+                (Matches::Only(a), Matches::AllBut(b)) | (Matches::AllBut(b), Matches::Only(a)) => {
+                    Matches::Only(&a - &b)
+                }
+                (Matches::AllBut(a), Matches::AllBut(b)) => Matches::AllBut(&a | &b),
+            },
+            Expr::Or(ref left, ref right) => match (left.matches(members), right.matches(members)) {
+                (Matches::Only(a), Matches::Only(b)) => Matches::Only(&a | &b),
+                (Matches::Only(a), Matches::AllBut(b)) | (Matches::AllBut(b), Matches::Only(a)) => {
+                    Matches::AllBut(&b - &a)
+                }
+                (Matches::AllBut(a), Matches::AllBut(b)) => Matches::AllBut(&a & &b),
+            },
+        }
+    }
+}
+
+/// A set of users, kept as its complement when that's the finite side.
+enum Matches {
+    Only(BTreeSet<String>),
+    AllBut(BTreeSet<String>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    Tag(String),
+}
+
+struct Lexer<'a> {
+    input: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(input: &'a str) -> Lexer<'a> {
+        Lexer {
+            input,
+            chars: input.char_indices().peekable(),
+        }
+    }
+
+    fn quoted(&mut self, start: usize) -> Result<Token, ParseError> {
+        let mut tag = String::new();
+
+        loop {
+            match self.chars.next() {
+                Some((_, '"')) => break,
+                Some((_, '\\')) => match self.chars.next() {
+                    Some((_, c)) if c == '"' || c == '\\' => tag.push(c),
+                    Some((position, _)) => return Err(ParseError { position, message: "Unknown escape" }),
+                    None => return Err(ParseError { position: start, message: "Unterminated quote" }),
+                },
+                Some((_, c)) => tag.push(c),
+                None => return Err(ParseError { position: start, message: "Unterminated quote" }),
+            }
+        }
+
+        if tag.is_empty() {
+            return Err(ParseError { position: start, message: "Empty tag" });
+        }
+
+        Ok(Token::Tag(tag))
+    }
+
+    fn bare(&mut self, start: usize) -> Token {
+        let mut end = self.input.len();
+        while let Some(&(position, c)) = self.chars.peek() {
+            if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+                end = position;
+                break;
+            }
+            self.chars.next();
+        }
+
+        let word = &self.input[start..end];
+        if word.eq_ignore_ascii_case("and") {
+            Token::And
+        } else if word.eq_ignore_ascii_case("or") {
+            Token::Or
+        } else if word.eq_ignore_ascii_case("not") {
+            Token::Not
+        } else {
+            Token::Tag(String::from(word))
+        }
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<(usize, Token), ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.chars.peek().is_some_and(|&(_, c)| c.is_whitespace()) {
+            self.chars.next();
+        }
+
+        let (start, c) = self.chars.next()?;
+        let token = match c {
+            '(' => Ok(Token::Open),
+            ')' => Ok(Token::Close),
+            '"' => self.quoted(start),
+            _ => Ok(self.bare(start)),
+        };
+
+        Some(token.map(|token| (start, token)))
+    }
+}
+
+/// A recursive descent parser, one method per precedence level.
+struct Parser<'a> {
+    tokens: Peekable<Lexer<'a>>,
+    end: usize,
+    tags: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn or(&mut self, depth: usize) -> Result<Expr, ParseError> {
+        let mut expr = self.and(depth)?;
+        while self.eat(Token::Or)? {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and(depth)?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self, depth: usize) -> Result<Expr, ParseError> {
+        let mut expr = self.unary(depth)?;
+        while self.eat(Token::And)? {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary(depth)?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self, depth: usize) -> Result<Expr, ParseError> {
+        let (position, token) = match self.tokens.next().transpose()? {
+            Some(next) => next,
+            None => return Err(ParseError { position: self.end, message: "Expected a tag" }),
+        };
+
+        if depth >= MAX_DEPTH && (token == Token::Not || token == Token::Open) {
+            return Err(ParseError { position, message: "Expression is nested too deeply" });
+        }
+
+        match token {
+            Token::Tag(_) if self.tags == MAX_TAGS => Err(ParseError { position, message: "Expression has too many tags" }),
+            Token::Tag(tag) => {
+                self.tags += 1;
+                Ok(Expr::Tag(tag))
+            }
+            Token::Not => Ok(Expr::Not(Box::new(self.unary(depth + 1)?))),
+            Token::Open => {
+                let expr = self.or(depth + 1)?;
+                if !self.eat(Token::Close)? {
+                    return Err(ParseError { position, message: "Unmatched (" });
+                }
+                Ok(expr)
+            }
+            _ => Err(ParseError { position, message: "Expected a tag" }),
+        }
+    }
+
+    /// Consumes the next token if it's `expected`.
+    fn eat(&mut self, expected: Token) -> Result<bool, ParseError> {
+        match self.tokens.peek() {
+            Some(Ok((_, token))) if *token == expected => {
+                self.tokens.next();
+                Ok(true)
+            }
+            Some(Err(e)) => Err(e.clone()),
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(tag: &str) -> Box<Expr> {
+        Box::new(Expr::Tag(String::from(tag)))
+    }
+
+    fn error(input: &str) -> ParseError {
+        Expr::parse(input).unwrap_err()
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and_which_binds_tighter_than_or() {
+        assert_eq!(
+            Expr::parse("a OR NOT b AND c").unwrap(),
+            Expr::Or(tag("a"), Box::new(Expr::And(Box::new(Expr::Not(tag("b"))), tag("c")))),
+        );
+        assert_eq!(
+            Expr::parse("a and b or c").unwrap(),
+            Expr::Or(Box::new(Expr::And(tag("a"), tag("b"))), tag("c")),
+        );
+        // Chains group to the left
+        assert_eq!(
+            Expr::parse("a AND b AND c").unwrap(),
+            Expr::And(Box::new(Expr::And(tag("a"), tag("b"))), tag("c")),
+        );
+    }
+
+    #[test]
+    fn parentheses_override_precedence() {
+        assert_eq!(
+            Expr::parse("(a OR b) AND NOT (c OR d)").unwrap(),
+            Expr::And(Box::new(Expr::Or(tag("a"), tag("b"))), Box::new(Expr::Not(Box::new(Expr::Or(tag("c"), tag("d")))))),
+        );
+        assert_eq!(Expr::parse("((a))").unwrap(), *tag("a"));
+        assert_eq!(Expr::parse(r#""not" AND "a \"b\" (c)""#).unwrap(), Expr::And(tag("not"), tag(r#"a "b" (c)"#)));
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let nested = |depth| format!("{}a{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(Expr::parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(error(&nested(MAX_DEPTH + 1)), ParseError { position: MAX_DEPTH, message: "Expression is nested too deeply" });
+
+        let nots = |depth| format!("{}a", "NOT ".repeat(depth));
+        assert!(Expr::parse(&nots(MAX_DEPTH)).is_ok());
+        assert_eq!(error(&nots(MAX_DEPTH + 1)).message, "Expression is nested too deeply");
+    }
+
+    #[test]
+    fn rejects_too_many_tags() {
+        let tags = |count| vec!["a"; count].join(" OR ");
+        assert!(Expr::parse(&tags(MAX_TAGS)).is_ok());
+        assert_eq!(error(&tags(MAX_TAGS + 1)), ParseError { position: MAX_TAGS * 5, message: "Expression has too many tags" });
+    }
+
+    #[test]
+    fn rejects_trailing_garbage() {
+        assert_eq!(error("a b"), ParseError { position: 2, message: "Expected AND or OR" });
+        assert_eq!(error("a) OR b"), ParseError { position: 1, message: "Unmatched )" });
+        assert_eq!(error("a AND"), ParseError { position: 5, message: "Expected a tag" });
+        assert_eq!(error("(a OR b"), ParseError { position: 0, message: "Unmatched (" });
+        assert_eq!(error("a \"b"), ParseError { position: 2, message: "Unterminated quote" });
+        assert_eq!(error(""), ParseError { position: 0, message: "Expected a tag" });
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::{BTreeSet, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicIsize, Ordering};
+
+use backend::UserPage;
+
+const SHARDS: usize = 64;
+
+/// The users currently holding each tag, sharded by tag so unrelated writes don't contend.
+pub struct TagIndex {
+    shards: Vec<RwLock<HashMap<String, BTreeSet<String>>>>,
+}
+
+impl TagIndex {
+    pub fn new() -> TagIndex {
+        TagIndex {
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+        }
+    }
+
+    fn shard(&self, tag: &str) -> &RwLock<HashMap<String, BTreeSet<String>>> {
+        let mut hasher = DefaultHasher::new();
+        tag.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    /// Brings the user's membership of `tag` in line with the tag's cell. The cell is read under
+    /// the shard lock, so whichever of several racing updates reconciles last leaves the index
+    /// matching the final state. If the membership changes, `changed` is called with the cell's
+    /// value while the lock is still held, so changes are reported in the order they were made.
+    pub fn reconcile<F: FnOnce(isize)>(&self, user: &str, tag: &str, cell: &AtomicIsize, changed: F) {
+        let mut shard = self.shard(tag).write().unwrap();
+        let ts = cell.load(Ordering::Acquire);
+
+        let changes = if ts > 0 {
+            shard.entry(String::from(tag)).or_default().insert(String::from(user))
+        } else if let Some(users) = shard.get_mut(tag) {
+            let removed = users.remove(user);
+            if users.is_empty() {
+                shard.remove(tag);
+            }
+            removed
+        } else {
+            false
+        };
+
+        if changes {
+            changed(ts);
+        }
+    }
+
+    /// The users currently holding `tag`.
+    pub fn members(&self, tag: &str) -> BTreeSet<String> {
+        self.shard(tag).read().unwrap().get(tag).cloned().unwrap_or_default()
+    }
+
+    /// Every user holding at least one tag.
+    pub fn all_users(&self) -> BTreeSet<String> {
+        let mut users = BTreeSet::new();
+        for shard in self.shards.iter() {
+            for members in shard.read().unwrap().values() {
+                users.extend(members.iter().cloned());
+            }
+        }
+        users
+    }
+
+    /// Up to `limit` users with `tag`, in order, starting after the user `after`.
+    pub fn users_with_tag(&self, tag: &str, after: Option<&str>, limit: usize) -> UserPage {
+        let shard = self.shard(tag).read().unwrap();
+
+        let users = match shard.get(tag) {
+            Some(users) => users,
+            None => return UserPage::default(),
+        };
+
+        UserPage::from_set(users, after, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(index: &TagIndex, user: &str, tag: &str) {
+        index.reconcile(user, tag, &AtomicIsize::new(1_000), |_| {});
+    }
+
+    #[test]
+    fn pages_through_users_after_the_cursor() {
+        let index = TagIndex::new();
+        for user in ["carol", "alice", "erin", "bob", "dave"].iter() {
+            add(&index, user, "vip");
+        }
+        add(&index, "frank", "other");
+
+        let page = index.users_with_tag("vip", None, 2);
+        assert_eq!(page.users, vec!["alice", "bob"]);
+        assert_eq!((page.total, page.next.as_deref()), (5, Some("bob")));
+
+        let page = index.users_with_tag("vip", Some("bob"), 2);
+        assert_eq!(page.users, vec!["carol", "dave"]);
+        assert_eq!((page.total, page.next.as_deref()), (5, Some("dave")));
+
+        let page = index.users_with_tag("vip", Some("dave"), 2);
+        assert_eq!(page.users, vec!["erin"]);
+        assert_eq!((page.total, page.next), (5, None));
+
+        // A cursor needn't be a member, as when the user it names has since lost the tag
+        let page = index.users_with_tag("vip", Some("bz"), 10);
+        assert_eq!(page.users, vec!["carol", "dave", "erin"]);
+
+        assert_eq!(index.users_with_tag("missing", None, 10).total, 0);
+    }
+
+    #[test]
+    fn reconciles_with_the_cell() {
+        let index = TagIndex::new();
+        let cell = AtomicIsize::new(1_000);
+        let mut changes = Vec::new();
+
+        index.reconcile("alice", "vip", &cell, |ts| changes.push(ts));
+        add(&index, "bob", "vip");
+        // Nothing changes for a user already in line with the cell
+        index.reconcile("alice", "vip", &cell, |ts| changes.push(ts));
+        assert_eq!(index.members("vip").len(), 2);
+
+        cell.store(-1_001, Ordering::Release);
+        index.reconcile("alice", "vip", &cell, |ts| changes.push(ts));
+        index.reconcile("alice", "vip", &cell, |ts| changes.push(ts));
+        assert_eq!(changes, vec![1_000, -1_001]);
+        assert_eq!(index.users_with_tag("vip", None, 10).users, vec!["bob"]);
+
+        // The tag goes once its last user does
+        index.reconcile("bob", "vip", &cell, |_| {});
+        assert!(index.shard("vip").read().unwrap().get("vip").is_none());
+        assert!(index.all_users().is_empty());
+    }
+}
@@ -0,0 +1,1379 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::mem;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::sync::atomic::{AtomicIsize, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use wal::{Wal, SyncPolicy, LogEntry, Op};
+use snapshot::{self, SnapshotWriter};
+use backend::{self, TagBackend, SnapshotStats, GcStats, UserPage, Change, ChangeKind, Operation, GROUP_SEPARATOR, attribute_wins};
+use tag_index::TagIndex;
+use tag_expr::Expr;
+use events::EventBus;
+use merkle::{MerkleTree, Digest, LEAVES, NODES};
+
+type UserTags = Arc<RwLock<UserState>>;
+
+/// Operations kept in each user's history.
+const HISTORY_LIMIT: usize = 100;
+/// The source of the removes `expire_tags` applies.
+const EXPIRY_SOURCE: &str = "expiry";
+/// The source of the operations `bucket_contents` describes the store with.
+const REPAIR_SOURCE: &str = "repair";
+
+/// Everything held for one user's tags.
+#[derive(Default)]
+struct UserState {
+    tags: HashMap<String, Arc<TagCell>>,
+    /// The latest time each of the user's groups was replaced at.
+    groups: HashMap<String, isize>,
+    /// The user's latest tag operations, oldest first. Only kept in memory.
+    history: Mutex<VecDeque<Change>>,
+}
+
+impl UserState {
+    fn record(&self, change: Change) {
+        let mut history = self.history.lock().unwrap();
+        if history.len() == HISTORY_LIMIT {
+            history.pop_front();
+        }
+        history.push_back(change);
+    }
+
+    /// The signed timestamp `ts` for `tag` once any replacement of its group is taken into
+    /// account, which turns anything older into a remove just before the replacement.
+    fn clamp(&self, tag: &str, ts: isize) -> isize {
+        match backend::group_of(tag).and_then(|group| self.groups.get(group)) {
+            Some(&cleared) if backend::group_clears(cleared, ts) => -(cleared - 1),
+            _ => ts,
+        }
+    }
+
+    /// Merges an operation into the cell for `tag`, creating it unless the operation is `stale`.
+    /// Returns the cell and, if its timestamp was changed, the value that replaced, which is
+    /// zero for a new cell.
+    fn update(&mut self, tag: &str, ts: isize, expires: isize, stale: bool) -> Option<(Arc<TagCell>, Option<isize>)> {
+        let ts = self.clamp(tag, ts);
+
+        match self.tags.get(tag) {
+            Some(cell) => Some(update_cell(cell.clone(), ts, expires)),
+            None if stale => None,
+            None => {
+                let cell = Arc::new(TagCell::new(ts, expires));
+                self.tags.insert(String::from(tag), cell.clone());
+                Some((cell, Some(0)))
+            }
+        }
+    }
+}
+
+/// The state of one of a user's tags.
+struct TagCell {
+    /// The timestamp of the latest operation, positive if that was an add and negated if it was
+    /// a remove.
+    ts: AtomicIsize,
+    /// The latest expiry carried by any add, or 0. It only matters while it would win over `ts`.
+    expires: AtomicIsize,
+}
+
+impl TagCell {
+    fn new(ts: isize, expires: isize) -> TagCell {
+        TagCell {
+            ts: AtomicIsize::new(ts),
+            expires: AtomicIsize::new(expires),
+        }
+    }
+
+    fn present(&self, now: isize) -> bool {
+        backend::is_present(self.ts.load(Ordering::Acquire), self.expires.load(Ordering::Acquire), now)
+    }
+
+    /// The expiry still waiting to be applied, if any.
+    fn pending_expiry(&self) -> Option<isize> {
+        let expires = self.expires.load(Ordering::Acquire);
+        Some(expires).filter(|expires| *expires > 0 && wins(-expires, self.ts.load(Ordering::Acquire)))
+    }
+}
+
+/// One of a user's attributes.
+struct Attribute {
+    /// The timestamp of the latest set, or negated, of the latest remove.
+    ts: isize,
+    /// The value set, empty once removed.
+    value: String,
+}
+
+/// Users re-checked under each hold of the store's write lock during garbage collection.
+const GC_BATCH: usize = 1024;
+
+/// An in-memory `TagBackend`, optionally made durable with a write-ahead log and snapshots. Each
+/// tag holds the timestamp of the latest operation on it, positive if that was an add and
+/// negated if it was a remove, along with any expiry still to be applied. Every change to which
+/// users hold which tags is published on the store's `EventBus`, and a Merkle tree over each
+/// user's state lets replicas find where they differ.
+pub struct TagStore {
+    /// Writers hold the read lock for the whole of an update, so garbage collection can take the
+    /// write lock to drop cells without losing updates to them.
+    store: RwLock<HashMap<String, UserTags>>,
+    index: TagIndex,
+    persistence: Option<Persistence>,
+    /// Tombstones at or before this timestamp may have been collected, so operations this old
+    /// that find no cell are dropped rather than resurrecting a removed tag.
+    collected_before: AtomicIsize,
+    gc_tombstones: AtomicU64,
+    gc_users: AtomicU64,
+    /// Pending expiries by deadline, then user and tag, for `expire_tags` to apply.
+    expiries: Mutex<BTreeSet<(isize, String, String)>>,
+    /// Each user's attributes by key, removed ones included. Kept apart from tags, since a key
+    /// and a tag may share a name.
+    attributes: RwLock<HashMap<String, HashMap<String, Attribute>>>,
+    events: EventBus,
+    merkle: MerkleTree,
+}
+
+/// Where a durable store keeps its log and snapshots.
+struct Persistence {
+    dir: PathBuf,
+    wal: Mutex<Wal>,
+    /// Held shared from logging an operation until it has been applied, and exclusively to
+    /// rotate the log, so every operation in a sealed segment is already applied.
+    applying: RwLock<()>,
+    /// Held while snapshotting or collecting garbage, which must not interleave.
+    snapshot_lock: Mutex<()>,
+}
+
+impl Default for TagStore {
+    fn default() -> TagStore {
+        TagStore::new()
+    }
+}
+
+impl TagStore {
+    /// Creates an empty store that only lives in memory.
+    pub fn new() -> TagStore {
+        TagStore {
+            store: RwLock::new(HashMap::new()),
+            index: TagIndex::new(),
+            persistence: None,
+            collected_before: AtomicIsize::new(0),
+            gc_tombstones: AtomicU64::new(0),
+            gc_users: AtomicU64::new(0),
+            expiries: Mutex::new(BTreeSet::new()),
+            attributes: RwLock::new(HashMap::new()),
+            events: EventBus::new(),
+            merkle: MerkleTree::new(),
+        }
+    }
+
+    /// Opens a store kept in `dir`, creating it if needed. The latest snapshot is loaded, then
+    /// the write-ahead log segments written since are replayed. Every later add and remove is
+    /// logged before it is applied.
+    pub fn open<P: AsRef<Path>>(dir: P, policy: SyncPolicy) -> Result<TagStore, Error> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let mut store = TagStore::new();
+
+        let generation = match snapshot::open_latest(dir)? {
+            None => 0,
+            Some(mut reader) => {
+                while let Some((entry, _)) = reader.next_entry()? {
+                    store.apply(&entry);
+                }
+
+                store.collected_before.store(reader.collected_before() as isize, Ordering::SeqCst);
+                reader.generation()
+            }
+        };
+
+        let wal = Wal::open(dir, policy, generation, |entry| { store.apply(&entry); })?;
+
+        // Recovery published everything it replayed, which is of no use to anyone subscribing now
+        store.events = EventBus::new();
+
+        store.persistence = Some(Persistence {
+            dir: dir.to_path_buf(),
+            wal: Mutex::new(wal),
+            applying: RwLock::new(()),
+            snapshot_lock: Mutex::new(()),
+        });
+
+        Ok(store)
+    }
+
+    /// Writes every user's tags, tombstones included, to a new snapshot, then deletes the log
+    /// segments it makes redundant. Writes carry on while the snapshot is taken.
+    pub fn snapshot(&self) -> Result<SnapshotStats, Error> {
+        let persistence = match self.persistence.as_ref() {
+            Some(persistence) => persistence,
+            None => return Err(Error::other("Store is not persistent")),
+        };
+
+        let _snapshotting = persistence.snapshot_lock.lock().unwrap();
+
+        // Operations logged before the rotation are already applied, so the snapshot holds them.
+        // Later ones may or may not make it in, but replaying them is harmless.
+        let generation = {
+            let _applying = persistence.applying.write().unwrap();
+            persistence.wal.lock().unwrap().rotate()?
+        };
+
+        let users: Vec<(String, UserTags)> = self.store.read().unwrap().iter()
+            .map(|(user, tags)| (user.clone(), tags.clone()))
+            .collect();
+
+        let mut writer = SnapshotWriter::create(&persistence.dir, generation)?;
+        writer.set_collected_before(self.collected_before.load(Ordering::SeqCst) as i64);
+
+        for (user, state) in users.iter() {
+            for entry in tag_entries(user, &state.read().unwrap()) {
+                writer.write(&entry)?;
+            }
+        }
+
+        // Copied out so attribute writes aren't held up by the disk
+        let tag_users: HashSet<&str> = users.iter().map(|(user, _)| user.as_str()).collect();
+        let mut attribute_users = 0;
+        let mut attribute_entries = Vec::new();
+        for (user, attributes) in self.attributes.read().unwrap().iter() {
+            if !tag_users.contains(user.as_str()) {
+                attribute_users += 1;
+            }
+
+            for (key, attribute) in attributes.iter() {
+                attribute_entries.push(attribute_entry(user, key, attribute));
+            }
+        }
+
+        for entry in attribute_entries.iter() {
+            writer.write(entry)?;
+        }
+
+        let entries = writer.commit()?;
+        persistence.wal.lock().unwrap().remove_before(generation)?;
+
+        Ok(SnapshotStats {
+            generation,
+            users: users.len() + attribute_users,
+            entries,
+        })
+    }
+
+    /// Drops tombstones and group replacements older than `horizon`, and users left without any
+    /// tags. Later operations
+    /// older than the horizon are ignored for tags that have no remaining state, since they may
+    /// have been superseded by a collected remove.
+    pub fn collect_garbage(&self, horizon: Duration) -> GcStats {
+        let _collecting = self.persistence.as_ref().map(|persistence| persistence.snapshot_lock.lock().unwrap());
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let cutoff = now.saturating_sub(horizon).as_millis() as isize;
+        let collectable = |cell: &Arc<TagCell>| {
+            let ts = cell.ts.load(Ordering::Acquire);
+            ts <= 0 && -ts <= cutoff
+        };
+
+        self.collected_before.fetch_max(cutoff, Ordering::SeqCst);
+
+        // Find candidates without blocking writers, then re-check them in batches under the lock
+        let candidates: Vec<String> = self.store.read().unwrap().iter()
+            .filter(|(_, state)| {
+                let state = state.read().unwrap();
+                (state.tags.is_empty() && state.groups.is_empty())
+                    || state.tags.values().any(&collectable)
+                    || state.groups.values().any(|cleared| *cleared <= cutoff)
+            })
+            .map(|(user, _)| user.clone())
+            .collect();
+
+        let mut stats = GcStats::default();
+        for batch in candidates.chunks(GC_BATCH) {
+            let mut store = self.store.write().unwrap();
+
+            for user in batch {
+                let emptied = match store.get(user) {
+                    None => continue,
+                    Some(state) => {
+                        let mut state = state.write().unwrap();
+
+                        let before = state.tags.len();
+                        state.tags.retain(|_, cell| !collectable(cell));
+                        stats.tombstones += (before - state.tags.len()) as u64;
+
+                        // Anything a replacement this old would remove is a collected tombstone
+                        // by now, or too old to apply anyway
+                        state.groups.retain(|_, cleared| *cleared > cutoff);
+
+                        state.tags.is_empty() && state.groups.is_empty()
+                    }
+                };
+
+                if emptied {
+                    store.remove(user);
+                    stats.users += 1;
+                }
+                self.merkle.touch(user);
+            }
+        }
+
+        self.attributes.write().unwrap().retain(|user, attributes| {
+            let before = attributes.len();
+            attributes.retain(|_, attribute| attribute.ts > 0 || -attribute.ts > cutoff);
+            stats.tombstones += (before - attributes.len()) as u64;
+
+            if attributes.len() != before {
+                self.merkle.touch(user);
+            }
+
+            !attributes.is_empty()
+        });
+
+        self.gc_tombstones.fetch_add(stats.tombstones, Ordering::Relaxed);
+        self.gc_users.fetch_add(stats.users, Ordering::Relaxed);
+
+        stats
+    }
+
+    /// Totals reclaimed by every garbage collection since the store was created or opened.
+    pub fn gc_totals(&self) -> GcStats {
+        GcStats {
+            tombstones: self.gc_tombstones.load(Ordering::Relaxed),
+            users: self.gc_users.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn tags_for_user(&self, user: &str) -> Vec<String> {
+        self.user_tags(user).unwrap_or_default()
+    }
+
+    /// The user's current tags, or `None` if there are no tags or tombstones for them at all.
+    /// Expired tags are left out even before `expire_tags` removes them.
+    pub fn user_tags(&self, user: &str) -> Option<Vec<String>> {
+        let now = backend::now_millis();
+
+        self.store.read().unwrap().get(user).map(|state| {
+            let state = state.read().unwrap();
+
+            let mut tags = Vec::with_capacity(state.tags.len());
+            for (tag, cell) in state.tags.iter() {
+                if cell.present(now) {
+                    tags.push(tag.clone());
+                }
+            }
+            tags
+        })
+    }
+
+    /// The user's current attributes, or `None` if there are none for them at all, removed or not.
+    pub fn user_attributes(&self, user: &str) -> Option<HashMap<String, String>> {
+        self.attributes.read().unwrap().get(user).map(|attributes| {
+            attributes.iter()
+                .filter(|(_, attribute)| attribute.ts > 0)
+                .map(|(key, attribute)| (key.clone(), attribute.value.clone()))
+                .collect()
+        })
+    }
+
+    /// The user's latest tag operations, oldest first, or `None` if there is no state for them.
+    /// History is only kept in memory, and goes with the user if garbage collection drops them.
+    pub fn history(&self, user: &str) -> Option<Vec<Change>> {
+        self.store.read().unwrap().get(user)
+            .map(|state| state.read().unwrap().history.lock().unwrap().iter().cloned().collect())
+    }
+
+    /// Up to `limit` of the users currently holding `tag`, in order, starting after `after`.
+    /// Expired tags count until `expire_tags` removes them.
+    pub fn users_with_tag(&self, tag: &str, after: Option<&str>, limit: usize) -> UserPage {
+        self.index.users_with_tag(tag, after, limit)
+    }
+
+    /// Every user matching `expr`, answered from the tag index. Each tag's users are read
+    /// separately, so writes landing meanwhile may show up for some tags and not others.
+    pub fn audience(&self, expr: &Expr) -> BTreeSet<String> {
+        expr.evaluate(&|tag| self.index.members(tag), || self.index.all_users())
+    }
+
+    pub fn add_tag(&self, user: &str, tag: &str, ts: i64, source: &str) -> Result<(), Error> {
+        self.log_and_record(LogEntry {
+            op: Op::Add,
+            user: String::from(user),
+            tag: String::from(tag),
+            timestamp: ts,
+            expires_at: None,
+            value: None,
+        }, source)
+    }
+
+    /// Adds a tag that `expire_tags` removes again once `expires_at` has passed.
+    pub fn add_expiring_tag(&self, user: &str, tag: &str, ts: i64, expires_at: i64, source: &str) -> Result<(), Error> {
+        self.log_and_record(LogEntry {
+            op: Op::Add,
+            user: String::from(user),
+            tag: String::from(tag),
+            timestamp: ts,
+            expires_at: Some(expires_at),
+            value: None,
+        }, source)
+    }
+
+    pub fn remove_tag(&self, user: &str, tag: &str, ts: i64, source: &str) -> Result<(), Error> {
+        self.log_and_record(LogEntry {
+            op: Op::Remove,
+            user: String::from(user),
+            tag: String::from(tag),
+            timestamp: ts,
+            expires_at: None,
+            value: None,
+        }, source)
+    }
+
+    /// Replaces the user's tags in `group` with `tags` as of `ts`. The replacement and the adds
+    /// are applied under one hold of the user's lock, so readers never see half of it.
+    pub fn set_group(&self, user: &str, group: &str, tags: &[String], ts: i64, source: &str) -> Result<(), Error> {
+        if !backend::is_group_name(group) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid group name {:?}", group)));
+        }
+
+        if ts > isize::MAX as i64 {
+            panic!("This program must be run on a 64bit system")
+        }
+
+        let clear = LogEntry {
+            op: Op::ClearGroup,
+            user: String::from(user),
+            tag: String::from(group),
+            timestamp: ts,
+            expires_at: None,
+            value: None,
+        };
+        let tags: Vec<String> = tags.iter().map(|tag| format!("{}{}{}", group, GROUP_SEPARATOR, tag)).collect();
+
+        // Logged as the replacement followed by an add of each tag, which replay applies in turn
+        let mut entries = vec![clear.clone()];
+        entries.extend(tags.iter().map(|tag| LogEntry { op: Op::Add, tag: tag.clone(), ..clear.clone() }));
+        let _applying = self.log(&entries)?;
+
+        let cleared = ts as isize;
+        let stale = cleared <= self.collected_before.load(Ordering::SeqCst);
+        let received_at = backend::now_millis() as i64;
+        let change = |kind, tag: &str, applied| Change {
+            kind,
+            tag: String::from(tag),
+            timestamp: ts,
+            expires_at: None,
+            received_at,
+            source: String::from(source),
+            applied,
+        };
+
+        self.with_user(user, stale, |state| {
+            let replaced = self.clear_group(user, state, group, cleared);
+            state.record(change(ChangeKind::ReplaceGroup, group, replaced));
+
+            for tag in tags.iter() {
+                let applied = match state.update(tag, cleared, 0, stale) {
+                    Some((cell, previous)) => {
+                        self.reconcile(user, tag, &cell, previous);
+                        previous.is_some()
+                    }
+                    None => false,
+                };
+                state.record(change(ChangeKind::Add, tag, applied));
+            }
+        });
+        self.merkle.touch(user);
+
+        Ok(())
+    }
+
+    pub fn set_attribute(&self, user: &str, key: &str, value: &str, ts: i64) -> Result<(), Error> {
+        self.log_and_apply(LogEntry {
+            op: Op::Set,
+            user: String::from(user),
+            tag: String::from(key),
+            timestamp: ts,
+            expires_at: None,
+            value: Some(String::from(value)),
+        }).map(|_| ())
+    }
+
+    pub fn remove_attribute(&self, user: &str, key: &str, ts: i64) -> Result<(), Error> {
+        self.log_and_apply(LogEntry {
+            op: Op::Unset,
+            user: String::from(user),
+            tag: String::from(key),
+            timestamp: ts,
+            expires_at: None,
+            value: None,
+        }).map(|_| ())
+    }
+
+    /// Logs and applies a remove at the deadline of every expiry that has passed, returning how
+    /// many tags that removed.
+    pub fn expire_tags(&self) -> Result<u64, Error> {
+        let now = backend::now_millis();
+
+        let due: Vec<(isize, String, String)> = {
+            let mut expiries = self.expiries.lock().unwrap();
+            let pending = expiries.split_off(&(now + 1, String::new(), String::new()));
+            mem::replace(&mut *expiries, pending).into_iter().collect()
+        };
+
+        let mut expired = 0;
+        for (deadline, user, tag) in due {
+            // A later add or remove may have overtaken the expiry since it was queued
+            let overtaken = self.store.read().unwrap().get(&user)
+                .and_then(|state| state.read().unwrap().tags.get(&tag).cloned())
+                .is_none_or(|cell| !wins(-deadline, cell.ts.load(Ordering::Acquire)));
+
+            if !overtaken {
+                self.remove_tag(&user, &tag, deadline as i64, EXPIRY_SOURCE)?;
+                expired += 1;
+            }
+        }
+
+        Ok(expired)
+    }
+
+    /// The digests of `nodes` of the Merkle tree over every user's tags, tombstones and group
+    /// replacements included, and attributes. Leaves changed since the last call are rehashed
+    /// first.
+    pub fn merkle_digests(&self, nodes: &[usize]) -> Vec<Digest> {
+        assert!(nodes.iter().all(|node| (1..NODES).contains(node)), "No such Merkle tree node");
+
+        self.merkle.digests(nodes, |user| {
+            let mut entries: Vec<Vec<u8>> = self.user_entries(user).iter()
+                .map(|entry| {
+                    let mut encoded = Vec::new();
+                    entry.encode(&mut encoded);
+                    encoded
+                })
+                .collect();
+
+            if entries.is_empty() {
+                return None;
+            }
+
+            entries.sort();
+            Some(entries.concat())
+        })
+    }
+
+    /// Everything held for the users in `buckets`, as operations that recreate it.
+    pub fn bucket_contents(&self, buckets: &[usize]) -> Vec<Operation> {
+        assert!(buckets.iter().all(|bucket| *bucket < LEAVES), "No such Merkle tree bucket");
+
+        buckets.iter()
+            .flat_map(|bucket| self.merkle.users(*bucket))
+            .flat_map(|user| self.user_entries(&user))
+            .map(|entry| {
+                let LogEntry { op, user, tag, timestamp, expires_at, value } = entry;
+                let source = String::from(REPAIR_SOURCE);
+
+                match op {
+                    Op::Add => Operation::Add { user, tag, timestamp, expires_at, source },
+                    Op::Remove => Operation::Remove { user, tag, timestamp, source },
+                    Op::ClearGroup => Operation::SetGroup { user, group: tag, tags: Vec::new(), timestamp, source },
+                    Op::Set => Operation::SetAttribute { user, key: tag, value: value.unwrap_or_default(), timestamp },
+                    Op::Unset => Operation::RemoveAttribute { user, key: tag, timestamp },
+                }
+            })
+            .collect()
+    }
+
+    /// Logs and applies operations taken from another store's contents, leaving them out of
+    /// history.
+    pub fn merge(&self, ops: &[Operation]) -> Result<(), Error> {
+        let entry = |op, user: &str, tag: &str, timestamp| LogEntry {
+            op,
+            user: String::from(user),
+            tag: String::from(tag),
+            timestamp,
+            expires_at: None,
+            value: None,
+        };
+
+        for op in ops.iter() {
+            match *op {
+                Operation::Add { ref user, ref tag, timestamp, expires_at, .. } => {
+                    self.log_and_apply(LogEntry { expires_at, ..entry(Op::Add, user, tag, timestamp) })?;
+                }
+                Operation::Remove { ref user, ref tag, timestamp, .. } => {
+                    self.log_and_apply(entry(Op::Remove, user, tag, timestamp))?;
+                }
+                Operation::SetGroup { ref user, ref group, ref tags, timestamp, .. } => {
+                    self.log_and_apply(entry(Op::ClearGroup, user, group, timestamp))?;
+                    for tag in tags.iter() {
+                        let tag = format!("{}{}{}", group, GROUP_SEPARATOR, tag);
+                        self.log_and_apply(entry(Op::Add, user, &tag, timestamp))?;
+                    }
+                }
+                Operation::SetAttribute { ref user, ref key, ref value, timestamp } => {
+                    self.log_and_apply(LogEntry { value: Some(value.clone()), ..entry(Op::Set, user, key, timestamp) })?;
+                }
+                Operation::RemoveAttribute { ref user, ref key, timestamp } => {
+                    self.log_and_apply(entry(Op::Unset, user, key, timestamp))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Everything held for the user, as it would be written to a snapshot.
+    fn user_entries(&self, user: &str) -> Vec<LogEntry> {
+        let mut entries = match self.store.read().unwrap().get(user) {
+            Some(state) => tag_entries(user, &state.read().unwrap()),
+            None => Vec::new(),
+        };
+
+        if let Some(attributes) = self.attributes.read().unwrap().get(user) {
+            entries.extend(attributes.iter().map(|(key, attribute)| attribute_entry(user, key, attribute)));
+        }
+
+        entries
+    }
+
+    /// Logs and applies an add or remove, then records it in the user's history.
+    fn log_and_record(&self, entry: LogEntry, source: &str) -> Result<(), Error> {
+        let mut change = Change {
+            kind: if entry.op == Op::Add { ChangeKind::Add } else { ChangeKind::Remove },
+            tag: entry.tag.clone(),
+            timestamp: entry.timestamp,
+            expires_at: entry.expires_at,
+            received_at: backend::now_millis() as i64,
+            source: String::from(source),
+            applied: false,
+        };
+        let user = entry.user.clone();
+
+        change.applied = self.log_and_apply(entry)?.is_some();
+
+        // A stale operation for a user with no state at all has nowhere to be recorded
+        if let Some(state) = self.store.read().unwrap().get(&user) {
+            state.read().unwrap().record(change);
+        }
+
+        Ok(())
+    }
+
+    /// Logs and applies an operation, returning what `apply` does.
+    fn log_and_apply(&self, entry: LogEntry) -> Result<Option<isize>, Error> {
+        if entry.timestamp > isize::MAX as i64 || entry.expires_at.is_some_and(|expires_at| expires_at > isize::MAX as i64) {
+            panic!("This program must be run on a 64bit system")
+        }
+
+        let _applying = self.log(std::slice::from_ref(&entry))?;
+        Ok(self.apply(&entry))
+    }
+
+    /// Appends entries to the log, if the store keeps one, returning a guard to hold until they
+    /// are applied so a rotation can't land in between. Only the append itself is serialized, so
+    /// writers may apply in a different order than they logged, which replay doesn't mind since
+    /// operations commute.
+    fn log(&self, entries: &[LogEntry]) -> Result<Option<RwLockReadGuard<'_, ()>>, Error> {
+        let persistence = match self.persistence.as_ref() {
+            Some(persistence) => persistence,
+            None => return Ok(None),
+        };
+
+        let applying = persistence.applying.read().unwrap();
+        let mut wal = persistence.wal.lock().unwrap();
+        for entry in entries {
+            wal.append(entry)?;
+        }
+
+        Ok(Some(applying))
+    }
+
+    /// Applies an operation, returning the tag's previous state if it changed it. A tag with no
+    /// state before reads as zero.
+    fn apply(&self, entry: &LogEntry) -> Option<isize> {
+        let previous = match entry.op {
+            Op::Add | Op::Remove => self.apply_tag(entry),
+            Op::Set | Op::Unset => self.apply_attribute(entry),
+            Op::ClearGroup => {
+                let ts = entry.timestamp as isize;
+                let stale = ts <= self.collected_before.load(Ordering::SeqCst);
+                self.with_user(&entry.user, stale, |state| self.clear_group(&entry.user, state, &entry.tag, ts));
+                None
+            }
+        };
+
+        self.merkle.touch(&entry.user);
+        previous
+    }
+
+    fn apply_tag(&self, entry: &LogEntry) -> Option<isize> {
+        let ts = entry.timestamp as isize;
+        let target = if entry.op == Op::Add { ts } else { -ts };
+        let expires = entry.expires_at.map_or(0, |expires_at| expires_at as isize);
+
+        let (cell, previous) = self.update_tag(&entry.user, &entry.tag, target, expires)?;
+
+        if expires > 0 && wins(-expires, cell.ts.load(Ordering::Acquire)) {
+            self.expiries.lock().unwrap().insert((expires, entry.user.clone(), entry.tag.clone()));
+        }
+
+        self.reconcile(&entry.user, &entry.tag, &cell, previous);
+        previous
+    }
+
+    /// Brings the index in line with a cell whose timestamp was changed from `previous`, if that
+    /// changed whether the user holds the tag, and publishes the change.
+    fn reconcile(&self, user: &str, tag: &str, cell: &TagCell, previous: Option<isize>) {
+        if previous.is_some_and(|previous| (previous > 0) != (cell.ts.load(Ordering::Acquire) > 0)) {
+            self.index.reconcile(user, tag, &cell.ts, |ts| self.events.publish(user, tag, ts > 0, ts.abs() as i64));
+        }
+    }
+
+    /// Records that the user's `group` was replaced at `cleared`, and removes the tags in it last
+    /// changed before then. Returns whether that was the latest replacement of the group.
+    fn clear_group(&self, user: &str, state: &mut UserState, group: &str, cleared: isize) -> bool {
+        let latest = state.groups.entry(String::from(group)).or_insert(0);
+        if *latest >= cleared {
+            return false;
+        }
+        *latest = cleared;
+
+        for (tag, cell) in state.tags.iter() {
+            if backend::group_of(tag) == Some(group) {
+                let previous = set_if_newer(&cell.ts, -(cleared - 1));
+                self.reconcile(user, tag, cell, previous);
+            }
+        }
+
+        true
+    }
+
+    /// Applies a set or remove of an attribute, returning the attribute's previous timestamp if it
+    /// changed it.
+    fn apply_attribute(&self, entry: &LogEntry) -> Option<isize> {
+        let ts = match entry.op {
+            Op::Set => entry.timestamp as isize,
+            _ => -(entry.timestamp as isize),
+        };
+        let value = entry.value.as_deref().unwrap_or_default();
+
+        let mut attributes = self.attributes.write().unwrap();
+        let stale = ts.abs() <= self.collected_before.load(Ordering::SeqCst);
+
+        let current = attributes.get_mut(&entry.user).and_then(|attributes| attributes.get_mut(&entry.tag));
+        match current {
+            Some(current) => {
+                if !attribute_wins(ts, value, current.ts, &current.value) {
+                    return None;
+                }
+
+                let previous = current.ts;
+                *current = Attribute { ts, value: String::from(value) };
+                Some(previous)
+            }
+            None if stale => None,
+            None => {
+                attributes.entry(entry.user.clone()).or_default()
+                    .insert(entry.tag.clone(), Attribute { ts, value: String::from(value) });
+                Some(0)
+            }
+        }
+    }
+
+    /// Sets the cell for a user's tag to `ts` if that wins over its current value, and raises its
+    /// expiry to `expires`, creating the cell if it doesn't exist yet. Does nothing if the
+    /// operation is too old to apply to a tag with no state. Returns the cell and, if its
+    /// timestamp was changed, the value that replaced.
+    fn update_tag(&self, user: &str, tag: &str, ts: isize, expires: isize) -> Option<(Arc<TagCell>, Option<isize>)> {
+        let stale = ts.abs() <= self.collected_before.load(Ordering::SeqCst);
+
+        {
+            let store = self.store.read().unwrap();
+
+            if let Some(state) = store.get(user) {
+                // Holding the user's lock keeps the tag's group from being replaced between the
+                // clamp and the update
+                let state = state.read().unwrap();
+                if let Some(cell) = state.tags.get(tag) {
+                    return Some(update_cell(cell.clone(), state.clamp(tag, ts), expires));
+                }
+            }
+        }
+
+        self.with_user(user, stale, |state| state.update(tag, ts, expires, stale)).flatten()
+    }
+
+    /// Runs `f` on the user's state under its write lock, creating the state unless `stale`. The
+    /// store's lock is held throughout, so garbage collection can't drop the state meanwhile.
+    fn with_user<F, R>(&self, user: &str, stale: bool, f: F) -> Option<R> where F: FnOnce(&mut UserState) -> R {
+        {
+            let store = self.store.read().unwrap();
+
+            if let Some(state) = store.get(user) {
+                return Some(f(&mut state.write().unwrap()));
+            }
+        }
+
+        if stale {
+            return None;
+        }
+
+        let mut store = self.store.write().unwrap();
+        let state = store.entry(String::from(user)).or_default();
+        let mut state = state.write().unwrap();
+        Some(f(&mut state))
+    }
+}
+
+/// The entries a snapshot holds for the user's tags, tombstones included, and group replacements.
+fn tag_entries(user: &str, state: &UserState) -> Vec<LogEntry> {
+    let tags = state.tags.iter().map(|(tag, cell)| {
+        let ts = cell.ts.load(Ordering::Acquire);
+
+        LogEntry {
+            op: if ts > 0 { Op::Add } else { Op::Remove },
+            user: String::from(user),
+            tag: tag.clone(),
+            timestamp: ts.abs() as i64,
+            expires_at: cell.pending_expiry().map(|expires| expires as i64),
+            value: None,
+        }
+    });
+
+    let groups = state.groups.iter().map(|(group, cleared)| LogEntry {
+        op: Op::ClearGroup,
+        user: String::from(user),
+        tag: group.clone(),
+        timestamp: *cleared as i64,
+        expires_at: None,
+        value: None,
+    });
+
+    tags.chain(groups).collect()
+}
+
+/// The entry a snapshot holds for one of the user's attributes.
+fn attribute_entry(user: &str, key: &str, attribute: &Attribute) -> LogEntry {
+    LogEntry {
+        op: if attribute.ts > 0 { Op::Set } else { Op::Unset },
+        user: String::from(user),
+        tag: String::from(key),
+        timestamp: attribute.ts.abs() as i64,
+        expires_at: None,
+        value: Some(attribute.value.clone()).filter(|_| attribute.ts > 0),
+    }
+}
+
+/// Merges an operation into a cell. Returns the cell and, if its timestamp was changed, the value
+/// that replaced.
+fn update_cell(cell: Arc<TagCell>, ts: isize, expires: isize) -> (Arc<TagCell>, Option<isize>) {
+    if expires > 0 {
+        cell.expires.fetch_max(expires, Ordering::AcqRel);
+    }
+
+    let previous = set_if_newer(&cell.ts, ts);
+    (cell, previous)
+}
+
+/// Whether the signed timestamp `new` wins over `old` under last-write-wins: the later one wins,
+/// and a remove wins a tie with an add.
+fn wins(new: isize, old: isize) -> bool {
+    new.abs() > old.abs() || (new.abs() == old.abs() && new < 0 && old >= 0)
+}
+
+/// Stores `new` in the cell if it wins over the current value, returning the value it replaced.
+fn set_if_newer(tag_ts: &AtomicIsize, new: isize) -> Option<isize> {
+    loop {
+        let old = tag_ts.load(Ordering::Acquire);
+        if !wins(new, old) {
+            return None;
+        }
+
+        if tag_ts.compare_exchange(old, new, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+            return Some(old);
+        }
+    }
+}
+
+impl TagBackend for TagStore {
+    fn add_tag(&self, user: &str, tag: &str, ts: i64, source: &str) -> Result<(), Error> {
+        TagStore::add_tag(self, user, tag, ts, source)
+    }
+
+    fn remove_tag(&self, user: &str, tag: &str, ts: i64, source: &str) -> Result<(), Error> {
+        TagStore::remove_tag(self, user, tag, ts, source)
+    }
+
+    fn add_expiring_tag(&self, user: &str, tag: &str, ts: i64, expires_at: i64, source: &str) -> Result<(), Error> {
+        TagStore::add_expiring_tag(self, user, tag, ts, expires_at, source)
+    }
+
+    fn set_group(&self, user: &str, group: &str, tags: &[String], ts: i64, source: &str) -> Result<(), Error> {
+        TagStore::set_group(self, user, group, tags, ts, source)
+    }
+
+    fn set_attribute(&self, user: &str, key: &str, value: &str, ts: i64) -> Result<(), Error> {
+        TagStore::set_attribute(self, user, key, value, ts)
+    }
+
+    fn remove_attribute(&self, user: &str, key: &str, ts: i64) -> Result<(), Error> {
+        TagStore::remove_attribute(self, user, key, ts)
+    }
+
+    fn user_attributes(&self, user: &str) -> Result<Option<HashMap<String, String>>, Error> {
+        Ok(TagStore::user_attributes(self, user))
+    }
+
+    fn user_tags(&self, user: &str) -> Result<Option<Vec<String>>, Error> {
+        Ok(TagStore::user_tags(self, user))
+    }
+
+    fn history(&self, user: &str) -> Result<Option<Vec<Change>>, Error> {
+        Ok(TagStore::history(self, user))
+    }
+
+    fn users_with_tag(&self, tag: &str, after: Option<&str>, limit: usize) -> Result<UserPage, Error> {
+        Ok(TagStore::users_with_tag(self, tag, after, limit))
+    }
+
+    fn audience(&self, expr: &Expr) -> Result<BTreeSet<String>, Error> {
+        Ok(TagStore::audience(self, expr))
+    }
+
+    fn snapshot(&self) -> Result<SnapshotStats, Error> {
+        TagStore::snapshot(self)
+    }
+
+    fn collect_garbage(&self, horizon: Duration) -> Result<GcStats, Error> {
+        Ok(TagStore::collect_garbage(self, horizon))
+    }
+
+    fn expire_tags(&self) -> Result<u64, Error> {
+        TagStore::expire_tags(self)
+    }
+
+    fn events(&self) -> Option<&EventBus> {
+        Some(&self.events)
+    }
+
+    fn merkle_digests(&self, nodes: &[usize]) -> Result<Vec<Digest>, Error> {
+        Ok(TagStore::merkle_digests(self, nodes))
+    }
+
+    fn bucket_contents(&self, buckets: &[usize]) -> Result<Vec<Operation>, Error> {
+        Ok(TagStore::bucket_contents(self, buckets))
+    }
+
+    fn merge(&self, ops: &[Operation]) -> Result<(), Error> {
+        TagStore::merge(self, ops)
+    }
+
+    fn gc_totals(&self) -> GcStats {
+        TagStore::gc_totals(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use wal::tests::scratch_dir;
+
+    /// The user's sorted tags and their attributes.
+    fn contents(store: &TagStore, user: &str) -> (Option<Vec<String>>, Option<HashMap<String, String>>) {
+        let mut tags = store.user_tags(user);
+        if let Some(tags) = tags.as_mut() {
+            tags.sort();
+        }
+        (tags, store.user_attributes(user))
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| String::from(*tag)).collect()
+    }
+
+    #[test]
+    fn restores_a_snapshot_and_the_log_after_it() {
+        let dir = scratch_dir("store-recovery");
+        let users = ["alice", "bob", "carol"];
+
+        let store = TagStore::open(&dir, SyncPolicy::Never).unwrap();
+        store.add_tag("alice", "vip", 1_000, "test").unwrap();
+        store.add_tag("alice", "beta", 1_000, "test").unwrap();
+        store.remove_tag("alice", "beta", 1_001, "test").unwrap();
+        store.set_group("bob", "device", &tags(&["ios", "android"]), 1_000, "test").unwrap();
+        store.set_attribute("carol", "plan", "gold", 1_000).unwrap();
+
+        let stats = store.snapshot().unwrap();
+        assert_eq!(stats.users, 3);
+
+        // The log tail, applied on top of the snapshot
+        store.add_tag("alice", "beta", 1_002, "test").unwrap();
+        store.set_group("bob", "device", &tags(&["web"]), 1_001, "test").unwrap();
+        store.remove_attribute("carol", "plan", 1_001).unwrap();
+        store.set_attribute("carol", "region", "eu", 1_001).unwrap();
+        store.add_tag("dave", "new", 1_000, "test").unwrap();
+
+        let expected: Vec<_> = users.iter().chain(["dave"].iter()).map(|user| contents(&store, user)).collect();
+        drop(store);
+
+        let store = TagStore::open(&dir, SyncPolicy::Never).unwrap();
+        let restored: Vec<_> = users.iter().chain(["dave"].iter()).map(|user| contents(&store, user)).collect();
+        assert_eq!(restored, expected);
+        assert_eq!(contents(&store, "bob").0, Some(tags(&["device:web"])));
+
+        // A late add from before the replacement is still cleared by it
+        store.add_tag("bob", "device:ios", 1_000, "test").unwrap();
+        assert_eq!(contents(&store, "bob").0, Some(tags(&["device:web"])));
+    }
+
+    #[test]
+    fn snapshots_taken_during_writes_lose_none_of_them() {
+        let dir = scratch_dir("store-concurrent-snapshot");
+        let store = Arc::new(TagStore::open(&dir, SyncPolicy::Never).unwrap());
+
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    for i in 0..500 {
+                        store.add_tag(&format!("user-{}", writer), &format!("tag-{}", i), 1_000, "test").unwrap();
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..10 {
+            store.snapshot().unwrap();
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        drop(store);
+
+        let store = TagStore::open(&dir, SyncPolicy::Never).unwrap();
+        for writer in 0..4 {
+            assert_eq!(store.user_tags(&format!("user-{}", writer)).map(|tags| tags.len()), Some(500));
+        }
+    }
+
+    #[test]
+    fn keeps_the_previous_snapshot_after_a_crash_before_the_rename() {
+        let dir = scratch_dir("store-crash");
+
+        let store = TagStore::open(&dir, SyncPolicy::Never).unwrap();
+        store.add_tag("alice", "vip", 1_000, "test").unwrap();
+        store.snapshot().unwrap();
+        store.add_tag("alice", "beta", 1_001, "test").unwrap();
+
+        // Everything up to the rename of a second snapshot, as `snapshot` does it
+        {
+            let persistence = store.persistence.as_ref().unwrap();
+            let generation = persistence.wal.lock().unwrap().rotate().unwrap();
+            let mut writer = SnapshotWriter::create(&dir, generation).unwrap();
+            for entry in store.user_entries("alice") {
+                writer.write(&entry).unwrap();
+            }
+        }
+        store.add_tag("alice", "late", 1_002, "test").unwrap();
+        drop(store);
+
+        assert!(dir.join("snapshot.tmp").exists());
+
+        let store = TagStore::open(&dir, SyncPolicy::Never).unwrap();
+        assert_eq!(contents(&store, "alice").0, Some(tags(&["beta", "late", "vip"])));
+
+        // The next snapshot replaces the leftover file
+        store.snapshot().unwrap();
+        drop(store);
+        assert!(!dir.join("snapshot.tmp").exists());
+
+        let store = TagStore::open(&dir, SyncPolicy::Never).unwrap();
+        assert_eq!(contents(&store, "alice").0, Some(tags(&["beta", "late", "vip"])));
+    }
+
+    #[test]
+    fn restores_the_garbage_collection_watermark() {
+        let dir = scratch_dir("store-watermark");
+
+        let store = TagStore::open(&dir, SyncPolicy::Never).unwrap();
+        store.add_tag("alice", "vip", 1_000, "test").unwrap();
+        store.remove_tag("alice", "vip", 2_000, "test").unwrap();
+        store.add_tag("bob", "vip", 1_000, "test").unwrap();
+        assert_eq!(store.collect_garbage(Duration::from_secs(3_600)).tombstones, 1);
+        let collected_before = store.collected_before.load(Ordering::SeqCst);
+        store.snapshot().unwrap();
+        drop(store);
+
+        let store = TagStore::open(&dir, SyncPolicy::Never).unwrap();
+        assert_eq!(store.collected_before.load(Ordering::SeqCst), collected_before);
+
+        // Without the watermark, this would bring back the collected tag
+        store.add_tag("alice", "vip", 1_500, "test").unwrap();
+        assert_eq!(store.user_tags("alice"), None);
+        assert_eq!(store.user_tags("bob"), Some(tags(&["vip"])));
+    }
+
+    const HOUR: i64 = 3_600_000;
+
+    #[test]
+    fn collects_tombstones_older_than_the_horizon() {
+        let store = TagStore::new();
+        let now = backend::now_millis() as i64;
+
+        store.add_tag("alice", "old", now - 3 * HOUR, "test").unwrap();
+        store.remove_tag("alice", "old", now - 2 * HOUR, "test").unwrap();
+        store.add_tag("alice", "recent", now - 3 * HOUR, "test").unwrap();
+        store.remove_tag("alice", "recent", now - 1_000, "test").unwrap();
+        store.add_tag("alice", "vip", now - 3 * HOUR, "test").unwrap();
+
+        let stats = store.collect_garbage(Duration::from_secs(3_600));
+        assert_eq!((stats.tombstones, stats.users), (1, 0));
+
+        let state = store.store.read().unwrap()["alice"].clone();
+        let mut held: Vec<String> = state.read().unwrap().tags.keys().cloned().collect();
+        held.sort();
+        assert_eq!(held, vec!["recent", "vip"]);
+        assert_eq!(store.gc_totals().tombstones, 1);
+    }
+
+    #[test]
+    fn rejects_late_operations_older_than_the_horizon() {
+        let store = TagStore::new();
+        let now = backend::now_millis() as i64;
+
+        store.add_tag("alice", "vip", now - 3 * HOUR, "test").unwrap();
+        store.remove_tag("alice", "vip", now - 2 * HOUR, "test").unwrap();
+        store.add_tag("alice", "beta", now, "test").unwrap();
+        store.collect_garbage(Duration::from_secs(3_600));
+
+        // An add from before the collected remove, which would otherwise resurrect the tag
+        store.add_tag("alice", "vip", now - 3 * HOUR, "test").unwrap();
+        // A tag that never had state is no different, since its remove may have been collected
+        store.add_tag("alice", "other", now - 2 * HOUR, "test").unwrap();
+        assert_eq!(store.user_tags("alice"), Some(vec![String::from("beta")]));
+        assert_eq!(store.users_with_tag("vip", None, 10).total, 0);
+
+        // Operations newer than the horizon still apply
+        store.add_tag("alice", "vip", now - 1_000, "test").unwrap();
+        let mut tags = store.tags_for_user("alice");
+        tags.sort();
+        assert_eq!(tags, vec!["beta", "vip"]);
+
+        // Stale history is recorded, but not applied
+        let history = store.history("alice").unwrap();
+        let applied: Vec<(&str, bool)> = history.iter().map(|change| (change.tag.as_str(), change.applied)).collect();
+        assert_eq!(&applied[applied.len() - 3..], &[("vip", false), ("other", false), ("vip", true)]);
+    }
+
+    #[test]
+    fn removes_users_left_empty() {
+        let store = TagStore::new();
+        let now = backend::now_millis() as i64;
+
+        store.add_tag("alice", "vip", now - 3 * HOUR, "test").unwrap();
+        store.remove_tag("alice", "vip", now - 2 * HOUR, "test").unwrap();
+        store.set_group("bob", "device", &[], now - 2 * HOUR, "test").unwrap();
+        store.add_tag("carol", "vip", now - 3 * HOUR, "test").unwrap();
+        store.set_attribute("dave", "plan", "gold", now - 3 * HOUR).unwrap();
+        store.remove_attribute("dave", "plan", now - 2 * HOUR).unwrap();
+
+        let stats = store.collect_garbage(Duration::from_secs(3_600));
+        assert_eq!((stats.tombstones, stats.users), (2, 2));
+
+        assert_eq!(store.user_tags("alice"), None);
+        assert!(store.history("alice").is_none());
+        assert_eq!(store.user_tags("bob"), None);
+        assert_eq!(store.user_attributes("dave"), None);
+        assert_eq!(store.user_tags("carol"), Some(vec![String::from("vip")]));
+        assert_eq!(store.gc_totals().users, 2);
+    }
+
+    #[test]
+    fn not_only_matches_users_holding_some_tag() {
+        let store = TagStore::new();
+        store.add_tag("alice", "vip", 1_000, "test").unwrap();
+        store.add_tag("bob", "beta", 1_000, "test").unwrap();
+        store.add_tag("carol", "vip", 1_000, "test").unwrap();
+        store.add_tag("carol", "beta", 1_000, "test").unwrap();
+        // Known to the store, but holding no tags
+        store.add_tag("dave", "vip", 1_000, "test").unwrap();
+        store.remove_tag("dave", "vip", 1_001, "test").unwrap();
+        store.set_attribute("erin", "plan", "gold", 1_000).unwrap();
+
+        let matching = |query: &str| store.users_matching(&Expr::parse(query).unwrap(), None, 10).unwrap().users;
+        assert_eq!(matching("NOT vip"), vec!["bob"]);
+        assert_eq!(matching("NOT missing"), vec!["alice", "bob", "carol"]);
+        assert_eq!(matching("NOT (vip AND beta)"), vec!["alice", "bob"]);
+        assert_eq!(matching("vip AND NOT beta OR NOT vip"), vec!["alice", "bob"]);
+
+        let page = store.users_matching(&Expr::parse("NOT missing").unwrap(), Some("alice"), 1).unwrap();
+        assert_eq!(page.users, vec!["bob"]);
+        assert_eq!((page.total, page.next.as_deref()), (3, Some("bob")));
+    }
+
+    #[test]
+    fn expired_tags_disappear_once_due() {
+        let store = TagStore::new();
+        let now = backend::now_millis() as i64;
+
+        store.add_expiring_tag("alice", "trial", now - 1_000, now - 1, "test").unwrap();
+        store.add_expiring_tag("alice", "pending", now - 1_000, now + HOUR, "test").unwrap();
+
+        // Hidden from reads straight away, though lookups by tag wait for the sweep
+        assert_eq!(store.user_tags("alice"), Some(tags(&["pending"])));
+        assert_eq!(store.users_with_tag("trial", None, 10).total, 1);
+
+        assert_eq!(store.expire_tags().unwrap(), 1);
+        assert_eq!(store.users_with_tag("trial", None, 10).total, 0);
+        assert_eq!(store.users_with_tag("pending", None, 10).total, 1);
+        assert_eq!(store.expire_tags().unwrap(), 0);
+    }
+
+    #[test]
+    fn expiries_lose_to_later_adds() {
+        let store = TagStore::new();
+        let now = backend::now_millis() as i64;
+
+        store.add_expiring_tag("alice", "renewed", now - 1_000, now - 500, "test").unwrap();
+        store.add_tag("alice", "renewed", now - 100, "test").unwrap();
+        // An add from before the expiry doesn't outlast it
+        store.add_expiring_tag("alice", "lapsed", now - 1_000, now - 500, "test").unwrap();
+        store.add_tag("alice", "lapsed", now - 800, "test").unwrap();
+
+        assert_eq!(contents(&store, "alice").0, Some(tags(&["renewed"])));
+        assert_eq!(store.expire_tags().unwrap(), 1);
+        assert_eq!(contents(&store, "alice").0, Some(tags(&["renewed"])));
+        assert_eq!(store.users_with_tag("renewed", None, 10).total, 1);
+        assert_eq!(store.users_with_tag("lapsed", None, 10).total, 0);
+    }
+
+    #[test]
+    fn sweeping_an_expiry_leaves_a_tombstone_and_an_event() {
+        let store = TagStore::new();
+        let now = backend::now_millis() as i64;
+        let deadline = now - 1;
+
+        store.add_expiring_tag("alice", "trial", now - 1_000, deadline, "test").unwrap();
+        let added = store.events.latest();
+        assert_eq!(store.expire_tags().unwrap(), 1);
+
+        let cell = store.store.read().unwrap()["alice"].read().unwrap().tags["trial"].clone();
+        assert_eq!(cell.ts.load(Ordering::Acquire), -(deadline as isize));
+        assert_eq!(cell.pending_expiry(), None);
+
+        let events = store.events.wait(added, Duration::from_secs(1)).unwrap().events;
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].tag.as_str(), events[0].present, events[0].timestamp), ("trial", false, deadline));
+
+        // A later add brings the tag back over the tombstone
+        store.add_tag("alice", "trial", now, "test").unwrap();
+        assert_eq!(store.user_tags("alice"), Some(tags(&["trial"])));
+    }
+
+    #[test]
+    fn replacing_a_group_removes_its_older_tags() {
+        let store = TagStore::new();
+        store.add_tag("alice", "device:ios", 1_000, "test").unwrap();
+        store.add_tag("alice", "device:android", 2_500, "test").unwrap();
+        store.set_group("alice", "device", &tags(&["web"]), 2_000, "test").unwrap();
+
+        // Changed after the replacement, so it survives it
+        assert_eq!(contents(&store, "alice").0, Some(tags(&["device:android", "device:web"])));
+
+        // Late operations from before the replacement are cleared as they arrive
+        store.add_tag("alice", "device:tv", 1_500, "test").unwrap();
+        store.add_tag("alice", "device:ios", 1_999, "test").unwrap();
+        assert_eq!(contents(&store, "alice").0, Some(tags(&["device:android", "device:web"])));
+
+        // While ones as late as the replacement or later still apply
+        store.add_tag("alice", "device:tv", 2_000, "test").unwrap();
+        store.add_tag("alice", "device:ios", 2_001, "test").unwrap();
+        assert_eq!(contents(&store, "alice").0, Some(tags(&["device:android", "device:ios", "device:tv", "device:web"])));
+
+        // An older replacement arriving late changes nothing
+        store.set_group("alice", "device", &[], 1_800, "test").unwrap();
+        assert_eq!(store.tags_for_user("alice").len(), 4);
+    }
+
+    #[test]
+    fn replacing_a_group_leaves_other_tags_alone() {
+        let store = TagStore::new();
+        store.add_tag("alice", "vip", 1_000, "test").unwrap();
+        store.add_tag("alice", "plan:pro", 1_000, "test").unwrap();
+        store.add_tag("alice", "devices", 1_000, "test").unwrap();
+        store.add_tag("alice", "device:ios", 1_000, "test").unwrap();
+
+        store.set_group("alice", "device", &[], 2_000, "test").unwrap();
+        assert_eq!(contents(&store, "alice").0, Some(tags(&["devices", "plan:pro", "vip"])));
+
+        // Nor does it reach other users
+        store.add_tag("bob", "device:ios", 1_000, "test").unwrap();
+        assert_eq!(store.user_tags("bob"), Some(tags(&["device:ios"])));
+    }
+
+    #[test]
+    fn rejects_invalid_group_names() {
+        let store = TagStore::new();
+
+        for group in ["", "device:ios"].iter() {
+            let err = store.set_group("alice", group, &tags(&["web"]), 1_000, "test").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+        assert_eq!(store.user_tags("alice"), None);
+    }
+
+    #[test]
+    fn keeps_only_the_latest_history() {
+        let store = TagStore::new();
+        for i in 0..HISTORY_LIMIT + 10 {
+            store.add_tag("alice", &format!("tag-{}", i), 1_000 + i as i64, "test").unwrap();
+        }
+
+        let history = store.history("alice").unwrap();
+        assert_eq!(history.len(), HISTORY_LIMIT);
+        assert_eq!(history[0].tag, "tag-10");
+        assert_eq!(history[HISTORY_LIMIT - 1].tag, format!("tag-{}", HISTORY_LIMIT + 9));
+    }
+
+    #[test]
+    fn records_stale_operations_as_not_applied() {
+        let store = TagStore::new();
+        store.add_tag("alice", "vip", 2_000, "api").unwrap();
+        store.remove_tag("alice", "vip", 1_000, "batch").unwrap();
+        // Ties go to the remove, so only the add repeated at the same time is stale
+        store.remove_tag("alice", "vip", 2_000, "api").unwrap();
+        store.add_tag("alice", "vip", 2_000, "api").unwrap();
+        store.set_group("alice", "device", &tags(&["ios"]), 3_000, "api").unwrap();
+        store.set_group("alice", "device", &[], 2_500, "api").unwrap();
+
+        let history = store.history("alice").unwrap();
+        let history: Vec<(ChangeKind, &str, i64, &str, bool)> = history.iter()
+            .map(|change| (change.kind, change.tag.as_str(), change.timestamp, change.source.as_str(), change.applied))
+            .collect();
+        assert_eq!(history, vec![
+            (ChangeKind::Add, "vip", 2_000, "api", true),
+            (ChangeKind::Remove, "vip", 1_000, "batch", false),
+            (ChangeKind::Remove, "vip", 2_000, "api", true),
+            (ChangeKind::Add, "vip", 2_000, "api", false),
+            (ChangeKind::ReplaceGroup, "device", 3_000, "api", true),
+            (ChangeKind::Add, "device:ios", 3_000, "api", true),
+            (ChangeKind::ReplaceGroup, "device", 2_500, "api", false),
+        ]);
+    }
+
+    #[test]
+    fn records_expiries_under_their_own_source() {
+        let store = TagStore::new();
+        let now = backend::now_millis() as i64;
+
+        store.add_expiring_tag("alice", "trial", now - 1_000, now - 1, "api").unwrap();
+        store.expire_tags().unwrap();
+
+        let history = store.history("alice").unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!((history[0].kind, history[0].expires_at, history[0].source.as_str()), (ChangeKind::Add, Some(now - 1), "api"));
+        assert_eq!((history[1].kind, history[1].timestamp, history[1].source.as_str()), (ChangeKind::Remove, now - 1, EXPIRY_SOURCE));
+        assert!(history[1].applied);
+    }
+}
@@ -2,6 +2,7 @@ extern crate core;
 
 use std::thread;
 use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
 pub struct ThreadPool {
     workers: Vec<Worker>,
@@ -32,24 +33,61 @@ impl ThreadPool {
         where F: FnOnce() + Send + 'static
     {
         let job = Box::new(func);
-        match self.queue.send(QueueItem::Work(job)) {
-            Ok(_) => true,
-            Err(_) => false,
+        self.queue.send(QueueItem::Work(job)).is_ok()
+    }
+
+    /// Lets workers finish every job queued so far, then stops them. Returns false if some were
+    /// still busy at the deadline, in which case they are left to finish in the background.
+    pub fn shutdown(mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut unsignalled = self.workers.len();
+
+        loop {
+            // The queue may be full of jobs behind busy workers, so signals are sent as room frees
+            // up rather than blocking past the deadline
+            while unsignalled > 0 {
+                match self.queue.try_send(QueueItem::ShutdownSignal) {
+                    Ok(()) => unsignalled -= 1,
+                    Err(mpsc::TrySendError::Full(_)) => break,
+                    Err(mpsc::TrySendError::Disconnected(_)) => unsignalled = 0,
+                }
+            }
+
+            if unsignalled == 0 && self.workers.iter().all(Worker::is_finished) {
+                break;
+            }
+
+            if Instant::now() >= deadline {
+                // Dropping the remaining handles detaches their threads, and dropping the queue
+                // stops them once they finish the jobs they've already taken
+                self.workers.clear();
+                return false;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        while let Some(worker) = self.workers.pop() {
+            worker.join();
         }
+
+        true
     }
-}
 
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
+    fn signal_shutdown(&self) {
         for _ in self.workers.iter() {
-            match self.queue.send(QueueItem::ShutdownSignal) {
-                Ok(_) => {},
-                Err(_) => eprintln!("Failed to dispatch shutdown signal"),
+            if self.queue.send(QueueItem::ShutdownSignal).is_err() {
+                eprintln!("Failed to dispatch shutdown signal")
             }
         }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.signal_shutdown();
 
-        while self.workers.len() > 0 {
-            let worker = self.workers.pop().unwrap();
+        while let Some(worker) = self.workers.pop() {
             worker.join();
         }
     }
@@ -65,7 +103,7 @@ impl<F: FnOnce()> FnBox for F {
     }
 }
 
-type Job = Box<FnBox + Send + 'static>;
+type Job = Box<dyn FnBox + Send + 'static>;
 
 enum QueueItem {
     ShutdownSignal,
@@ -97,6 +135,10 @@ impl Worker {
         }
     }
 
+    fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
     fn join(self) {
         match self.thread.join() {
             Ok(_) => {}
@@ -104,3 +146,48 @@ impl Worker {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn shutdown_finishes_queued_jobs() {
+        let pool = ThreadPool::new(2, 10);
+        let done = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..10 {
+            let done = done.clone();
+            assert!(pool.execute(move || {
+                thread::sleep(Duration::from_millis(5));
+                done.fetch_add(1, Ordering::SeqCst);
+            }));
+        }
+
+        assert!(pool.shutdown(Duration::from_secs(5)));
+        assert_eq!(done.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn shutdown_times_out_with_a_full_queue_behind_a_stuck_job() {
+        let pool = ThreadPool::new(1, 1);
+        let (release, stuck) = mpsc::channel::<()>();
+        let (started, running) = mpsc::channel();
+
+        assert!(pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = stuck.recv();
+        }));
+        running.recv().unwrap();
+        // Fills the queue, so there's no room for a shutdown signal
+        assert!(pool.execute(|| {}));
+
+        let start = Instant::now();
+        assert!(!pool.shutdown(Duration::from_millis(100)));
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_millis(100) && waited < Duration::from_secs(2), "Waited {:?}", waited);
+
+        release.send(()).unwrap();
+    }
+}
@@ -0,0 +1,502 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write, Seek, SeekFrom, Error, ErrorKind, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crc32fast;
+
+/// Bytes before each record's payload: its length, then a CRC32 of the payload.
+const RECORD_HEADER: usize = 8;
+/// Records larger than this can only come from a corrupt length field, so larger entries are
+/// refused rather than written.
+const MAX_RECORD: usize = 1024 * 1024;
+
+const SEGMENT_PREFIX: &str = "wal.";
+
+const OP_ADD: u8 = 0;
+const OP_REMOVE: u8 = 1;
+const OP_SET: u8 = 2;
+const OP_UNSET: u8 = 3;
+const OP_CLEAR_GROUP: u8 = 4;
+/// Set on the op byte when an expiry follows the timestamp.
+const EXPIRES_FLAG: u8 = 0x80;
+
+/// When appended records are forced to stable storage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// fsync after every record. Nothing acknowledged is lost, even on power failure.
+    Always,
+    /// fsync on the first append once the interval has passed since the last one. A crash of the
+    /// machine may lose the records written since.
+    Interval(Duration),
+    /// Leave flushing to the OS. Survives the process crashing, but not the machine.
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Remove,
+    /// Sets an attribute, whose key is in the entry's `tag`.
+    Set,
+    Unset,
+    /// Removes every tag in the group named by the entry's `tag` that was last changed before
+    /// the entry's timestamp, including ones whose operations arrive later.
+    ClearGroup,
+}
+
+/// One tag operation as recorded in the log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogEntry {
+    pub op: Op,
+    pub user: String,
+    /// The tag, the attribute's key, or the group's name.
+    pub tag: String,
+    pub timestamp: i64,
+    /// When the tag is due to be removed, as if by a remove at that time.
+    pub expires_at: Option<i64>,
+    /// The value an attribute is set to.
+    pub value: Option<String>,
+}
+
+impl LogEntry {
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.extend_from_slice(&[0; RECORD_HEADER]);
+
+        let op = match self.op {
+            Op::Add => OP_ADD,
+            Op::Remove => OP_REMOVE,
+            Op::Set => OP_SET,
+            Op::Unset => OP_UNSET,
+            Op::ClearGroup => OP_CLEAR_GROUP,
+        };
+
+        buf.push(if self.expires_at.is_some() { op | EXPIRES_FLAG } else { op });
+        buf.extend_from_slice(&self.timestamp.to_le_bytes());
+        if let Some(expires_at) = self.expires_at {
+            buf.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        put_str(buf, &self.user);
+        put_str(buf, &self.tag);
+        if self.op == Op::Set {
+            put_str(buf, self.value.as_deref().expect("Set entries carry a value"));
+        }
+
+        let payload_len = (buf.len() - start - RECORD_HEADER) as u32;
+        let crc = crc32fast::hash(&buf[start + RECORD_HEADER..]);
+        buf[start..start + 4].copy_from_slice(&payload_len.to_le_bytes());
+        buf[start + 4..start + 8].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<LogEntry> {
+        let mut cursor = payload;
+
+        let op_byte = take(&mut cursor, 1)?[0];
+        let op = match op_byte & !EXPIRES_FLAG {
+            OP_ADD => Op::Add,
+            OP_REMOVE => Op::Remove,
+            OP_SET => Op::Set,
+            OP_UNSET => Op::Unset,
+            OP_CLEAR_GROUP => Op::ClearGroup,
+            _ => return None,
+        };
+
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(take(&mut cursor, 8)?);
+
+        let expires_at = if op_byte & EXPIRES_FLAG != 0 {
+            let mut expires_at = [0; 8];
+            expires_at.copy_from_slice(take(&mut cursor, 8)?);
+            Some(i64::from_le_bytes(expires_at))
+        } else {
+            None
+        };
+
+        let user = take_str(&mut cursor)?;
+        let tag = take_str(&mut cursor)?;
+        let value = if op == Op::Set { Some(take_str(&mut cursor)?) } else { None };
+
+        if !cursor.is_empty() {
+            return None;
+        }
+
+        Some(LogEntry { op, user, tag, timestamp: i64::from_le_bytes(timestamp), expires_at, value })
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, value: &str) {
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buf.extend_from_slice(value.as_bytes());
+}
+
+fn take<'a>(cursor: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if cursor.len() < len {
+        return None;
+    }
+
+    let (head, tail) = cursor.split_at(len);
+    *cursor = tail;
+    Some(head)
+}
+
+fn take_str(cursor: &mut &[u8]) -> Option<String> {
+    let mut len = [0; 4];
+    len.copy_from_slice(take(cursor, 4)?);
+    let bytes = take(cursor, u32::from_le_bytes(len) as usize)?;
+
+    String::from_utf8(bytes.to_vec()).ok()
+}
+
+/// An append-only log of tag operations, split into numbered segment files in one directory.
+/// Each record is length-prefixed and checksummed, so a record torn by a crash mid-write is
+/// detected on replay and cut off.
+pub struct Wal {
+    dir: PathBuf,
+    generation: u64,
+    file: File,
+    /// Where the current segment's last whole record ends.
+    len: u64,
+    /// Set if a failed append couldn't be cut back off, after which nothing more is appended.
+    torn: bool,
+    policy: SyncPolicy,
+    last_sync: Instant,
+    buf: Vec<u8>,
+}
+
+impl Wal {
+    /// Opens the log in `dir` and passes every intact record in segments numbered
+    /// `first_generation` or later to `apply`, in order. Appends continue in the newest segment,
+    /// after its last intact record.
+    pub fn open<P: AsRef<Path>, F: FnMut(LogEntry)>(dir: P, policy: SyncPolicy, first_generation: u64, mut apply: F)
+                                                    -> Result<Wal, Error> {
+        let dir = dir.as_ref().to_path_buf();
+
+        let mut generations = list_segments(&dir)?;
+        generations.retain(|generation| *generation >= first_generation);
+
+        let mut current = None;
+        for generation in generations {
+            current = Some((generation, replay_segment(&segment_path(&dir, generation), &mut apply)?));
+        }
+
+        let (generation, mut file) = match current {
+            Some(current) => current,
+            None => (first_generation, create_segment(&dir, first_generation)?),
+        };
+        let len = file.stream_position()?;
+
+        Ok(Wal {
+            dir,
+            generation,
+            file,
+            len,
+            torn: false,
+            policy,
+            last_sync: Instant::now(),
+            buf: Vec::new(),
+        })
+    }
+
+    /// Writes a record to the log, syncing it according to the policy before returning. Fails
+    /// with `ErrorKind::InvalidInput` if the entry is too large to be replayed.
+    pub fn append(&mut self, entry: &LogEntry) -> Result<(), Error> {
+        if self.torn {
+            return Err(Error::other("Log has a torn record that couldn't be removed"));
+        }
+
+        self.buf.clear();
+        entry.encode(&mut self.buf);
+        if self.buf.len() - RECORD_HEADER > MAX_RECORD {
+            return Err(Error::new(ErrorKind::InvalidInput, "Log entry too large"));
+        }
+
+        if let Err(e) = self.file.write_all(&self.buf) {
+            // Replay stops at a torn record, so anything appended after one would be lost
+            let len = self.len;
+            if self.file.set_len(len).and_then(|_| self.file.seek(SeekFrom::Start(len))).is_err() {
+                self.torn = true;
+            }
+            return Err(e);
+        }
+        self.len += self.buf.len() as u64;
+
+        match self.policy {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::Interval(interval) if self.last_sync.elapsed() >= interval => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.file.sync_data()?;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Seals the current segment and starts appending to a new one, returning its number.
+    pub fn rotate(&mut self) -> Result<u64, Error> {
+        self.sync()?;
+
+        let generation = self.generation + 1;
+        self.file = create_segment(&self.dir, generation)?;
+        self.generation = generation;
+        self.len = 0;
+
+        Ok(generation)
+    }
+
+    /// Deletes the segments numbered below `generation`, once a snapshot has made them redundant.
+    pub fn remove_before(&self, generation: u64) -> Result<(), Error> {
+        for old in list_segments(&self.dir)? {
+            if old < generation {
+                fs::remove_file(segment_path(&self.dir, old))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("{}{:020}", SEGMENT_PREFIX, generation))
+}
+
+/// Returns the numbers of the segments in `dir`, oldest first.
+fn list_segments(dir: &Path) -> Result<Vec<u64>, Error> {
+    let mut generations = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let generation = name.to_str()
+            .and_then(|name| name.strip_prefix(SEGMENT_PREFIX))
+            .and_then(|generation| generation.parse::<u64>().ok());
+
+        if let Some(generation) = generation {
+            generations.push(generation);
+        }
+    }
+
+    generations.sort_unstable();
+    Ok(generations)
+}
+
+fn create_segment(dir: &Path, generation: u64) -> Result<File, Error> {
+    let file = OpenOptions::new().write(true).create_new(true).open(segment_path(dir, generation))?;
+    sync_dir(dir)?;
+    Ok(file)
+}
+
+/// Replays one segment, truncating anything after its last intact record, and returns it
+/// positioned for appending.
+fn replay_segment<F: FnMut(LogEntry)>(path: &Path, apply: &mut F) -> Result<File, Error> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+    let valid_len = {
+        let mut reader = BufReader::new(&mut file);
+        let mut valid_len = 0;
+
+        while let Some((entry, record_len)) = read_record(&mut reader)? {
+            apply(entry);
+            valid_len += record_len as u64;
+        }
+
+        valid_len
+    };
+
+    if file.metadata()?.len() > valid_len {
+        file.set_len(valid_len)?;
+        file.sync_all()?;
+    }
+
+    file.seek(SeekFrom::Start(valid_len))?;
+    Ok(file)
+}
+
+/// Makes file creations, renames and deletions in `dir` durable.
+pub(crate) fn sync_dir(dir: &Path) -> Result<(), Error> {
+    File::open(dir)?.sync_all()
+}
+
+/// Reads the next record, returning it with its size on disk, or `None` at the end of the intact
+/// part of the log.
+pub(crate) fn read_record<R: Read>(reader: &mut R) -> Result<Option<(LogEntry, usize)>, Error> {
+    let mut header = [0; RECORD_HEADER];
+    if !read_full(reader, &mut header)? {
+        return Ok(None);
+    }
+
+    let mut len = [0; 4];
+    let mut crc = [0; 4];
+    len.copy_from_slice(&header[..4]);
+    crc.copy_from_slice(&header[4..]);
+
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_RECORD {
+        return Ok(None);
+    }
+
+    let mut payload = vec![0; len];
+    if !read_full(reader, &mut payload)? || crc32fast::hash(&payload) != u32::from_le_bytes(crc) {
+        return Ok(None);
+    }
+
+    Ok(LogEntry::decode(&payload).map(|entry| (entry, RECORD_HEADER + len)))
+}
+
+/// Fills `buf`, returning false if the input ran out first.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool, Error> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// Returns an empty directory unique to the test and process.
+    pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rust-tag-server-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entry(op: Op, tag: &str, timestamp: i64) -> LogEntry {
+        LogEntry {
+            op,
+            user: String::from("user-0"),
+            tag: String::from(tag),
+            timestamp,
+            expires_at: None,
+            value: if op == Op::Set { Some(String::from("value")) } else { None },
+        }
+    }
+
+    fn replay(dir: &Path, first_generation: u64) -> (Wal, Vec<LogEntry>) {
+        let mut entries = Vec::new();
+        let wal = Wal::open(dir, SyncPolicy::Never, first_generation, |entry| entries.push(entry)).unwrap();
+        (wal, entries)
+    }
+
+    fn segment_len(dir: &Path, generation: u64) -> u64 {
+        fs::metadata(segment_path(dir, generation)).unwrap().len()
+    }
+
+    #[test]
+    fn replays_appended_records_in_order() {
+        let dir = scratch_dir("wal-round-trip");
+        let written = vec![
+            entry(Op::Add, "vip", 1),
+            LogEntry { expires_at: Some(10), ..entry(Op::Add, "trial", 2) },
+            entry(Op::Remove, "vip", 3),
+            entry(Op::Set, "plan", 4),
+            entry(Op::Unset, "plan", 5),
+            entry(Op::ClearGroup, "device", 6),
+        ];
+
+        let (mut wal, replayed) = replay(&dir, 0);
+        assert!(replayed.is_empty());
+        for entry in written.iter() {
+            wal.append(entry).unwrap();
+        }
+        drop(wal);
+
+        let (mut wal, replayed) = replay(&dir, 0);
+        assert_eq!(replayed, written);
+
+        // Appends continue after the replayed records
+        wal.append(&entry(Op::Add, "late", 7)).unwrap();
+        drop(wal);
+        assert_eq!(replay(&dir, 0).1.len(), written.len() + 1);
+    }
+
+    #[test]
+    fn truncates_a_torn_final_record() {
+        let dir = scratch_dir("wal-torn");
+        let (mut wal, _) = replay(&dir, 0);
+        wal.append(&entry(Op::Add, "vip", 1)).unwrap();
+        wal.append(&entry(Op::Add, "trial", 2)).unwrap();
+        drop(wal);
+
+        let intact = segment_len(&dir, 0);
+        let mut torn = Vec::new();
+        entry(Op::Add, "torn", 3).encode(&mut torn);
+        let mut file = OpenOptions::new().append(true).open(segment_path(&dir, 0)).unwrap();
+        file.write_all(&torn[..torn.len() - 3]).unwrap();
+        drop(file);
+
+        let (mut wal, replayed) = replay(&dir, 0);
+        assert_eq!(replayed, vec![entry(Op::Add, "vip", 1), entry(Op::Add, "trial", 2)]);
+        assert_eq!(segment_len(&dir, 0), intact);
+
+        // The next record goes where the torn one was
+        wal.append(&entry(Op::Add, "next", 4)).unwrap();
+        drop(wal);
+        assert_eq!(replay(&dir, 0).1.last(), Some(&entry(Op::Add, "next", 4)));
+    }
+
+    #[test]
+    fn refuses_entries_too_large_to_replay() {
+        let dir = scratch_dir("wal-too-large");
+        let (mut wal, _) = replay(&dir, 0);
+        wal.append(&entry(Op::Add, "vip", 1)).unwrap();
+        let intact = segment_len(&dir, 0);
+
+        let err = wal.append(&entry(Op::Add, &"x".repeat(MAX_RECORD), 2)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(segment_len(&dir, 0), intact);
+
+        // Nothing after it is lost on replay
+        wal.append(&entry(Op::Add, "next", 3)).unwrap();
+        drop(wal);
+        assert_eq!(replay(&dir, 0).1, vec![entry(Op::Add, "vip", 1), entry(Op::Add, "next", 3)]);
+    }
+
+    #[test]
+    fn stops_at_a_checksum_mismatch() {
+        let dir = scratch_dir("wal-crc");
+        let (mut wal, _) = replay(&dir, 0);
+        wal.append(&entry(Op::Add, "vip", 1)).unwrap();
+        let first = segment_len(&dir, 0);
+        wal.append(&entry(Op::Add, "trial", 2)).unwrap();
+        wal.append(&entry(Op::Add, "beta", 3)).unwrap();
+        drop(wal);
+
+        // Flips a byte in the second record's payload, leaving its header alone
+        let mut bytes = fs::read(segment_path(&dir, 0)).unwrap();
+        bytes[first as usize + RECORD_HEADER + 2] ^= 0xff;
+        fs::write(segment_path(&dir, 0), &bytes).unwrap();
+
+        let (_, replayed) = replay(&dir, 0);
+        assert_eq!(replayed, vec![entry(Op::Add, "vip", 1)]);
+        assert_eq!(segment_len(&dir, 0), first);
+    }
+
+    #[test]
+    fn rotates_and_removes_old_segments() {
+        let dir = scratch_dir("wal-rotate");
+        let (mut wal, _) = replay(&dir, 0);
+        wal.append(&entry(Op::Add, "vip", 1)).unwrap();
+        assert_eq!(wal.rotate().unwrap(), 1);
+        wal.append(&entry(Op::Add, "trial", 2)).unwrap();
+        assert_eq!(wal.rotate().unwrap(), 2);
+        wal.append(&entry(Op::Add, "beta", 3)).unwrap();
+        assert_eq!(list_segments(&dir).unwrap(), vec![0, 1, 2]);
+
+        wal.remove_before(2).unwrap();
+        assert_eq!(list_segments(&dir).unwrap(), vec![2]);
+        drop(wal);
+
+        let (mut wal, replayed) = replay(&dir, 2);
+        assert_eq!(replayed, vec![entry(Op::Add, "beta", 3)]);
+        // Appends continue in the newest segment rather than starting another
+        wal.append(&entry(Op::Add, "next", 4)).unwrap();
+        assert_eq!(list_segments(&dir).unwrap(), vec![2]);
+    }
+}
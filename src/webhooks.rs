@@ -0,0 +1,286 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use ring::hmac;
+use serde_json::{self, Value};
+
+use backend::{self, TagBackend};
+use client;
+use events::TagEvent;
+
+/// How long the feeder waits for events before checking whether it should stop.
+const FEED_INTERVAL: Duration = Duration::from_millis(500);
+/// Most characters of a failed response's body kept in the dead-letter file.
+const MAX_ERROR_BODY: usize = 200;
+/// Header carrying the hex HMAC-SHA256 of the body, keyed with the endpoint's secret.
+pub const SIGNATURE_HEADER: &str = "X-Tag-Signature";
+pub const EVENT_ID_HEADER: &str = "X-Tag-Event-Id";
+
+/// A URL to post tag changes to, and the secret their signatures are made with.
+#[derive(Clone, Debug)]
+pub struct Endpoint {
+    /// Only `http://` URLs are supported.
+    pub url: String,
+    pub secret: String,
+}
+
+/// Tunables for how `WebhookDispatcher` delivers to each endpoint.
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    /// How long to wait before the first retry. Each later retry waits twice as long as the last.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Attempts made to deliver an event before it goes to the dead-letter file.
+    pub max_attempts: u32,
+    /// Timeout for connecting to an endpoint and for each read and write.
+    pub request_timeout: Duration,
+    /// Events waiting for each endpoint. More go straight to the dead-letter file.
+    pub queue_limit: usize,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> WebhookConfig {
+        WebhookConfig {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(60),
+            max_attempts: 10,
+            request_timeout: Duration::from_secs(10),
+            queue_limit: 10_000,
+        }
+    }
+}
+
+/// Posts every change to which users hold which tags to each endpoint as JSON, signed in the
+/// `X-Tag-Signature` header as `sha256=` and the hex HMAC-SHA256 of the body. Each endpoint has
+/// its own queue and worker, so a slow or failing endpoint only holds up its own deliveries, which
+/// stay in order. Failed deliveries are retried with exponential backoff, and events that can't be
+/// delivered are appended to a dead-letter file as lines of JSON.
+pub struct WebhookDispatcher {
+    queues: Vec<Arc<Queue>>,
+    stopping: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+struct Queue {
+    endpoint: Endpoint,
+    key: hmac::Key,
+    pending: Mutex<VecDeque<Value>>,
+    /// Signalled when an event is queued or the dispatcher stops.
+    changed: Condvar,
+}
+
+/// Where undeliverable events are recorded.
+struct DeadLetters {
+    file: Mutex<File>,
+}
+
+impl DeadLetters {
+    fn record(&self, endpoint: &str, event: &Value, attempts: u32, error: &str) {
+        let mut line = serde_json::json!({
+            "endpoint": endpoint,
+            "event": event,
+            "attempts": attempts,
+            "error": error,
+        }).to_string();
+        line.push('\n');
+
+        // There's nowhere left to put an event that can't be written here either
+        let _ = self.file.lock().unwrap().write_all(line.as_bytes());
+    }
+}
+
+impl WebhookDispatcher {
+    /// Starts delivering the changes `store` publishes from now on to `endpoints`, appending
+    /// undeliverable ones to the file at `dead_letters`. Fails if the store publishes no events.
+    pub fn start<P: AsRef<Path>>(store: Arc<dyn TagBackend>, endpoints: Vec<Endpoint>, dead_letters: P,
+                                 config: WebhookConfig) -> Result<WebhookDispatcher, Error> {
+        let after = match store.events() {
+            Some(bus) => bus.latest(),
+            None => return Err(Error::new(ErrorKind::Unsupported, "Backend doesn't publish events")),
+        };
+
+        let dead_letters = Arc::new(DeadLetters {
+            file: Mutex::new(OpenOptions::new().create(true).append(true).open(dead_letters)?),
+        });
+        let stopping = Arc::new(AtomicBool::new(false));
+
+        let queues: Vec<Arc<Queue>> = endpoints.into_iter()
+            .map(|endpoint| Arc::new(Queue {
+                key: hmac::Key::new(hmac::HMAC_SHA256, endpoint.secret.as_bytes()),
+                endpoint,
+                pending: Mutex::new(VecDeque::new()),
+                changed: Condvar::new(),
+            }))
+            .collect();
+
+        let mut threads = Vec::with_capacity(queues.len() + 1);
+        for queue in queues.iter() {
+            let queue = queue.clone();
+            let dead_letters = dead_letters.clone();
+            let stopping = stopping.clone();
+            let config = config.clone();
+            threads.push(thread::spawn(move || deliver(&queue, &dead_letters, &stopping, &config)));
+        }
+
+        {
+            let queues = queues.clone();
+            let stopping = stopping.clone();
+            let queue_limit = config.queue_limit;
+            threads.push(thread::spawn(move || feed(&*store, after, &queues, &dead_letters, &stopping, queue_limit)));
+        }
+
+        Ok(WebhookDispatcher { queues, stopping, threads })
+    }
+
+    /// Stops delivering, appending events still queued to the dead-letter file, and waits for
+    /// the dispatcher's threads to finish. An attempt already under way is completed first.
+    pub fn shutdown(self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        for queue in self.queues.iter() {
+            // Taking the lock means no worker is between checking the flag and waiting
+            let _pending = queue.pending.lock().unwrap();
+            queue.changed.notify_all();
+        }
+
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Hands each event the store publishes after `after` to every endpoint's queue, until the bus
+/// closes or the dispatcher stops.
+fn feed(store: &dyn TagBackend, mut after: u64, queues: &[Arc<Queue>], dead_letters: &DeadLetters, stopping: &AtomicBool, queue_limit: usize) {
+    let bus = match store.events() {
+        Some(bus) => bus,
+        None => return,
+    };
+
+    while !stopping.load(Ordering::SeqCst) {
+        let batch = match bus.wait(after, FEED_INTERVAL) {
+            Some(batch) => batch,
+            None => return,
+        };
+
+        if batch.missed {
+            for queue in queues.iter() {
+                dead_letters.record(&queue.endpoint.url, &Value::Null, 0, "Events were dropped before they could be queued");
+            }
+        }
+
+        for event in batch.events {
+            after = event.id;
+            let payload = payload(&bus.external_id(event.id), &event);
+
+            for queue in queues.iter() {
+                let mut pending = queue.pending.lock().unwrap();
+                if pending.len() >= queue_limit {
+                    drop(pending);
+                    dead_letters.record(&queue.endpoint.url, &payload, 0, "Queue full");
+                    continue;
+                }
+
+                pending.push_back(payload.clone());
+                queue.changed.notify_all();
+            }
+        }
+    }
+}
+
+/// Delivers the endpoint's queued events in order until the dispatcher stops, then dead-letters
+/// whatever is left.
+fn deliver(queue: &Queue, dead_letters: &DeadLetters, stopping: &AtomicBool, config: &WebhookConfig) {
+    loop {
+        let event = {
+            let mut pending = queue.pending.lock().unwrap();
+            while pending.is_empty() && !stopping.load(Ordering::SeqCst) {
+                pending = queue.changed.wait(pending).unwrap();
+            }
+
+            if stopping.load(Ordering::SeqCst) {
+                break;
+            }
+            pending.pop_front().unwrap()
+        };
+
+        let body = event.to_string();
+        let signature = format!("sha256={}", backend::hex(hmac::sign(&queue.key, body.as_bytes()).as_ref()));
+        let id = event["id"].as_str().unwrap_or_default().to_owned();
+        let headers = [
+            ("Content-Type", "application/json"),
+            (SIGNATURE_HEADER, signature.as_str()),
+            (EVENT_ID_HEADER, id.as_str()),
+        ];
+
+        let mut attempts = 0;
+        let mut backoff = config.initial_backoff;
+        loop {
+            attempts += 1;
+            let (retry, error) = match client::send("POST", &queue.endpoint.url, &headers, body.as_bytes(), config.request_timeout) {
+                Ok(ref response) if (200..300).contains(&response.status) => break,
+                // The endpoint rejected the event itself, which won't change on a retry
+                Ok(ref response) if (400..500).contains(&response.status) && response.status != 408 && response.status != 429 => {
+                    (false, describe(response))
+                }
+                Ok(ref response) => (true, describe(response)),
+                Err(e) => (true, e.to_string()),
+            };
+
+            if !retry || attempts >= config.max_attempts || !sleep_unless_stopped(queue, stopping, backoff) {
+                dead_letters.record(&queue.endpoint.url, &event, attempts, &error);
+                break;
+            }
+            backoff = (backoff * 2).min(config.max_backoff);
+        }
+    }
+
+    for event in queue.pending.lock().unwrap().drain(..) {
+        dead_letters.record(&queue.endpoint.url, &event, 0, "Shut down before delivery");
+    }
+}
+
+/// Waits for `duration`, returning false instead if the dispatcher stops meanwhile.
+fn sleep_unless_stopped(queue: &Queue, stopping: &AtomicBool, duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    let mut pending = queue.pending.lock().unwrap();
+
+    loop {
+        if stopping.load(Ordering::SeqCst) {
+            return false;
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        pending = queue.changed.wait_timeout(pending, deadline - now).unwrap().0;
+    }
+}
+
+/// The status of a failed response and the start of its body, for the dead-letter file.
+fn describe(response: &client::Response) -> String {
+    let body = String::from_utf8_lossy(&response.body);
+    let body: String = body.trim().chars().take(MAX_ERROR_BODY).collect();
+
+    if body.is_empty() {
+        format!("Endpoint responded {}", response.status)
+    } else {
+        format!("Endpoint responded {}: {}", response.status, body)
+    }
+}
+
+fn payload(id: &str, event: &TagEvent) -> Value {
+    serde_json::json!({
+        "id": id,
+        "type": if event.present { "add" } else { "remove" },
+        "user": event.user,
+        "tag": event.tag,
+        "timestamp": backend::format_timestamp(event.timestamp),
+    })
+}
@@ -0,0 +1,362 @@
+extern crate rust_tag_server;
+
+use rust_tag_server::httpd::{WebServer, Router, ShutdownHandle};
+use rust_tag_server::tags::{TagBackend, TagStore, ReplicatedBackend, ReplicationConfig, ReplicationHandler, MerkleHandler,
+                            BucketsHandler, Operation, RepairStats, ChangeKind, bucket_of, REPLICATION_PATH, MERKLE_PATH,
+                            BUCKETS_PATH};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const USERS: usize = 5;
+
+/// A replica serving its replication handlers on localhost.
+struct Node {
+    store: Arc<TagStore>,
+    replicated: Arc<ReplicatedBackend>,
+    addr: SocketAddr,
+    server: Option<(ShutdownHandle, JoinHandle<()>)>,
+}
+
+impl Node {
+    fn start() -> Node {
+        let store = Arc::new(TagStore::new());
+        let config = ReplicationConfig {
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+            batch_size: 16,
+            ..ReplicationConfig::default()
+        };
+        let replicated = Arc::new(ReplicatedBackend::new(store.clone(), config));
+
+        let mut node = Node { store, replicated, addr: "127.0.0.1:0".parse().unwrap(), server: None };
+        node.serve();
+        node
+    }
+
+    /// Starts serving, on the node's previous address if it had one.
+    fn serve(&mut self) {
+        let mut router = Router::new();
+        router.add_route(REPLICATION_PATH, "POST", ReplicationHandler::new(self.replicated.clone()));
+        router.add_route(MERKLE_PATH, "POST", MerkleHandler::new(self.replicated.clone()));
+        router.add_route(BUCKETS_PATH, "POST", BucketsHandler::new(self.replicated.clone()));
+
+        let server = WebServer::new(self.addr, router, 2, 100, |err| eprintln!("{}", err)).unwrap();
+        self.addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle().unwrap();
+        self.server = Some((shutdown, thread::spawn(move || server.run())));
+    }
+
+    fn stop_serving(&mut self) {
+        if let Some((shutdown, thread)) = self.server.take() {
+            shutdown.shutdown();
+            thread.join().unwrap();
+        }
+    }
+
+    fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    fn pending(&self) -> usize {
+        self.replicated.status().iter().map(|status| status.pending).sum()
+    }
+
+    fn stop(mut self) {
+        self.replicated.shutdown();
+        self.stop_serving();
+    }
+}
+
+/// Starts `count` nodes, each replicating to every other.
+fn cluster(count: usize) -> Vec<Node> {
+    let nodes: Vec<Node> = (0..count).map(|_| Node::start()).collect();
+
+    for (i, node) in nodes.iter().enumerate() {
+        for (j, peer) in nodes.iter().enumerate() {
+            if i != j {
+                node.replicated.add_peer(&peer.url());
+            }
+        }
+    }
+
+    nodes
+}
+
+/// Polls `check` until it holds, failing the test if it doesn't within a few seconds.
+fn wait_until<F: FnMut() -> bool>(what: &str, mut check: F) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !check() {
+        assert!(Instant::now() < deadline, "Timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// The sorted tags and attributes of the first `users` users.
+fn contents(store: &dyn TagBackend, users: usize) -> BTreeMap<String, (Vec<String>, BTreeMap<String, String>)> {
+    (0..users)
+        .map(|i| {
+            let user = format!("user-{}", i);
+            let mut tags = store.tags_for_user(&user).unwrap();
+            tags.sort();
+            let attributes = store.user_attributes(&user).unwrap().unwrap_or_default().into_iter().collect();
+            (user, (tags, attributes))
+        })
+        .collect()
+}
+
+/// A small xorshift generator, so every run makes the same operations.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+/// Operations on a handful of users, tags and attributes, with timestamps drawn from a narrow
+/// range so that many of them conflict or tie.
+fn random_ops(seed: u64, count: usize) -> Vec<Operation> {
+    let mut rng = Rng(seed);
+
+    (0..count)
+        .map(|_| {
+            let user = format!("user-{}", rng.below(USERS as u64));
+            let timestamp = 1_000 + rng.below(200) as i64;
+            let source = String::from("test");
+
+            match rng.below(6) {
+                0 | 1 => Operation::Add { user, tag: format!("tag-{}", rng.below(4)), timestamp, expires_at: None, source },
+                2 => Operation::Remove { user, tag: format!("tag-{}", rng.below(4)), timestamp, source },
+                3 => {
+                    let tags = (0..rng.below(3)).map(|_| format!("t{}", rng.below(3))).collect();
+                    Operation::SetGroup { user, group: String::from("device"), tags, timestamp, source }
+                }
+                4 => Operation::SetAttribute {
+                    user,
+                    key: format!("key-{}", rng.below(2)),
+                    value: format!("value-{}", rng.below(3)),
+                    timestamp,
+                },
+                _ => Operation::RemoveAttribute { user, key: format!("key-{}", rng.below(2)), timestamp },
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn concurrent_writes_converge() {
+    let nodes = cluster(3);
+    let reference = TagStore::new();
+
+    let batches: Vec<Vec<Operation>> = (0..nodes.len()).map(|i| random_ops(0x9e37_79b9 + i as u64, 300)).collect();
+    for op in batches.iter().flatten() {
+        op.apply(&reference).unwrap();
+    }
+
+    let writers: Vec<JoinHandle<()>> = nodes.iter().zip(batches)
+        .map(|(node, ops)| {
+            let replicated = node.replicated.clone();
+            thread::spawn(move || {
+                for op in ops {
+                    op.apply(&*replicated).unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    wait_until("every operation to be sent", || nodes.iter().all(|node| node.pending() == 0));
+
+    let expected = contents(&reference, USERS);
+    for node in nodes.iter() {
+        assert_eq!(contents(&*node.store, USERS), expected);
+    }
+
+    for node in nodes {
+        node.stop();
+    }
+}
+
+#[test]
+fn resends_to_a_peer_once_it_returns() {
+    let mut nodes = cluster(2);
+    nodes[1].stop_serving();
+
+    nodes[0].replicated.add_tag("user-0", "vip", 1_000, "test").unwrap();
+    nodes[0].replicated.set_attribute("user-0", "plan", "gold", 1_000).unwrap();
+
+    wait_until("a failed send", || nodes[0].replicated.status()[0].last_error.is_some());
+    assert_eq!(nodes[0].pending(), 2);
+    assert_eq!(nodes[1].store.user_tags("user-0"), None);
+
+    nodes[1].serve();
+    wait_until("the backlog to be sent", || nodes[0].pending() == 0);
+
+    let status = &nodes[0].replicated.status()[0];
+    assert_eq!((status.sent, status.dropped, status.rejected, status.last_error.as_ref()), (2, 0, 0, None));
+    assert_eq!(contents(&*nodes[1].store, USERS), contents(&*nodes[0].store, USERS));
+    assert_eq!(nodes[1].store.tags_for_user("user-0"), vec!["vip"]);
+
+    for node in nodes {
+        node.stop();
+    }
+}
+
+#[test]
+fn drops_batches_a_peer_refuses() {
+    let nodes = cluster(1);
+    let peer = Node::start();
+    // Served nowhere, so every batch is refused with a 404
+    nodes[0].replicated.add_peer(&format!("{}/nowhere", peer.url()));
+
+    nodes[0].replicated.add_tag("user-0", "vip", 1_000, "test").unwrap();
+    nodes[0].replicated.set_attribute("user-0", "plan", "gold", 1_000).unwrap();
+    wait_until("the refused batches to be dropped", || nodes[0].replicated.status()[0].rejected == 2);
+
+    let status = &nodes[0].replicated.status()[0];
+    assert_eq!((status.pending, status.sent, status.dropped), (0, 0, 0));
+    assert!(status.last_error.as_ref().is_some_and(|error| error.starts_with("Peer responded 404")), "{:?}", status.last_error);
+
+    // Later operations are still sent rather than queued behind the refused ones
+    nodes[0].replicated.add_tag("user-1", "vip", 1_000, "test").unwrap();
+    wait_until("the next batch to be refused", || nodes[0].replicated.status()[0].rejected == 3);
+
+    peer.stop();
+    for node in nodes {
+        node.stop();
+    }
+}
+
+#[test]
+fn rejects_invalid_batches_whole() {
+    let node = Node::start();
+
+    let body = r#"{"ops": [
+        {"op": "add", "user": "user-0", "tag": "vip", "timestamp": 1000, "source": "test"},
+        {"op": "set_group", "user": "user-0", "group": "a:b", "tags": [], "timestamp": 1000, "source": "test"}
+    ]}"#;
+
+    let mut stream = TcpStream::connect(node.addr).unwrap();
+    write!(stream, "POST {} HTTP/1.1\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}", REPLICATION_PATH, body.len(), body).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 400"), "Unexpected response: {}", response);
+    assert_eq!(node.store.user_tags("user-0"), None);
+
+    node.stop();
+}
+
+#[test]
+fn rejects_batches_with_times_that_arent_positive() {
+    let node = Node::start();
+
+    let ops = [
+        r#"{"op": "add", "user": "user-0", "tag": "vip", "timestamp": 0, "source": "test"}"#,
+        r#"{"op": "remove", "user": "user-0", "tag": "vip", "timestamp": -1000, "source": "test"}"#,
+        r#"{"op": "set_attribute", "user": "user-0", "key": "plan", "value": "gold", "timestamp": -9223372036854775808}"#,
+        r#"{"op": "add", "user": "user-0", "tag": "vip", "timestamp": 1000, "expires_at": 0, "source": "test"}"#,
+        r#"{"op": "add", "user": "user-0", "tag": "vip", "timestamp": 1000, "expires_at": -5, "source": "test"}"#,
+    ];
+
+    for op in ops.iter() {
+        let body = format!(r#"{{"ops": [{{"op": "add", "user": "user-0", "tag": "ok", "timestamp": 1000, "source": "test"}}, {}]}}"#, op);
+
+        let mut stream = TcpStream::connect(node.addr).unwrap();
+        write!(stream, "POST {} HTTP/1.1\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}", REPLICATION_PATH, body.len(), body).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 400"), "Unexpected response to {}: {}", op, response);
+        assert!(response.ends_with("to be positive"), "Unexpected response to {}: {}", op, response);
+    }
+
+    assert_eq!(node.store.user_tags("user-0"), None);
+    assert_eq!(node.store.user_attributes("user-0"), None);
+
+    node.stop();
+}
+
+#[test]
+fn repair_merges_diverged_replicas() {
+    let nodes = cluster(2);
+    let reference = TagStore::new();
+
+    // Written straight to each store, as if every operation had been lost in a partition
+    for (i, node) in nodes.iter().enumerate() {
+        for op in random_ops(0x5bd1_e995 + i as u64, 300) {
+            op.apply(&*node.store).unwrap();
+            op.apply(&reference).unwrap();
+        }
+    }
+    assert_ne!(nodes[0].store.merkle_digests(&[1]), nodes[1].store.merkle_digests(&[1]));
+
+    for node in nodes.iter() {
+        let stats = node.replicated.repair().unwrap();
+        assert!(stats.buckets > 0 && stats.operations > 0, "Nothing was repaired: {:?}", stats);
+    }
+
+    let expected = contents(&reference, USERS);
+    for node in nodes.iter() {
+        assert_eq!(contents(&*node.store, USERS), expected);
+        assert_eq!(node.replicated.repair().unwrap(), RepairStats::default());
+    }
+    assert_eq!(nodes[0].store.merkle_digests(&[1]), nodes[1].store.merkle_digests(&[1]));
+
+    for node in nodes {
+        node.stop();
+    }
+}
+
+#[test]
+fn repair_only_fetches_differing_buckets() {
+    let nodes = cluster(2);
+
+    for i in 0..200 {
+        nodes[0].replicated.add_tag(&format!("user-{}", i), "shared", 1_000, "test").unwrap();
+    }
+    wait_until("the shared tags to be sent", || nodes[0].pending() == 0);
+    assert_eq!(nodes[0].store.merkle_digests(&[1]), nodes[1].store.merkle_digests(&[1]));
+
+    // Lost on the way to the other node
+    nodes[0].store.add_tag("user-7", "lost", 2_000, "test").unwrap();
+    nodes[1].store.remove_tag("user-42", "shared", 2_000, "test").unwrap();
+    nodes[1].store.set_attribute("user-99", "plan", "gold", 2_000).unwrap();
+
+    // Buckets differ wherever either side is ahead, though only the peer's contents are merged
+    let buckets: BTreeSet<usize> = ["user-7", "user-42", "user-99"].iter().map(|user| bucket_of(user)).collect();
+    let stats = nodes[0].replicated.repair().unwrap();
+    assert_eq!(stats.buckets, buckets.len());
+    // Only the users sharing those buckets are sent, rather than all 200
+    assert!(stats.operations < 20, "Too many operations fetched: {:?}", stats);
+
+    assert_eq!(nodes[0].store.tags_for_user("user-42"), Vec::<String>::new());
+    assert_eq!(nodes[0].store.user_attributes("user-99").unwrap()["plan"], "gold");
+    assert_eq!(nodes[1].store.tags_for_user("user-7"), vec!["shared"]);
+
+    nodes[1].replicated.repair().unwrap();
+    let mut tags = nodes[1].store.tags_for_user("user-7");
+    tags.sort();
+    assert_eq!(tags, vec!["lost", "shared"]);
+    assert_eq!(contents(&*nodes[0].store, 200), contents(&*nodes[1].store, 200));
+
+    // Merged state isn't presented as operations sent to the node
+    let history = nodes[1].store.history("user-7").unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!((history[0].kind, history[0].source.as_str()), (ChangeKind::Add, "test"));
+
+    for node in nodes {
+        node.stop();
+    }
+}
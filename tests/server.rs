@@ -0,0 +1,344 @@
+extern crate rust_tag_server;
+extern crate http;
+
+use rust_tag_server::httpd::{WebServer, Router, Handler, Request, ServerConfig, ShutdownHandle, Middleware, Flow};
+use http::StatusCode;
+use std::io::{BufRead, BufReader, Read, Write, Error};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Answers with the request's path.
+struct EchoPathHandler;
+
+impl Handler for EchoPathHandler {
+    fn handle(&self, request: &mut Request) -> Result<(), Error> {
+        let body = request.path.clone();
+        request.send_preamble(StatusCode::OK, body.len())?;
+        request.write_all(body.as_bytes())
+    }
+}
+
+struct RunningServer {
+    addr: SocketAddr,
+    shutdown: ShutdownHandle,
+    thread: JoinHandle<()>,
+}
+
+impl RunningServer {
+    fn start(config: ServerConfig) -> RunningServer {
+        let mut router = Router::new();
+        router.add_route("/*path", "GET", EchoPathHandler);
+
+        let server = WebServer::new("127.0.0.1:0", router, 2, 10, |err| eprintln!("{}", err))
+            .unwrap()
+            .with_config(config);
+        RunningServer::run(server)
+    }
+
+    fn run<L: Fn(&str) + Send + Sync + 'static>(server: WebServer<L>) -> RunningServer {
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle().unwrap();
+        let thread = thread::spawn(move || server.run());
+
+        RunningServer { addr, shutdown, thread }
+    }
+
+    fn connect(&self) -> BufReader<TcpStream> {
+        let stream = TcpStream::connect(self.addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        BufReader::new(stream)
+    }
+
+    fn stop(self) {
+        self.shutdown.shutdown();
+        self.thread.join().unwrap();
+    }
+}
+
+/// A response's status line, headers with lowercased names, and body.
+struct Response {
+    status_line: String,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/// Reads one response delimited by its `Content-Length`.
+fn read_response(connection: &mut BufReader<TcpStream>) -> Response {
+    let mut status_line = String::new();
+    connection.read_line(&mut status_line).unwrap();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        connection.read_line(&mut line).unwrap();
+        if line.trim().is_empty() {
+            break;
+        }
+
+        let (name, value) = line.split_at(line.find(':').unwrap());
+        headers.push((name.trim().to_lowercase(), String::from(value[1..].trim())));
+    }
+
+    let length: usize = headers.iter().find(|(name, _)| name == "content-length").unwrap().1.parse().unwrap();
+    let mut body = vec![0; length];
+    connection.read_exact(&mut body).unwrap();
+
+    Response { status_line: String::from(status_line.trim_end()), headers, body: String::from_utf8(body).unwrap() }
+}
+
+/// Whether the server has closed the connection, with nothing more sent on it.
+fn is_closed(connection: &mut BufReader<TcpStream>) -> bool {
+    let mut rest = Vec::new();
+    match connection.read_to_end(&mut rest) {
+        Ok(_) => rest.is_empty(),
+        Err(_) => false,
+    }
+}
+
+#[test]
+fn serves_several_requests_on_one_connection() {
+    let server = RunningServer::start(ServerConfig::default());
+    let mut connection = server.connect();
+
+    for path in ["/first", "/second"].iter() {
+        write!(connection.get_mut(), "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+
+        let response = read_response(&mut connection);
+        assert_eq!(response.status_line, "HTTP/1.1 200 OK");
+        assert_eq!(response.header("connection"), None);
+        assert_eq!(response.body, *path);
+    }
+
+    // Both pipelined at once, answered in order
+    connection.get_mut().write_all(b"GET /third HTTP/1.1\r\n\r\nGET /fourth HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut connection).body, "/third");
+    assert_eq!(read_response(&mut connection).body, "/fourth");
+
+    drop(connection);
+    server.stop();
+}
+
+#[test]
+fn closes_when_the_client_asks() {
+    let server = RunningServer::start(ServerConfig::default());
+    let mut connection = server.connect();
+
+    connection.get_mut().write_all(b"GET /bye HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+
+    let response = read_response(&mut connection);
+    assert_eq!(response.body, "/bye");
+    assert_eq!(response.header("connection"), Some("close"));
+    assert!(is_closed(&mut connection));
+
+    // HTTP/1.0 closes unless asked to keep the connection open
+    let mut connection = server.connect();
+    connection.get_mut().write_all(b"GET /old HTTP/1.0\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut connection).header("connection"), Some("close"));
+    assert!(is_closed(&mut connection));
+
+    let mut connection = server.connect();
+    connection.get_mut().write_all(b"GET /old HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut connection).header("connection"), Some("keep-alive"));
+    connection.get_mut().write_all(b"GET /again HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut connection).body, "/again");
+
+    drop(connection);
+    server.stop();
+}
+
+#[test]
+fn closes_after_the_request_limit() {
+    let server = RunningServer::start(ServerConfig { max_requests_per_connection: 2, ..ServerConfig::default() });
+    let mut connection = server.connect();
+
+    connection.get_mut().write_all(b"GET /1 HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut connection).header("connection"), None);
+
+    connection.get_mut().write_all(b"GET /2 HTTP/1.1\r\n\r\n").unwrap();
+    let response = read_response(&mut connection);
+    assert_eq!(response.body, "/2");
+    assert_eq!(response.header("connection"), Some("close"));
+    assert!(is_closed(&mut connection));
+
+    server.stop();
+}
+
+#[test]
+fn closes_idle_connections() {
+    let server = RunningServer::start(ServerConfig { idle_timeout: Duration::from_millis(200), ..ServerConfig::default() });
+    let mut connection = server.connect();
+
+    connection.get_mut().write_all(b"GET /1 HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut connection).body, "/1");
+
+    // Quiet for less than the timeout, so still open
+    thread::sleep(Duration::from_millis(50));
+    connection.get_mut().write_all(b"GET /2 HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut connection).body, "/2");
+
+    let idle = Instant::now();
+    assert!(is_closed(&mut connection));
+    let waited = idle.elapsed();
+    assert!(waited >= Duration::from_millis(150) && waited < Duration::from_secs(2), "Closed after {:?}", waited);
+
+    server.stop();
+}
+
+#[test]
+fn answers_bad_heads_with_an_error_and_closes() {
+    let server = RunningServer::start(ServerConfig {
+        header_timeout: Duration::from_millis(100),
+        max_request_line: 64,
+        max_header_count: 4,
+        ..ServerConfig::default()
+    });
+
+    let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(64));
+    let many_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "X-Header: 1\r\n".repeat(5));
+    let cases = [
+        ("GET / HTTP/1.1\r\nHost: loc", "HTTP/1.1 408 REQUEST TIMEOUT"),
+        (long_line.as_str(), "HTTP/1.1 414 URI TOO LONG"),
+        (many_headers.as_str(), "HTTP/1.1 431 REQUEST HEADER FIELDS TOO LARGE"),
+        ("GET /\r\n\r\n", "HTTP/1.1 400 BAD REQUEST"),
+    ];
+
+    for (raw, status_line) in cases.iter() {
+        let mut connection = server.connect();
+        connection.get_mut().write_all(raw.as_bytes()).unwrap();
+
+        let response = read_response(&mut connection);
+        assert_eq!(response.status_line, *status_line);
+        assert_eq!(response.header("connection"), Some("close"));
+        assert!(is_closed(&mut connection));
+    }
+
+    // A head cut short isn't dispatched as though it were complete
+    let mut connection = server.connect();
+    connection.get_mut().write_all(b"GET /cut HTTP/1.1\r\nHost: localhost\r\n").unwrap();
+    connection.get_mut().shutdown(Shutdown::Write).unwrap();
+    assert_eq!(read_response(&mut connection).status_line, "HTTP/1.1 400 BAD REQUEST");
+
+    server.stop();
+}
+
+type Log = Arc<Mutex<Vec<String>>>;
+
+/// Logs each hook it runs, and if `halt` is set answers with a 403 instead of carrying on.
+struct Recorder {
+    name: &'static str,
+    log: Log,
+    halt: bool,
+}
+
+impl Recorder {
+    fn new(name: &'static str, log: &Log, halt: bool) -> Recorder {
+        Recorder { name, log: log.clone(), halt }
+    }
+}
+
+impl Middleware for Recorder {
+    fn before(&self, request: &mut Request) -> Result<Flow, Error> {
+        self.log.lock().unwrap().push(format!("{} before", self.name));
+
+        if self.halt {
+            request.send_preamble(StatusCode::FORBIDDEN, 0)?;
+            return Ok(Flow::Halt);
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn after(&self, _request: &Request, result: &Result<(), Error>) {
+        self.log.lock().unwrap().push(format!("{} after {}", self.name, if result.is_ok() { "ok" } else { "err" }));
+    }
+}
+
+struct LoggingHandler {
+    log: Log,
+}
+
+impl Handler for LoggingHandler {
+    fn handle(&self, request: &mut Request) -> Result<(), Error> {
+        self.log.lock().unwrap().push(String::from("handler"));
+        request.send_preamble(StatusCode::OK, 0)
+    }
+}
+
+/// Serves `/open` and `/guarded`, the latter behind route middleware that halts if `halt_route`
+/// is set, with `server`, `router` and `route` middleware logging to the returned log.
+fn start_with_middleware(halt_router: bool, halt_route: bool) -> (RunningServer, Log) {
+    let log = Arc::new(Mutex::new(Vec::new()));
+
+    let mut router = Router::new();
+    router.add_middleware(Recorder::new("router", &log, halt_router));
+    router.add_route("/open", "GET", LoggingHandler { log: log.clone() });
+    router.add_route_with_middleware("/guarded", "GET", LoggingHandler { log: log.clone() },
+                                     vec![Arc::new(Recorder::new("route", &log, halt_route))]);
+
+    let server = WebServer::new("127.0.0.1:0", router, 2, 10, |err| eprintln!("{}", err))
+        .unwrap()
+        .with_middleware(Recorder::new("server", &log, false));
+
+    (RunningServer::run(server), log)
+}
+
+fn get(server: &RunningServer, path: &str) -> Response {
+    let mut connection = server.connect();
+    write!(connection.get_mut(), "GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path).unwrap();
+    read_response(&mut connection)
+}
+
+/// Takes everything logged for a request, waiting for the outermost `after` hook, which may run
+/// once the response has been sent.
+fn take(log: &Log) -> Vec<String> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !log.lock().unwrap().last().is_some_and(|last| last.starts_with("server after")) {
+        assert!(Instant::now() < deadline, "Timed out waiting for the server middleware");
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    log.lock().unwrap().drain(..).collect()
+}
+
+#[test]
+fn server_middleware_wraps_router_middleware_wraps_route_middleware() {
+    let (server, log) = start_with_middleware(false, false);
+
+    assert_eq!(get(&server, "/guarded").status_line, "HTTP/1.1 200 OK");
+    assert_eq!(take(&log), vec!["server before", "router before", "route before", "handler",
+                                "route after ok", "router after ok", "server after ok"]);
+
+    // Router middleware also runs for requests no route matched
+    assert_eq!(get(&server, "/missing").status_line, "HTTP/1.1 404 Not Found");
+    assert_eq!(take(&log), vec!["server before", "router before", "router after ok", "server after ok"]);
+
+    server.stop();
+}
+
+#[test]
+fn halting_skips_the_rest_of_the_chain() {
+    let (server, log) = start_with_middleware(false, true);
+
+    assert_eq!(get(&server, "/guarded").status_line, "HTTP/1.1 403 Forbidden");
+    // Everything that ran its before hook still runs its after hook
+    assert_eq!(take(&log), vec!["server before", "router before", "route before",
+                                "route after ok", "router after ok", "server after ok"]);
+
+    assert_eq!(get(&server, "/open").status_line, "HTTP/1.1 200 OK");
+    assert_eq!(take(&log), vec!["server before", "router before", "handler", "router after ok", "server after ok"]);
+    server.stop();
+
+    let (server, log) = start_with_middleware(true, false);
+
+    assert_eq!(get(&server, "/guarded").status_line, "HTTP/1.1 403 Forbidden");
+    assert_eq!(take(&log), vec!["server before", "router before", "router after ok", "server after ok"]);
+
+    server.stop();
+}
@@ -0,0 +1,175 @@
+extern crate rust_tag_server;
+extern crate rustls;
+extern crate rcgen;
+extern crate http;
+
+use rust_tag_server::httpd::{WebServer, Router, Handler, Request, ServerConfig};
+use http::StatusCode;
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::io::{Read, Write, Error, ErrorKind};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct HelloHandler;
+
+impl Handler for HelloHandler {
+    fn handle(&self, request: &mut Request) -> Result<(), Error> {
+        let body = b"hello over tls";
+        request.send_preamble(StatusCode::OK, body.len())?;
+        request.write_all(body)
+    }
+}
+
+/// Writes a freshly generated self-signed certificate for localhost to a scratch directory,
+/// returning the directory and the certificate in DER form for the client to trust.
+fn write_self_signed_cert(name: &str) -> (PathBuf, rustls::pki_types::CertificateDer<'static>) {
+    let certified = rcgen::generate_simple_self_signed(vec![String::from("localhost")])
+        .expect("Couldn't generate certificate");
+
+    let dir = env::temp_dir().join(format!("rust-tag-server-{}-{}", name, process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("cert.pem"), certified.cert.pem()).unwrap();
+    fs::write(dir.join("key.pem"), certified.key_pair.serialize_pem()).unwrap();
+
+    (dir, certified.cert.der().clone())
+}
+
+fn tls_client(cert: rustls::pki_types::CertificateDer<'static>) -> Arc<rustls::ClientConfig> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert).unwrap();
+
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Arc::new(config)
+}
+
+#[test]
+fn serves_https_with_pem_certificate() {
+    let (dir, cert) = write_self_signed_cert("https");
+
+    let mut router = Router::new();
+    router.add_route("/hello", "GET", HelloHandler);
+
+    let server = WebServer::new("127.0.0.1:0", router, 2, 10, |err| eprintln!("{}", err))
+        .unwrap()
+        .with_tls(dir.join("cert.pem"), dir.join("key.pem"))
+        .unwrap();
+
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle().unwrap();
+    let server_thread = thread::spawn(move || server.run());
+
+    let session = rustls::ClientConnection::new(tls_client(cert), rustls::pki_types::ServerName::try_from("localhost").unwrap()).unwrap();
+    let mut tls = rustls::StreamOwned::new(session, TcpStream::connect(addr).unwrap());
+
+    tls.write_all(b"GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+
+    let mut response = String::new();
+    tls.read_to_string(&mut response).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "Unexpected response: {}", response);
+    assert!(response.ends_with("\r\n\r\nhello over tls"), "Unexpected response: {}", response);
+
+    shutdown.shutdown();
+    server_thread.join().unwrap();
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn rejects_plaintext_on_tls_listener() {
+    let (dir, _) = write_self_signed_cert("plaintext");
+
+    let server = WebServer::new("127.0.0.1:0", Router::new(), 2, 10, |_| {})
+        .unwrap()
+        .with_tls(dir.join("cert.pem"), dir.join("key.pem"))
+        .unwrap();
+
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle().unwrap();
+    let server_thread = thread::spawn(move || server.run());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET /hello HTTP/1.1\r\n\r\n").unwrap();
+
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    assert!(!response.starts_with(b"HTTP/1.1"), "Plaintext request was answered in plaintext");
+
+    shutdown.shutdown();
+    server_thread.join().unwrap();
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn times_out_trickled_handshakes() {
+    let (dir, cert) = write_self_signed_cert("trickle");
+
+    let config = ServerConfig { idle_timeout: Duration::from_millis(300), ..ServerConfig::default() };
+    let server = WebServer::new("127.0.0.1:0", Router::new(), 2, 10, |_| {})
+        .unwrap()
+        .with_config(config)
+        .with_tls(dir.join("cert.pem"), dir.join("key.pem"))
+        .unwrap();
+
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle().unwrap();
+    let server_thread = thread::spawn(move || server.run());
+
+    let mut session = rustls::ClientConnection::new(tls_client(cert), rustls::pki_types::ServerName::try_from("localhost").unwrap()).unwrap();
+    let mut hello = Vec::new();
+    session.write_tls(&mut hello).unwrap();
+
+    // Each byte arrives well within the timeout for a single read, but the whole hello would take
+    // far longer than the timeout to send
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    let start = Instant::now();
+    let mut closed = false;
+    for byte in hello.iter() {
+        if stream.write_all(&[*byte]).is_err() {
+            closed = true;
+            break;
+        }
+
+        match stream.read(&mut [0; 64]) {
+            Ok(0) => {
+                closed = true;
+                break;
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            _ => {
+                closed = true;
+                break;
+            }
+        }
+    }
+
+    let waited = start.elapsed();
+    assert!(closed && waited < Duration::from_secs(2), "Connection was held for {:?}", waited);
+
+    shutdown.shutdown();
+    server_thread.join().unwrap();
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn missing_key_file_is_an_error() {
+    let (dir, _) = write_self_signed_cert("missing-key");
+
+    let server = WebServer::new("127.0.0.1:0", Router::new(), 1, 1, |_| {})
+        .unwrap()
+        .with_tls(dir.join("cert.pem"), dir.join("no-such-key.pem"));
+
+    assert!(server.is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,283 @@
+extern crate rust_tag_server;
+extern crate http;
+extern crate ring;
+extern crate serde_json;
+
+use rust_tag_server::httpd::{WebServer, Router, Handler, Request, ShutdownHandle};
+use rust_tag_server::tags::{TagStore, LsmStore, SyncPolicy, WebhookDispatcher, Endpoint, WebhookConfig,
+                            SIGNATURE_HEADER, EVENT_ID_HEADER};
+use http::StatusCode;
+use ring::hmac;
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::io::{Write, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const SECRET: &str = "shh";
+
+/// A request the receiver was sent.
+#[derive(Clone)]
+struct Delivery {
+    signature: Option<String>,
+    event_id: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Delivery {
+    fn event(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+/// Records every request, failing the first `failures` with a 503 or, if `reject` is set,
+/// failing them all with a 400.
+struct Receiver {
+    deliveries: Arc<Mutex<Vec<Delivery>>>,
+    failures: Mutex<usize>,
+    reject: bool,
+}
+
+impl Handler for Receiver {
+    fn handle(&self, request: &mut Request) -> Result<(), Error> {
+        let delivery = Delivery {
+            signature: request.get_request_header(SIGNATURE_HEADER).cloned(),
+            event_id: request.get_request_header(EVENT_ID_HEADER).cloned(),
+            body: request.read_body()?,
+        };
+        self.deliveries.lock().unwrap().push(delivery);
+
+        let status = {
+            let mut failures = self.failures.lock().unwrap();
+            if self.reject {
+                StatusCode::BAD_REQUEST
+            } else if *failures > 0 {
+                *failures -= 1;
+                StatusCode::SERVICE_UNAVAILABLE
+            } else {
+                StatusCode::OK
+            }
+        };
+
+        let body = status.as_str().as_bytes();
+        request.send_preamble(status, body.len())?;
+        request.write_all(body)
+    }
+}
+
+struct RunningReceiver {
+    url: String,
+    deliveries: Arc<Mutex<Vec<Delivery>>>,
+    shutdown: ShutdownHandle,
+    thread: JoinHandle<()>,
+}
+
+impl RunningReceiver {
+    fn start(failures: usize, reject: bool) -> RunningReceiver {
+        let deliveries = Arc::new(Mutex::new(Vec::new()));
+
+        let mut router = Router::new();
+        router.add_route("/hook", "POST", Receiver {
+            deliveries: deliveries.clone(),
+            failures: Mutex::new(failures),
+            reject,
+        });
+
+        let server = WebServer::new("127.0.0.1:0", router, 2, 10, |err| eprintln!("{}", err)).unwrap();
+        let url = format!("http://{}/hook", server.local_addr().unwrap());
+        let shutdown = server.shutdown_handle().unwrap();
+        let thread = thread::spawn(move || server.run());
+
+        RunningReceiver { url, deliveries, shutdown, thread }
+    }
+
+    fn endpoint(&self) -> Endpoint {
+        Endpoint { url: self.url.clone(), secret: String::from(SECRET) }
+    }
+
+    fn deliveries(&self) -> Vec<Delivery> {
+        self.deliveries.lock().unwrap().clone()
+    }
+
+    fn stop(self) {
+        self.shutdown.shutdown();
+        self.thread.join().unwrap();
+    }
+}
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("rust-tag-server-webhooks-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn fast_config() -> WebhookConfig {
+    WebhookConfig {
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(40),
+        max_attempts: 3,
+        request_timeout: Duration::from_secs(5),
+        ..WebhookConfig::default()
+    }
+}
+
+/// Polls `check` until it holds, failing the test if it doesn't within a few seconds.
+fn wait_until<F: FnMut() -> bool>(what: &str, mut check: F) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !check() {
+        assert!(Instant::now() < deadline, "Timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn dead_letters(dir: &Path) -> Vec<Value> {
+    fs::read_to_string(dir.join("dead")).unwrap_or_default()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn delivers_signed_state_changes() {
+    let dir = scratch_dir("signed");
+    let receiver = RunningReceiver::start(0, false);
+    let store = Arc::new(TagStore::new());
+    let dispatcher = WebhookDispatcher::start(store.clone(), vec![receiver.endpoint()], dir.join("dead"), fast_config()).unwrap();
+
+    store.add_tag("alice", "vip", 1_000, "test").unwrap();
+    // Neither of these changes whether alice holds the tag
+    store.add_tag("alice", "vip", 2_000, "test").unwrap();
+    store.remove_tag("alice", "vip", 500, "test").unwrap();
+    store.remove_tag("alice", "vip", 3_000, "test").unwrap();
+
+    wait_until("two deliveries", || receiver.deliveries().len() >= 2);
+    dispatcher.shutdown();
+
+    let deliveries = receiver.deliveries();
+    assert_eq!(deliveries.len(), 2);
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, SECRET.as_bytes());
+    for delivery in deliveries.iter() {
+        let signature = delivery.signature.as_ref().expect("Delivery wasn't signed");
+        let hex = signature.strip_prefix("sha256=").expect("Signature isn't sha256");
+        let bytes: Vec<u8> = (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        hmac::verify(&key, &delivery.body, &bytes).expect("Signature doesn't match body");
+
+        assert_eq!(delivery.event_id.as_deref(), delivery.event()["id"].as_str());
+    }
+
+    let added = deliveries[0].event();
+    assert_eq!(added["type"], "add");
+    assert_eq!(added["user"], "alice");
+    assert_eq!(added["tag"], "vip");
+    assert_eq!(added["timestamp"], "1970-01-01T00:00:01.000Z");
+
+    let removed = deliveries[1].event();
+    assert_eq!(removed["type"], "remove");
+    assert_eq!(removed["timestamp"], "1970-01-01T00:00:03.000Z");
+
+    assert!(dead_letters(&dir).is_empty());
+    receiver.stop();
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn retries_failed_deliveries_in_order() {
+    let dir = scratch_dir("retries");
+    let receiver = RunningReceiver::start(2, false);
+    let store = Arc::new(TagStore::new());
+    let dispatcher = WebhookDispatcher::start(store.clone(), vec![receiver.endpoint()], dir.join("dead"), fast_config()).unwrap();
+
+    store.add_tag("bob", "first", 1_000, "test").unwrap();
+    store.add_tag("bob", "second", 1_000, "test").unwrap();
+
+    wait_until("four deliveries", || receiver.deliveries().len() >= 4);
+    dispatcher.shutdown();
+
+    let tags: Vec<Value> = receiver.deliveries().iter().map(|delivery| delivery.event()["tag"].clone()).collect();
+    assert_eq!(tags, vec!["first", "first", "first", "second"]);
+    assert!(dead_letters(&dir).is_empty());
+
+    receiver.stop();
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn dead_letters_undeliverable_events() {
+    let dir = scratch_dir("dead");
+    let failing = RunningReceiver::start(usize::MAX, false);
+    let rejecting = RunningReceiver::start(0, true);
+    let store = Arc::new(TagStore::new());
+    let dispatcher = WebhookDispatcher::start(store.clone(), vec![failing.endpoint(), rejecting.endpoint()],
+                                              dir.join("dead"), fast_config()).unwrap();
+
+    store.add_tag("carol", "lost", 1_000, "test").unwrap();
+
+    wait_until("two dead letters", || dead_letters(&dir).len() >= 2);
+    dispatcher.shutdown();
+
+    let letters = dead_letters(&dir);
+    let failed = letters.iter().find(|letter| letter["endpoint"] == failing.url.as_str()).unwrap();
+    assert_eq!(failed["attempts"], 3);
+    assert_eq!(failed["event"]["tag"], "lost");
+    assert_eq!(failing.deliveries().len(), 3);
+
+    // A rejection won't change on a retry, so it isn't retried
+    let rejected = letters.iter().find(|letter| letter["endpoint"] == rejecting.url.as_str()).unwrap();
+    assert_eq!(rejected["attempts"], 1);
+    assert_eq!(rejected["event"]["user"], "carol");
+    assert_eq!(rejecting.deliveries().len(), 1);
+
+    failing.stop();
+    rejecting.stop();
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn failing_endpoint_doesnt_hold_up_others() {
+    let dir = scratch_dir("isolated");
+    let healthy = RunningReceiver::start(0, false);
+    let store = Arc::new(TagStore::new());
+    let slow_retries = WebhookConfig {
+        initial_backoff: Duration::from_secs(60),
+        max_backoff: Duration::from_secs(60),
+        ..fast_config()
+    };
+    // Nothing listens on port 9 of localhost, so every attempt fails and waits a minute to retry
+    let unreachable = Endpoint { url: String::from("http://127.0.0.1:9/hook"), secret: String::from(SECRET) };
+    let dispatcher = WebhookDispatcher::start(store.clone(), vec![unreachable, healthy.endpoint()],
+                                              dir.join("dead"), slow_retries).unwrap();
+
+    for i in 0..5 {
+        store.add_tag("dave", &format!("tag-{}", i), 1_000, "test").unwrap();
+    }
+
+    wait_until("five deliveries", || healthy.deliveries().len() >= 5);
+    dispatcher.shutdown();
+
+    // Shutting down cuts the retry short, so every event for the unreachable endpoint is dead-lettered
+    let letters = dead_letters(&dir);
+    assert_eq!(letters.len(), 5);
+    assert!(letters.iter().all(|letter| letter["endpoint"] == "http://127.0.0.1:9/hook"));
+
+    healthy.stop();
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn requires_a_backend_that_publishes_events() {
+    let dir = scratch_dir("lsm");
+    let store = Arc::new(LsmStore::open(dir.join("lsm"), SyncPolicy::Always).unwrap());
+
+    let result = WebhookDispatcher::start(store, Vec::new(), dir.join("dead"), fast_config());
+    assert_eq!(result.err().map(|e| e.kind()), Some(ErrorKind::Unsupported));
+
+    fs::remove_dir_all(dir).unwrap();
+}